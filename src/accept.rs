@@ -0,0 +1,166 @@
+//! The accept loop, hardened against errors that shouldn't stop the server.
+//!
+//! A connection reset before it is accepted only loses that connection. Other
+//! accept errors, like running out of file descriptors, usually clear up once
+//! other connections close, so they are logged and retried after a backoff
+//! instead of failing the server or retrying in a tight loop.
+
+use futures::stream::{self, Stream};
+use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+/// What request handling needs to know about an accepted connection,
+/// however it is wrapped.
+pub trait Connection {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// An id for matching the connection's requests to its other logs, if it
+    /// has one.
+    fn id(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl Connection for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The connections accepted on `listener`. Must be called from within a
+/// runtime.
+pub fn incoming(listener: StdTcpListener) -> io::Result<impl Stream<Item = io::Result<TcpStream>>> {
+    let listener = TcpListener::from_std(listener)?;
+
+    // The failed accepts so far, for the log.
+    let errors = 0u64;
+    Ok(stream::unfold(
+        (listener, errors),
+        |(listener, mut errors)| async move {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => return Some((Ok(stream), (listener, errors))),
+                    Err(e) => {
+                        errors += 1;
+                        if is_connection_error(&e) {
+                            debug!("connection failed before it was accepted: {}", e);
+                            continue;
+                        }
+                        warn!(
+                            "accept error ({} so far), retrying in {:?}: {}",
+                            errors, backoff, e
+                        );
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            }
+        },
+    ))
+}
+
+/// Errors that only affect the connection being accepted.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use futures::stream::StreamExt;
+    use std::os::unix::io::AsRawFd;
+
+    /// Set in the child process that runs out of file descriptors.
+    const CHILD: &str = "SUFFICIENT_TEST_FD_CHILD";
+
+    #[test]
+    fn accepting_recovers_from_running_out_of_descriptors() {
+        // The descriptor limit is per process, so it is lowered in a copy of
+        // the test binary running only `out_of_descriptors`.
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(&["accept::tests::out_of_descriptors", "--exact", "--ignored"])
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+    }
+
+    #[test]
+    #[ignore = "run by accepting_recovers_from_running_out_of_descriptors"]
+    fn out_of_descriptors() {
+        if std::env::var_os(CHILD).is_none() {
+            return;
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let mut incoming = Box::pin(incoming(listener).unwrap());
+
+            // Leave room for a few connections, then use it all up with
+            // clients, so none of them can be accepted.
+            let lowest_free = std::fs::File::open("/dev/null").unwrap().as_raw_fd();
+            let mut limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            unsafe {
+                assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit), 0);
+                limit.rlim_cur = lowest_free as libc::rlim_t + 8;
+                assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &limit), 0);
+            }
+            let mut clients = Vec::new();
+            loop {
+                match std::net::TcpStream::connect(addr) {
+                    Ok(client) => clients.push(client),
+                    Err(e) => {
+                        assert_eq!(e.raw_os_error(), Some(libc::EMFILE), "{}", e);
+                        break;
+                    }
+                }
+            }
+            assert!(!clients.is_empty());
+
+            // Accepting fails, and is retried rather than ending the stream
+            // or handing out the error.
+            let wait = Duration::from_millis(300);
+            let next = tokio::time::timeout(wait, incoming.next()).await;
+            assert!(next.is_err(), "accepted with no descriptors left");
+
+            // Once some close, the rest are accepted.
+            clients.truncate(clients.len() - 4);
+            let wait = MAX_BACKOFF * 2;
+            for _ in 0..4 {
+                let next = tokio::time::timeout(wait, incoming.next()).await;
+                let conn = next.expect("no recovery").expect("stream ended");
+                conn.unwrap();
+            }
+        });
+    }
+}
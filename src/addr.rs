@@ -0,0 +1,310 @@
+//! Parsing `--addr` and binding the listening sockets it describes.
+//!
+//! One `--addr` can mean more than one socket: `:PORT` listens on every
+//! interface of both address families, and a host name listens on every
+//! address it resolves to. Names are resolved when the configuration is
+//! validated for serving, not when the argument is parsed, so commands that
+//! don't listen never wait on DNS.
+
+use socket2::{Domain, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, ToSocketAddrs};
+
+use crate::{Error, Result};
+
+const FORMATS: &str = "expected PORT, :PORT, HOST:PORT or [IPV6]:PORT";
+
+/// Where to listen, as given to `--addr`.
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    /// Every interface of both families, from `:PORT`.
+    Any(u16),
+    /// Specific addresses.
+    Addrs(Vec<SocketAddr>),
+    /// A host name and port, not resolved yet.
+    Host(String, u16),
+}
+
+/// One socket to listen on.
+#[derive(Clone, Copy, Debug)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    /// Whether an IPv6 socket refuses IPv4-mapped clients.
+    pub v6_only: bool,
+}
+
+impl ListenAddr {
+    /// This address with its host name, if any, resolved to the addresses it
+    /// names.
+    pub fn resolve(&self) -> Result<ListenAddr> {
+        let (host, port) = match self {
+            ListenAddr::Host(host, port) => (host, *port),
+            _ => return Ok(self.clone()),
+        };
+        let resolve = |e| Error::Resolve(host.clone(), e);
+        let mut addrs: Vec<SocketAddr> = (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(resolve)?
+            .collect();
+        addrs.sort();
+        addrs.dedup();
+        if addrs.is_empty() {
+            return Err(resolve(io::Error::other("no addresses found")));
+        }
+
+        Ok(ListenAddr::Addrs(addrs))
+    }
+
+    /// The sockets to bind. A host name has to be resolved first; until it is
+    /// there are none. With `ipv6_only`, IPv4 addresses are left out
+    /// and IPv6 sockets don't accept IPv4-mapped clients.
+    pub fn listeners(&self, ipv6_only: bool) -> Vec<Listener> {
+        match *self {
+            ListenAddr::Any(port) => {
+                // Both families share the port, so the IPv6 socket must leave
+                // IPv4 to the other one.
+                let mut listeners = vec![Listener {
+                    addr: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
+                    v6_only: true,
+                }];
+                if !ipv6_only {
+                    listeners.push(Listener {
+                        addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+                        v6_only: false,
+                    });
+                }
+                listeners
+            }
+            ListenAddr::Addrs(ref addrs) => addrs
+                .iter()
+                .filter(|addr| addr.is_ipv6() || !ipv6_only)
+                .map(|&addr| Listener {
+                    addr,
+                    v6_only: ipv6_only || overlaps_v4(addr, addrs),
+                })
+                .collect(),
+            ListenAddr::Host(..) => Vec::new(),
+        }
+    }
+
+    /// The host and port to put in URLs pointing at this server.
+    pub fn url_authority(&self) -> String {
+        match *self {
+            ListenAddr::Any(port) => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port).to_string(),
+            ListenAddr::Addrs(ref addrs) => addrs[0].to_string(),
+            ListenAddr::Host(ref host, port) => format!("{}:{}", host, port),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}", self.addr)?;
+        if self.addr.is_ipv6() && self.addr.ip().is_unspecified() {
+            if self.v6_only {
+                f.write_str(" (IPv6 only)")?;
+            } else {
+                f.write_str(" (IPv6 and IPv4-mapped)")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse an `--addr` argument.
+pub fn parse(s: &str) -> std::result::Result<ListenAddr, String> {
+    let bad_port = |port: &str| format!("invalid port '{}' ({})", port, FORMATS);
+
+    if !s.starts_with('[') && s.matches(':').count() > 1 {
+        return Err(format!(
+            "'{}' looks like an IPv6 address; put it in brackets, e.g. [::1]:4000 ({})",
+            s, FORMATS
+        ));
+    }
+    if let Some(port) = s.strip_prefix(':') {
+        return port
+            .parse()
+            .map(ListenAddr::Any)
+            .map_err(|_| bad_port(port));
+    }
+    if let Ok(port) = s.parse::<u16>() {
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        return Ok(ListenAddr::Addrs(vec![addr]));
+    }
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(ListenAddr::Addrs(vec![addr]));
+    }
+    if s.starts_with('[') {
+        return Err(format!("invalid bracketed address '{}' ({})", s, FORMATS));
+    }
+
+    let (host, port) = match s.rfind(':') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(format!("missing port in '{}' ({})", s, FORMATS)),
+    };
+    let port: u16 = port.parse().map_err(|_| bad_port(port))?;
+    let valid = !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+    if !valid {
+        return Err(format!("invalid host name '{}' ({})", host, FORMATS));
+    }
+
+    Ok(ListenAddr::Host(host.to_string(), port))
+}
+
+/// Bind every listener, failing on the first that can't be bound.
+pub fn bind_all(listeners: &[Listener], reuse_port: bool) -> Result<Vec<TcpListener>> {
+    listeners
+        .iter()
+        .map(|&listener| bind(listener, reuse_port).map_err(|e| Error::Bind(listener.addr, e)))
+        .collect()
+}
+
+/// Bind a listening socket, ready to be handed to hyper.
+fn bind(listener: Listener, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(listener.addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    if listener.addr.is_ipv6() {
+        socket.set_only_v6(listener.v6_only)?;
+    }
+    socket.bind(&listener.addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+/// Whether an unspecified IPv6 address would collide with an IPv4 wildcard
+/// on the same port if it accepted IPv4-mapped clients.
+fn overlaps_v4(addr: SocketAddr, addrs: &[SocketAddr]) -> bool {
+    addr.is_ipv6()
+        && addr.ip().is_unspecified()
+        && addrs.iter().any(|other| {
+            other.ip() == IpAddr::V4(Ipv4Addr::UNSPECIFIED) && other.port() == addr.port()
+        })
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::other(
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(s: &str) -> Vec<SocketAddr> {
+        match parse(s).unwrap() {
+            ListenAddr::Addrs(addrs) => addrs,
+            addr => panic!("{} parsed to {:?}", s, addr),
+        }
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ports_and_addresses() {
+        assert_eq!(addrs("4000"), [addr("127.0.0.1:4000")]);
+        assert_eq!(addrs("0.0.0.0:80"), [addr("0.0.0.0:80")]);
+        assert_eq!(addrs("[::1]:4000"), [addr("[::1]:4000")]);
+        assert_eq!(addrs("[::]:4000"), [addr("[::]:4000")]);
+        assert!(matches!(parse(":4000"), Ok(ListenAddr::Any(4000))));
+    }
+
+    #[test]
+    fn host_names_are_not_resolved_when_parsed() {
+        // `.invalid` never resolves, so this would fail if it were looked up.
+        match parse("server.invalid:4000") {
+            Ok(ListenAddr::Host(host, 4000)) => assert_eq!(host, "server.invalid"),
+            addr => panic!("parsed to {:?}", addr),
+        }
+        let unresolved = parse("server.invalid:4000").unwrap();
+        assert!(unresolved.listeners(false).is_empty());
+        assert_eq!(unresolved.url_authority(), "server.invalid:4000");
+        assert!(matches!(unresolved.resolve(), Err(Error::Resolve(..))));
+    }
+
+    #[test]
+    fn host_names_resolve_to_every_address() {
+        let addrs = match parse("localhost:4000").unwrap().resolve().unwrap() {
+            ListenAddr::Addrs(addrs) => addrs,
+            addr => panic!("resolved to {:?}", addr),
+        };
+        assert!(!addrs.is_empty());
+        assert!(addrs
+            .iter()
+            .all(|addr| addr.ip().is_loopback() && addr.port() == 4000));
+        let mut sorted = addrs.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(addrs, sorted);
+
+        // Anything else stays as it is.
+        assert!(matches!(
+            parse(":4000").unwrap().resolve(),
+            Ok(ListenAddr::Any(4000))
+        ));
+    }
+
+    #[test]
+    fn malformed_addresses_are_refused() {
+        for bad in &[
+            "",
+            ":",
+            ":port",
+            "70000",
+            "::1:4000",
+            "[::1]",
+            "[::1]:",
+            "[server]:4000",
+            "localhost",
+            "localhost:",
+            "localhost:port",
+            "local host:4000",
+            ":4000:",
+        ] {
+            assert!(parse(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn any_listens_on_both_families() {
+        let any = parse(":4000").unwrap();
+        let listeners = any.listeners(false);
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].addr, addr("[::]:4000"));
+        assert!(listeners[0].v6_only);
+        assert_eq!(listeners[1].addr, addr("0.0.0.0:4000"));
+
+        let listeners = any.listeners(true);
+        assert_eq!(listeners.len(), 1);
+        assert!(listeners[0].addr.is_ipv6() && listeners[0].v6_only);
+    }
+
+    #[test]
+    fn ipv6_only_leaves_out_ipv4() {
+        let v4 = parse("0.0.0.0:4000").unwrap();
+        assert!(v4.listeners(true).is_empty());
+        let v6 = parse("[::]:4000").unwrap();
+        assert!(!v6.listeners(false)[0].v6_only);
+        assert!(v6.listeners(true)[0].v6_only);
+
+        let both = ListenAddr::Addrs(vec![addr("[::]:4000"), addr("0.0.0.0:4000")]);
+        assert!(both.listeners(false)[0].v6_only);
+    }
+}
@@ -0,0 +1,48 @@
+//! The areas `--debug` can turn on debug logging for, each with its own
+//! tracing target.
+//!
+//! Most areas are a module, logging under the module's own path. Request
+//! handling in `main.rs` logs its debug events under `sufficient::request`,
+//! since the crate root's target would match every module.
+
+use clap::builder::{PossibleValue, PossibleValuesParser};
+
+/// Turns on every area.
+pub const ALL: &str = "all";
+
+/// Every area: its name, its target, and what it covers.
+const AREAS: &[(&str, &str, &str)] = &[
+    ("accept", "sufficient::accept", "accepting connections"),
+    ("hosts", "sufficient::hosts", "the allowed-hosts check"),
+    ("logfile", "sufficient::logfile", "opening log files"),
+    (
+        "proxy",
+        "sufficient::proxyproto",
+        "PROXY protocol preambles",
+    ),
+    ("record", "sufficient::record", "--record"),
+    (
+        "request",
+        "sufficient::request",
+        "admission checks, rewrites and error responses",
+    ),
+    ("workers", "sufficient::workers", "worker processes"),
+];
+
+/// The parser for `--debug`, which also lists the areas in `--help`.
+pub fn parser() -> PossibleValuesParser {
+    let areas = AREAS
+        .iter()
+        .map(|&(name, _, help)| PossibleValue::new(name).help(help));
+    let all = PossibleValue::new(ALL).help("every area");
+    PossibleValuesParser::new(areas.chain(std::iter::once(all)))
+}
+
+/// The target an area logs under. `all` is the crate's own, which every
+/// module's target falls under.
+pub fn target(area: &str) -> &'static str {
+    match AREAS.iter().find(|&&(name, _, _)| name == area) {
+        Some(&(_, target, _)) => target,
+        None => "sufficient",
+    }
+}
@@ -0,0 +1,229 @@
+//! `GET /_api/capabilities`, served with `--api`: what this server can do, as
+//! JSON, so tools can check for a feature instead of probing for it.
+//!
+//! The document is built from the `Config` the server runs with, once at
+//! startup; the configuration isn't reloaded, so neither it nor its `ETag`
+//! change until a restart.
+//!
+//! `api_version` follows this policy: fields may be added at any time, and
+//! clients must ignore fields they don't know. Removing a field, renaming it
+//! or changing what its values mean bumps the version.
+
+use http::header::{self, HeaderValue};
+use http::{Method, StatusCode};
+use hyper::{Body, Request, Response};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::findings::json_string;
+use crate::preconditions::{Preconditions, Resource};
+use crate::{Config, Error, Result, ALLOWED_METHODS};
+
+/// The path the document is served at.
+pub const PATH: &str = "/_api/capabilities";
+
+/// The version of the document's format. See the module docs for when it
+/// changes.
+const API_VERSION: u32 = 1;
+
+pub struct Capabilities {
+    body: String,
+    etag: String,
+}
+
+impl Capabilities {
+    pub fn new(config: &Config) -> Capabilities {
+        let body = document(config).to_string();
+        let etag = format!("\"{:x}\"", Sha256::digest(body.as_bytes()));
+
+        Capabilities { body, etag }
+    }
+
+    /// Answer a request for the document.
+    pub fn serve(&self, req: &Request<Body>) -> Result<Response<Body>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return crate::other_method(req.method());
+        }
+
+        let resp = Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::ETAG, self.etag.as_str())
+            .header(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+
+        let resource = Resource {
+            etag: &self.etag,
+            modified: None,
+            len: self.body.len() as u64,
+        };
+        let resp = if Preconditions::parse(req.headers()).not_modified(
+            &resource,
+            SystemTime::now(),
+            Duration::from_secs(0),
+        ) {
+            resp.status(StatusCode::NOT_MODIFIED).body(Body::empty())
+        } else {
+            resp.body(Body::from(self.body.clone()))
+        };
+
+        resp.map_err(Error::Http)
+    }
+}
+
+/// The document describing a server running with `config`.
+fn document(config: &Config) -> Value {
+    let enabled = |enabled| Value::Object(vec![("enabled", Value::Bool(enabled))]);
+    let auth = if config.protect.is_empty() {
+        "none"
+    } else {
+        "bearer"
+    };
+
+    Value::Object(vec![
+        ("api_version", Value::Number(API_VERSION.into())),
+        (
+            "server",
+            Value::Object(vec![
+                ("name", Value::String("sufficient")),
+                ("version", Value::String(env!("CARGO_PKG_VERSION"))),
+            ]),
+        ),
+        (
+            "methods",
+            Value::Array(ALLOWED_METHODS.split(", ").map(Value::String).collect()),
+        ),
+        // Nothing can be written through the server, whatever the options.
+        ("upload", enabled(false)),
+        ("delete", enabled(false)),
+        // Byte ranges are only served for the --stdin artifact.
+        ("ranges", Value::Bool(config.stdin.is_some())),
+        ("archive", Value::Bool(false)),
+        ("hash", Value::Bool(config.api)),
+        (
+            "auth",
+            Value::Object(vec![
+                ("mode", Value::String(auth)),
+                ("signed_links", Value::Bool(config.signing_key.is_some())),
+                (
+                    "required_headers",
+                    Value::Bool(!config.require_header.is_empty()),
+                ),
+            ]),
+        ),
+        (
+            "limits",
+            Value::Object(vec![
+                (
+                    "max_per_ip",
+                    match config.max_per_ip {
+                        Some(max) => Value::Number(max as u128),
+                        None => Value::Null,
+                    },
+                ),
+                (
+                    "max_per_ip_wait_ms",
+                    Value::Number(config.max_per_ip_wait.as_millis()),
+                ),
+            ]),
+        ),
+    ])
+}
+
+/// Just enough JSON to write the document with.
+enum Value {
+    Null,
+    Bool(bool),
+    Number(u128),
+    String(&'static str),
+    Array(Vec<Value>),
+    Object(Vec<(&'static str, Value)>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => f.write_str(&json_string(s)),
+            Value::Array(values) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}:{}", json_string(name), value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn capabilities(args: &[&str]) -> Capabilities {
+        let config = Config::try_parse_from(Some("sufficient").iter().chain(args)).unwrap();
+        Capabilities::new(&config)
+    }
+
+    #[test]
+    fn ranges_only_with_stdin() {
+        assert!(capabilities(&[]).body.contains("\"ranges\":false"));
+        assert!(capabilities(&["--stdin", "data.txt"])
+            .body
+            .contains("\"ranges\":true"));
+    }
+
+    #[test]
+    fn follows_the_options() {
+        let plain = capabilities(&[]).body;
+        assert!(plain.contains("\"auth\":{\"mode\":\"none\",\"signed_links\":false"));
+        assert!(plain.contains("\"limits\":{\"max_per_ip\":null,"));
+        assert!(plain.contains("\"hash\":false"));
+        assert!(capabilities(&["--api"]).body.contains("\"hash\":true"));
+
+        let body = capabilities(&[
+            "--protect",
+            "/private/**=token",
+            "--signing-key",
+            "key",
+            "--max-per-ip",
+            "4",
+        ])
+        .body;
+        assert!(body.contains("\"auth\":{\"mode\":\"bearer\",\"signed_links\":true"));
+        assert!(body.contains("\"max_per_ip\":4,"));
+    }
+
+    #[test]
+    fn writes_json() {
+        let body = capabilities(&[]).body;
+        assert!(body.starts_with("{\"api_version\":1,\"server\":{\"name\":\"sufficient\","));
+        assert!(body.contains("\"methods\":[\"GET\",\"HEAD\",\"OPTIONS\"]"));
+        assert!(body.contains("\"upload\":{\"enabled\":false}"));
+        assert!(body.ends_with("}}"));
+    }
+
+    #[test]
+    fn etag_follows_the_document() {
+        assert_eq!(capabilities(&[]).etag, capabilities(&[]).etag);
+        assert_ne!(
+            capabilities(&[]).etag,
+            capabilities(&["--stdin", "data.txt"]).etag
+        );
+    }
+}
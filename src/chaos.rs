@@ -0,0 +1,200 @@
+//! `--chaos` mode: slow and failing responses on purpose, for testing how a
+//! frontend copes with them.
+//!
+//! Each request whose decoded path matches a `--chaos-path` (or every
+//! request, without one) may be delayed by a random time in `--chaos-delay`,
+//! turned into one of the `--chaos-status` errors with the probability
+//! `--chaos-error-rate`, and have its body sent at no more than
+//! `--chaos-throttle` bytes a second. Whatever was done is logged, and sent
+//! back in `X-Chaos` so it isn't mistaken for a real problem.
+
+use futures::stream::{self, StreamExt};
+use globset::GlobMatcher;
+use http::header::{HeaderName, HeaderValue};
+use http::StatusCode;
+use hyper::{Body, Response};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::fmt;
+use std::time::Duration;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{units, urlpath};
+
+/// The header reporting what was injected.
+const X_CHAOS: &str = "x-chaos";
+
+/// The statuses injected when no `--chaos-status` is given.
+pub const DEFAULT_STATUSES: [StatusCode; 3] = [
+    StatusCode::INTERNAL_SERVER_ERROR,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+];
+
+/// How many times a second a throttled body is sent a piece.
+const THROTTLE_TICKS: u64 = 10;
+
+/// A range of delays, as given to `--chaos-delay`.
+#[derive(Clone, Copy, Debug)]
+pub struct DelayRange {
+    min: Duration,
+    max: Duration,
+}
+
+pub struct Chaos {
+    pub delay: Option<DelayRange>,
+    pub error_rate: f64,
+    pub statuses: Vec<StatusCode>,
+    /// The most body bytes sent per second.
+    pub throttle: Option<u64>,
+    pub paths: Vec<GlobMatcher>,
+}
+
+/// What was decided for one request.
+#[derive(Default)]
+pub struct Injected {
+    pub delay: Option<Duration>,
+    pub status: Option<StatusCode>,
+    pub throttle: Option<u64>,
+}
+
+impl Chaos {
+    /// Decide what to inject into the response to a request for `path`.
+    pub fn pick(&self, path: &str) -> Injected {
+        let path = urlpath::normalize(path);
+        if !self.paths.is_empty() && !self.paths.iter().any(|p| p.is_match(&path)) {
+            return Injected::default();
+        }
+
+        let mut rng = rand::thread_rng();
+        let delay = self.delay.map(|range| {
+            if range.min == range.max {
+                range.min
+            } else {
+                rng.gen_range(range.min..=range.max)
+            }
+        });
+        let status = if self.error_rate > 0.0 && rng.gen_bool(self.error_rate) {
+            self.statuses.choose(&mut rng).copied()
+        } else {
+            None
+        };
+
+        Injected {
+            delay,
+            status,
+            throttle: self.throttle,
+        }
+    }
+}
+
+impl Injected {
+    pub fn is_empty(&self) -> bool {
+        self.delay.is_none() && self.status.is_none() && self.throttle.is_none()
+    }
+
+    /// Report what was injected in `X-Chaos`, and throttle the body.
+    pub fn apply(&self, resp: Response<Body>) -> Response<Body> {
+        if self.is_empty() {
+            return resp;
+        }
+
+        let mut resp = match self.throttle {
+            Some(rate) => resp.map(|body| throttle(body, rate)),
+            None => resp,
+        };
+        if let Ok(value) = HeaderValue::from_str(&self.to_string()) {
+            resp.headers_mut()
+                .insert(HeaderName::from_static(X_CHAOS), value);
+        }
+        resp
+    }
+}
+
+/// `delay=834ms, status=503, throttle=65536B/s`, or `-` when nothing was
+/// injected.
+impl fmt::Display for Injected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(delay) = self.delay {
+            parts.push(format!("delay={}ms", delay.as_millis()));
+        }
+        if let Some(status) = self.status {
+            parts.push(format!("status={}", status.as_u16()));
+        }
+        if let Some(rate) = self.throttle {
+            parts.push(format!("throttle={}B/s", rate));
+        }
+
+        if parts.is_empty() {
+            f.write_str("-")
+        } else {
+            f.write_str(&parts.join(", "))
+        }
+    }
+}
+
+/// Send `body` in pieces of a tenth of `rate`, a tenth of a second apart.
+fn throttle(body: Body, rate: u64) -> Body {
+    let piece = (rate / THROTTLE_TICKS).max(1) as usize;
+    let tick = Duration::from_secs(1) / THROTTLE_TICKS as u32;
+
+    let pieces = body.flat_map(move |chunk| {
+        let pieces = match chunk {
+            Ok(chunk) => (0..chunk.len())
+                .step_by(piece)
+                .map(|start| Ok(chunk.slice(start..chunk.len().min(start + piece))))
+                .collect(),
+            Err(e) => vec![Err(e)],
+        };
+        stream::iter(pieces)
+    });
+    Body::wrap_stream(pieces.then(move |piece| async move {
+        tokio::time::sleep(tick).await;
+        piece
+    }))
+}
+
+/// Parse a `--chaos-delay` argument: a duration, or a range of them like
+/// `200ms-2s`.
+pub fn parse_delay(s: &str) -> std::result::Result<DelayRange, String> {
+    let (min, max) = match s.split_once('-') {
+        Some((min, max)) => (units::parse_duration(min)?, units::parse_duration(max)?),
+        None => {
+            let delay = units::parse_duration(s)?;
+            (delay, delay)
+        }
+    };
+    if min > max {
+        return Err(format!("delay range '{}' ends before it starts", s));
+    }
+
+    Ok(DelayRange { min, max })
+}
+
+/// Parse a `--chaos-error-rate` argument, a fraction from 0 to 1.
+pub fn parse_rate(s: &str) -> std::result::Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("error rate '{}' isn't a fraction from 0 to 1", s)),
+    }
+}
+
+/// Parse a `--chaos-status` argument, a 4xx or 5xx status code.
+pub fn parse_status(s: &str) -> std::result::Result<StatusCode, String> {
+    match s.trim().parse::<StatusCode>() {
+        Ok(status) if status.is_client_error() || status.is_server_error() => Ok(status),
+        _ => Err(format!("'{}' isn't a 4xx or 5xx status code", s)),
+    }
+}
+
+/// Parse a `--chaos-throttle` argument, a size sent per second.
+pub fn parse_throttle(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let size = s.strip_suffix("/s").unwrap_or(s);
+    match units::parse_size(size)? {
+        0 => Err("throttle must be more than 0 bytes a second".to_string()),
+        rate => Ok(rate),
+    }
+}
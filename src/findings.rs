@@ -0,0 +1,216 @@
+//! What `--check` found, and the ways of printing it.
+//!
+//! Validation produces `Finding`s; how they are shown is chosen separately
+//! with `--output`: aligned text for people, JSON for tools, or GitHub
+//! Actions workflow annotations.
+
+use clap::ValueEnum;
+use std::env;
+use std::error::Error as StdError;
+use std::path::PathBuf;
+
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// One problem found while checking.
+#[derive(Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The file at fault, if there is one.
+    pub path: Option<PathBuf>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Finding {
+    /// The finding for a validation error, with its whole cause chain in the
+    /// message.
+    pub fn from_error(e: &Error) -> Finding {
+        let (path, line) = match e {
+            Error::InvalidRoot(path, _) | Error::TemplateIo(path, _) => (Some(path.clone()), None),
+            Error::TemplateParse(path, parse) => (Some(path.clone()), Some(parse.line)),
+            _ => (None, None),
+        };
+
+        let mut message = e.to_string();
+        let mut source = e.source();
+        while let Some(cause) = source {
+            message.push_str(": ");
+            message.push_str(&cause.to_string());
+            source = cause.source();
+        }
+
+        Finding {
+            severity: Severity::Error,
+            path,
+            line,
+            message,
+        }
+    }
+
+    pub fn warning(path: Option<PathBuf>, message: String) -> Finding {
+        Finding {
+            severity: Severity::Warning,
+            path,
+            line: None,
+            message,
+        }
+    }
+}
+
+/// How `--check` prints its findings.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Output {
+    Human,
+    Json,
+    Github,
+}
+
+/// Print the findings to stdout.
+pub fn print(findings: &[Finding], output: Output) {
+    match output {
+        Output::Human => print_human(findings),
+        Output::Json => println!("{}", json(findings)),
+        Output::Github => {
+            for finding in findings {
+                println!("{}", github_annotation(finding));
+            }
+        }
+    }
+}
+
+/// Findings grouped by file, with the severities lined up.
+fn print_human(findings: &[Finding]) {
+    let ansi = env::var("NO_ANSI").is_err();
+    let paint = |code: &str, text: &str| {
+        if ansi {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    };
+
+    let mut sorted: Vec<&Finding> = findings.iter().collect();
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut group = None;
+    for finding in sorted {
+        if group != Some(&finding.path) {
+            let title = match &finding.path {
+                Some(path) => path.display().to_string(),
+                None => "configuration".to_string(),
+            };
+            println!("{}", paint("1", &title));
+            group = Some(&finding.path);
+        }
+
+        let severity = format!("{:<7}", finding.severity.as_str());
+        let severity = match finding.severity {
+            Severity::Error => paint("31", &severity),
+            Severity::Warning => paint("33", &severity),
+        };
+        let line = match finding.line {
+            Some(line) => format!("{:>5}", format!(":{}", line)),
+            None => " ".repeat(5),
+        };
+        println!("  {} {}  {}", line, severity, finding.message);
+    }
+}
+
+fn json(findings: &[Finding]) -> String {
+    let entries: Vec<String> = findings
+        .iter()
+        .map(|finding| {
+            let path = match &finding.path {
+                Some(path) => json_string(&path.display().to_string()),
+                None => "null".to_string(),
+            };
+            let line = match finding.line {
+                Some(line) => line.to_string(),
+                None => "null".to_string(),
+            };
+            format!(
+                "{{\"severity\":{},\"path\":{},\"line\":{},\"message\":{}}}",
+                json_string(finding.severity.as_str()),
+                path,
+                line,
+                json_string(&finding.message)
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A `::error file=...,line=...::message` workflow command.
+fn github_annotation(finding: &Finding) -> String {
+    let mut properties = Vec::new();
+    if let Some(path) = &finding.path {
+        properties.push(format!(
+            "file={}",
+            github_escape(&path.display().to_string(), true)
+        ));
+    }
+    if let Some(line) = finding.line {
+        properties.push(format!("line={}", line));
+    }
+
+    let properties = if properties.is_empty() {
+        String::new()
+    } else {
+        format!(" {}", properties.join(","))
+    };
+    format!(
+        "::{}{}::{}",
+        finding.severity.as_str(),
+        properties,
+        github_escape(&finding.message, false)
+    )
+}
+
+/// Escape data for a workflow command; property values also escape the
+/// separators `:` and `,`.
+fn github_escape(s: &str, property: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' => out.push_str("%25"),
+            '\r' => out.push_str("%0D"),
+            '\n' => out.push_str("%0A"),
+            ':' if property => out.push_str("%3A"),
+            ',' if property => out.push_str("%2C"),
+            c => out.push(c),
+        }
+    }
+    out
+}
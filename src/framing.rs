@@ -0,0 +1,286 @@
+//! Defensive checks on request framing headers.
+//!
+//! hyper does the actual message framing, but when sufficient sits behind a
+//! proxy a request that the two could frame differently is a smuggling risk.
+//! Such requests are refused outright rather than served.
+
+use http::header::{self, HeaderMap};
+use http::uri::{Authority, Uri};
+use std::fmt;
+
+/// Why a request's framing was rejected.
+#[derive(Debug)]
+pub enum Violation {
+    /// Both `Content-Length` and `Transfer-Encoding` were sent.
+    LengthAndEncoding,
+    /// `Transfer-Encoding` doesn't end in exactly one plain `chunked`.
+    BadEncoding,
+    /// `Content-Length` was sent more than once with different values.
+    ConflictingLength,
+    /// `Host` was sent more than once.
+    MultipleHosts,
+    /// An absolute-form target names a different authority than `Host`.
+    HostMismatch,
+}
+
+impl Violation {
+    /// The name of the header at fault.
+    pub fn header(&self) -> &'static str {
+        match self {
+            Violation::LengthAndEncoding | Violation::BadEncoding => "Transfer-Encoding",
+            Violation::ConflictingLength => "Content-Length",
+            Violation::MultipleHosts | Violation::HostMismatch => "Host",
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            Violation::LengthAndEncoding => "both Content-Length and Transfer-Encoding",
+            Violation::BadEncoding => "Transfer-Encoding not ending in a single chunked",
+            Violation::ConflictingLength => "conflicting Content-Length values",
+            Violation::MultipleHosts => "multiple Host headers",
+            Violation::HostMismatch => "request target authority does not match Host",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Check a request's framing headers. The absolute-form authority is only
+/// compared against `Host` when `strict_host` is set.
+pub fn check(
+    uri: &Uri,
+    headers: &HeaderMap,
+    strict_host: bool,
+) -> std::result::Result<(), Violation> {
+    if headers.contains_key(header::CONTENT_LENGTH)
+        && headers.contains_key(header::TRANSFER_ENCODING)
+    {
+        return Err(Violation::LengthAndEncoding);
+    }
+    if headers.contains_key(header::TRANSFER_ENCODING) && !chunked_last(headers) {
+        return Err(Violation::BadEncoding);
+    }
+
+    let mut lengths = headers
+        .get_all(header::CONTENT_LENGTH)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or("").split(','))
+        .map(str::trim);
+    if let Some(first) = lengths.next() {
+        if lengths.any(|length| length != first) {
+            return Err(Violation::ConflictingLength);
+        }
+    }
+
+    let mut hosts = headers.get_all(header::HOST).iter();
+    let host = hosts.next();
+    if hosts.next().is_some() {
+        return Err(Violation::MultipleHosts);
+    }
+
+    if strict_host {
+        if let (Some(authority), Some(host)) = (uri.authority(), host) {
+            let host = host
+                .to_str()
+                .ok()
+                .and_then(|host| host.parse::<Authority>().ok());
+            let matches = match host {
+                Some(host) => same_authority(uri, authority, &host),
+                None => false,
+            };
+            if !matches {
+                return Err(Violation::HostMismatch);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the request's transfer codings, across all its
+/// `Transfer-Encoding` headers, end in `chunked` and use it only there. Each
+/// coding has to be a plain token, so an obfuscated `chunked` like
+/// `"chunked"` or `chunked;q=1` that some proxy might still honor is refused.
+fn chunked_last(headers: &HeaderMap) -> bool {
+    let mut codings = Vec::new();
+    for value in headers.get_all(header::TRANSFER_ENCODING) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        for coding in value.split(',') {
+            let coding = coding.trim_matches(|c| c == ' ' || c == '\t');
+            if coding.is_empty() || !coding.bytes().all(is_tchar) {
+                return false;
+            }
+            codings.push(coding);
+        }
+    }
+
+    let chunked = |coding: &str| coding.eq_ignore_ascii_case("chunked");
+    matches!(codings.last(), Some(last) if chunked(last))
+        && codings.iter().filter(|coding| chunked(coding)).count() == 1
+}
+
+/// Whether `b` may appear in an RFC 9110 token.
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// The framing headers of a request, formatted for logging a rejection.
+pub fn describe(headers: &HeaderMap) -> String {
+    let mut described = Vec::new();
+    for name in &[
+        header::CONTENT_LENGTH,
+        header::TRANSFER_ENCODING,
+        header::HOST,
+    ] {
+        for value in headers.get_all(name) {
+            described.push(format!("{}: {:?}", name, value));
+        }
+    }
+    described.join(", ")
+}
+
+/// Compare two authorities by host (case-insensitively) and port, treating a
+/// missing port as the scheme's default.
+fn same_authority(uri: &Uri, target: &Authority, host: &Authority) -> bool {
+    let default_port = match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    };
+    let port = |authority: &Authority| authority.port_u16().unwrap_or(default_port);
+
+    target.host().eq_ignore_ascii_case(host.host()) && port(target) == port(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        headers
+    }
+
+    fn check_headers(pairs: &[(&str, &str)]) -> std::result::Result<(), Violation> {
+        check(&Uri::from_static("/"), &headers(pairs), false)
+    }
+
+    #[test]
+    fn plain_requests_pass() {
+        assert!(check_headers(&[("host", "example.com")]).is_ok());
+        assert!(check_headers(&[("content-length", "5")]).is_ok());
+        assert!(check_headers(&[("transfer-encoding", "chunked")]).is_ok());
+        assert!(check_headers(&[("transfer-encoding", "gzip, Chunked")]).is_ok());
+        assert!(check_headers(&[
+            ("transfer-encoding", "gzip"),
+            ("transfer-encoding", "chunked")
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn duplicate_content_length() {
+        assert!(check_headers(&[("content-length", "5"), ("content-length", "5")]).is_ok());
+        assert!(check_headers(&[("content-length", "5, 5")]).is_ok());
+        assert!(matches!(
+            check_headers(&[("content-length", "5"), ("content-length", "6")]),
+            Err(Violation::ConflictingLength)
+        ));
+        assert!(matches!(
+            check_headers(&[("content-length", "5, 50")]),
+            Err(Violation::ConflictingLength)
+        ));
+    }
+
+    #[test]
+    fn content_length_with_transfer_encoding() {
+        assert!(matches!(
+            check_headers(&[("content-length", "5"), ("transfer-encoding", "chunked")]),
+            Err(Violation::LengthAndEncoding)
+        ));
+    }
+
+    #[test]
+    fn chunked_not_last() {
+        for te in &["chunked, gzip", "gzip", "identity", "chunked, chunked"] {
+            assert!(
+                matches!(
+                    check_headers(&[("transfer-encoding", te)]),
+                    Err(Violation::BadEncoding)
+                ),
+                "{:?} passed",
+                te
+            );
+        }
+        assert!(matches!(
+            check_headers(&[
+                ("transfer-encoding", "chunked"),
+                ("transfer-encoding", "gzip")
+            ]),
+            Err(Violation::BadEncoding)
+        ));
+    }
+
+    #[test]
+    fn obfuscated_transfer_encoding() {
+        for te in &[
+            "\"chunked\"",
+            "chunked;q=1",
+            "xchunked",
+            "ch\tunked",
+            "chunked,",
+            "chunk ed",
+            "",
+        ] {
+            let value = HeaderValue::from_bytes(te.as_bytes()).unwrap();
+            let mut headers = HeaderMap::new();
+            headers.insert(header::TRANSFER_ENCODING, value);
+            assert!(
+                matches!(
+                    check(&Uri::from_static("/"), &headers, false),
+                    Err(Violation::BadEncoding)
+                ),
+                "{:?} passed",
+                te
+            );
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_bytes(b"chunked\xff").unwrap(),
+        );
+        assert!(check(&Uri::from_static("/"), &headers, false).is_err());
+    }
+
+    #[test]
+    fn multiple_hosts() {
+        assert!(matches!(
+            check_headers(&[("host", "a.example"), ("host", "b.example")]),
+            Err(Violation::MultipleHosts)
+        ));
+    }
+
+    #[test]
+    fn strict_host() {
+        let uri = Uri::from_static("http://example.com/x");
+        let same = headers(&[("host", "EXAMPLE.com:80")]);
+        let other = headers(&[("host", "other.example")]);
+        assert!(check(&uri, &same, true).is_ok());
+        assert!(check(&uri, &other, false).is_ok());
+        assert!(matches!(
+            check(&uri, &other, true),
+            Err(Violation::HostMismatch)
+        ));
+    }
+}
@@ -0,0 +1,567 @@
+//! `GET /_api/hash/<path>?algo=sha256|sha1|md5`, served with `--api`: the
+//! digest of what is served at `<path>`, as lowercase hex, for checking
+//! mirrored downloads. `algo` defaults to sha256. Rewrites aren't followed,
+//! but protected areas are: hashing a protected file takes its token.
+//!
+//! Files are read a chunk at a time on the blocking pool, so a large one is
+//! neither held in memory nor stalls other requests. Digests are cached by
+//! path, modification time, size and algorithm, so asking again about an
+//! unchanged file costs a `stat`. In `--stdin` mode the artifact is hashed
+//! instead, and other paths are a 404 as they are when served.
+//!
+//! Responses from the `--stdin` artifact also carry an RFC 3230 `Digest` of
+//! the whole artifact when the request's `Want-Digest` names an algorithm
+//! this knows.
+
+use http::header::{self, HeaderName, HeaderValue};
+use hyper::body::Bytes;
+use hyper::{Body, Method, Request, Response};
+use sha2::digest::DynDigest;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::query::QueryParams;
+use crate::stdin::{Artifact, Delivery};
+use crate::{urlpath, Error, Result};
+
+/// The path everything hashed is under.
+const PREFIX: &str = "/_api/hash";
+
+/// How much of a file is read at a time.
+const CHUNK: usize = 64 * 1024;
+
+/// The most digests kept. Once the cache is full the oldest is forgotten for
+/// each new one, which only costs rehashing.
+const MAX_ENTRIES: usize = 4096;
+
+const WANT_DIGEST: HeaderName = HeaderName::from_static("want-digest");
+const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Algorithm {
+    Sha256,
+    Sha1,
+    Md5,
+}
+
+impl Algorithm {
+    /// Parse an `algo` query parameter.
+    fn parse(s: &str) -> Result<Algorithm> {
+        match s {
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha1" => Ok(Algorithm::Sha1),
+            "md5" => Ok(Algorithm::Md5),
+            _ => Err(Error::BadQuery(format!(
+                "unknown algo '{}', expected sha256, sha1 or md5",
+                s
+            ))),
+        }
+    }
+
+    /// The algorithm's name in RFC 3230's registry.
+    fn digest_name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha1 => "SHA",
+            Algorithm::Md5 => "MD5",
+        }
+    }
+
+    fn from_digest_name(name: &str) -> Option<Algorithm> {
+        [Algorithm::Sha256, Algorithm::Sha1, Algorithm::Md5]
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.digest_name().eq_ignore_ascii_case(name))
+    }
+
+    fn hasher(self) -> Box<dyn DynDigest> {
+        match self {
+            Algorithm::Sha256 => Box::new(sha2::Sha256::default()),
+            Algorithm::Sha1 => Box::new(sha1::Sha1::default()),
+            Algorithm::Md5 => Box::new(md5::Md5::default()),
+        }
+    }
+}
+
+#[derive(Clone, Eq, Hash, PartialEq)]
+struct Key {
+    path: PathBuf,
+    modified: SystemTime,
+    size: u64,
+    algorithm: Algorithm,
+}
+
+/// The digests worked out so far.
+#[derive(Default)]
+pub struct Hashes {
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    digests: HashMap<Key, Vec<u8>>,
+    /// The keys of `digests`, oldest first.
+    order: VecDeque<Key>,
+}
+
+impl Hashes {
+    fn cached(&self, key: &Key) -> Option<Vec<u8>> {
+        self.cache.lock().unwrap().digests.get(key).cloned()
+    }
+
+    fn insert(&self, key: Key, digest: Vec<u8>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.digests.contains_key(&key) {
+            return;
+        }
+        if cache.order.len() >= MAX_ENTRIES {
+            if let Some(oldest) = cache.order.pop_front() {
+                cache.digests.remove(&oldest);
+            }
+        }
+        cache.order.push_back(key.clone());
+        cache.digests.insert(key, digest);
+    }
+
+    /// The digest of the artifact.
+    async fn artifact(
+        self: &Arc<Self>,
+        artifact: &Artifact,
+        algorithm: Algorithm,
+    ) -> Result<Vec<u8>> {
+        let key = Key {
+            path: PathBuf::from(artifact.path()),
+            modified: artifact.modified(),
+            size: artifact.size() as u64,
+            algorithm,
+        };
+        if let Some(digest) = self.cached(&key) {
+            return Ok(digest);
+        }
+
+        let data = artifact.data().clone();
+        let digest = blocking(move || Ok(digest_bytes(&data, algorithm))).await?;
+        self.insert(key, digest.clone());
+        Ok(digest)
+    }
+
+    /// The digest of the regular file at `path`, which must already be
+    /// canonical.
+    async fn file(self: &Arc<Self>, path: PathBuf, algorithm: Algorithm) -> Result<Vec<u8>> {
+        let hashes = self.clone();
+        blocking(move || {
+            let key = |metadata: &fs::Metadata| -> io::Result<Key> {
+                Ok(Key {
+                    path: path.clone(),
+                    modified: metadata.modified()?,
+                    size: metadata.len(),
+                    algorithm,
+                })
+            };
+            let before = key(&fs::metadata(&path)?)?;
+            if let Some(digest) = hashes.cached(&before) {
+                return Ok(digest);
+            }
+
+            let mut file = File::open(&path)?;
+            let digest = digest_reader(&mut file, algorithm)?;
+            // A file that changed while it was read isn't cached, since the
+            // digest might be of neither version.
+            if key(&file.metadata()?)? == before {
+                hashes.insert(before, digest.clone());
+            }
+            Ok(digest)
+        })
+        .await
+    }
+}
+
+/// The path a request for the hash of something is about, if it is one.
+pub fn target(path: &str) -> Option<&str> {
+    path.strip_prefix(PREFIX)
+        .filter(|path| path.starts_with('/'))
+}
+
+/// Answer a request for the hash of `path`: the artifact if there is one,
+/// otherwise a file under `root`, which `Config::validate` has made
+/// canonical.
+pub async fn serve(
+    hashes: &Arc<Hashes>,
+    root: &Path,
+    stdin: Option<&Arc<Artifact>>,
+    req: &Request<Body>,
+    path: &str,
+) -> Result<Response<Body>> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return crate::other_method(req.method());
+    }
+
+    let params = QueryParams::parse(req.uri().query())?;
+    let algorithm = match params.algo.as_deref() {
+        Some(algo) => Algorithm::parse(algo)?,
+        None => Algorithm::Sha256,
+    };
+
+    let path = urlpath::normalize_strict(path)?;
+    let digest = match stdin {
+        Some(artifact) if path == "/" || path == artifact.path() => {
+            hashes.artifact(artifact, algorithm).await?
+        }
+        Some(_) => return Err(not_found(&path)),
+        None => hashes.file(resolve(root, &path)?, algorithm).await?,
+    };
+
+    let hex = hex(&digest);
+    let resp = Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"))
+        .header(header::CONTENT_LENGTH, hex.len());
+    let body = if req.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::from(hex)
+    };
+
+    resp.body(body).map_err(Error::Http)
+}
+
+/// Add a `Digest` of the artifact to a response serving it, if `want`, the
+/// request's `Want-Digest`, asks for one.
+pub async fn add_digest(
+    hashes: &Arc<Hashes>,
+    artifact: &Artifact,
+    want: Option<&HeaderValue>,
+    resp: &mut Response<Body>,
+) -> Result<()> {
+    resp.headers_mut()
+        .append(header::VARY, HeaderValue::from_static("want-digest"));
+    // The digest is of the bytes piped in, so a transcoded body can't
+    // have it.
+    let delivery = resp.extensions().get::<Delivery>();
+    if !matches!(delivery, Some(Delivery::Full) | Some(Delivery::Range)) {
+        return Ok(());
+    }
+    let algorithm = match want.and_then(wanted) {
+        Some(algorithm) => algorithm,
+        None => return Ok(()),
+    };
+
+    let digest = hashes.artifact(artifact, algorithm).await?;
+    let value = format!("{}={}", algorithm.digest_name(), base64::encode(&digest));
+    let value = HeaderValue::from_str(&value).map_err(|e| Error::Http(e.into()))?;
+    resp.headers_mut().insert(DIGEST, value);
+
+    Ok(())
+}
+
+/// The request's `Want-Digest`, if it has one.
+pub fn want_digest(req: &Request<Body>) -> Option<HeaderValue> {
+    req.headers().get(WANT_DIGEST).cloned()
+}
+
+/// The algorithm a `Want-Digest` prefers, among those this knows. Entries
+/// with a malformed or zero `q` are skipped.
+fn wanted(value: &HeaderValue) -> Option<Algorithm> {
+    let mut best: Option<(f32, Algorithm)> = None;
+    for entry in value.to_str().ok()?.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let algorithm = match parts.next().and_then(Algorithm::from_digest_name) {
+            Some(algorithm) => algorithm,
+            None => continue,
+        };
+        let q = match parts.find_map(|param| param.strip_prefix("q=")) {
+            Some(q) => match q.parse::<f32>() {
+                Ok(q) if (0.0..=1.0).contains(&q) => q,
+                _ => continue,
+            },
+            None => 1.0,
+        };
+        let better = match best {
+            Some((best, _)) => q > best,
+            None => true,
+        };
+        if q > 0.0 && better {
+            best = Some((q, algorithm));
+        }
+    }
+
+    best.map(|(_, algorithm)| algorithm)
+}
+
+/// The regular file a decoded, normalized `path` names under `root`.
+fn resolve(root: &Path, path: &str) -> Result<PathBuf> {
+    let file = root
+        .join(path.trim_start_matches('/'))
+        .canonicalize()
+        .map_err(Error::Io)?;
+    // A symlink out of the root is refused, as it is when served. The root
+    // is only not canonical yet if --allow-missing-root let the server start
+    // before it existed.
+    let inside = file.starts_with(root)
+        || root
+            .canonicalize()
+            .map_or(false, |root| file.starts_with(root));
+    if !inside {
+        return Err(Error::Forbidden(PathBuf::from(path)));
+    }
+    if !file.is_file() {
+        return Err(not_found(path));
+    }
+
+    Ok(file)
+}
+
+/// Run `hash` on the blocking pool.
+async fn blocking<F>(hash: F) -> Result<Vec<u8>>
+where
+    F: FnOnce() -> io::Result<Vec<u8>> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(hash).await {
+        Ok(digest) => digest.map_err(Error::Io),
+        Err(_) => Err(Error::Panicked),
+    }
+}
+
+fn digest_bytes(data: &Bytes, algorithm: Algorithm) -> Vec<u8> {
+    let mut hasher = algorithm.hasher();
+    hasher.update(data);
+    hasher.finalize().into_vec()
+}
+
+fn digest_reader(reader: &mut impl Read, algorithm: Algorithm) -> io::Result<Vec<u8>> {
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0; CHUNK];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(hasher.finalize().into_vec())
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn not_found(path: &str) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mime_guess::mime;
+    use std::io::Write;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap()
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    fn body(resp: Response<Body>) -> String {
+        let bytes = futures::executor::block_on(hyper::body::to_bytes(resp.into_body())).unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn artifact() -> Arc<Artifact> {
+        Arc::new(Artifact::new(
+            "data",
+            b"abc".to_vec(),
+            &mime::TEXT_PLAIN,
+            None,
+        ))
+    }
+
+    fn hash_stdin(uri: &str) -> Result<Response<Body>> {
+        let hashes = Arc::new(Hashes::default());
+        let req = get(uri);
+        let path = target(req.uri().path()).unwrap().to_string();
+        runtime().block_on(serve(
+            &hashes,
+            Path::new("/"),
+            Some(&artifact()),
+            &req,
+            &path,
+        ))
+    }
+
+    #[test]
+    fn targets() {
+        assert_eq!(target("/_api/hash/a/b"), Some("/a/b"));
+        assert_eq!(target("/_api/hash/"), Some("/"));
+        assert_eq!(target("/_api/hash"), None);
+        assert_eq!(target("/_api/hashes/a"), None);
+    }
+
+    #[test]
+    fn known_digests() {
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(body(hash_stdin("/_api/hash/data").unwrap()), sha256);
+        let sha256 = hash_stdin("/_api/hash/data?algo=sha256").unwrap();
+        assert_eq!(sha256.headers()[header::CONTENT_LENGTH], "64");
+        assert_eq!(
+            body(hash_stdin("/_api/hash/?algo=sha1").unwrap()),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            body(hash_stdin("/_api/hash/data?algo=md5").unwrap()),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+    }
+
+    #[test]
+    fn unknown_algorithms_and_paths_are_refused() {
+        assert!(matches!(
+            hash_stdin("/_api/hash/data?algo=crc32"),
+            Err(Error::BadQuery(_))
+        ));
+        assert!(matches!(
+            hash_stdin("/_api/hash/other"),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn files_are_hashed_and_cached_until_they_change() {
+        let root = std::env::temp_dir().join(format!("sufficient-hash-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let file = root.join("a.txt");
+        fs::write(&file, b"abc").unwrap();
+
+        let hashes = Arc::new(Hashes::default());
+        let runtime = runtime();
+        let hash = |path: &str| {
+            let req = get(&format!("/_api/hash{}?algo=md5", path));
+            runtime
+                .block_on(serve(&hashes, &root, None, &req, path))
+                .map(body)
+        };
+        assert_eq!(hash("/a.txt").unwrap(), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hashes.cache.lock().unwrap().digests.len(), 1);
+
+        // Appending changes the size, so the cached digest isn't used.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&file)
+            .unwrap()
+            .write_all(b"d")
+            .unwrap();
+        assert_eq!(hash("/a.txt").unwrap(), "e2fc714c4727ee9395f324cd2e7f331f");
+        assert!(matches!(hash("/missing"), Err(Error::Io(_))));
+        assert!(matches!(hash("/"), Err(Error::Io(_))));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn the_oldest_digests_are_forgotten_first() {
+        let hashes = Hashes::default();
+        let key = |i: usize| Key {
+            path: PathBuf::from(format!("/{}", i)),
+            modified: SystemTime::UNIX_EPOCH,
+            size: 0,
+            algorithm: Algorithm::Sha256,
+        };
+        for i in 0..MAX_ENTRIES {
+            hashes.insert(key(i), vec![1]);
+        }
+        // Again, which doesn't make it any newer.
+        hashes.insert(key(0), vec![1]);
+        hashes.insert(key(MAX_ENTRIES), vec![2]);
+
+        assert!(hashes.cached(&key(0)).is_none());
+        assert_eq!(hashes.cached(&key(1)), Some(vec![1]));
+        assert_eq!(hashes.cached(&key(MAX_ENTRIES)), Some(vec![2]));
+        let cache = hashes.cache.lock().unwrap();
+        assert_eq!(cache.digests.len(), MAX_ENTRIES);
+        assert_eq!(cache.order.len(), MAX_ENTRIES);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinks_out_of_the_root_are_refused() {
+        let dir = std::env::temp_dir().join(format!("sufficient-hash-out-{}", std::process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(dir.join("outside.txt"), b"secret").unwrap();
+        fs::write(root.join("inside.txt"), b"fine").unwrap();
+        let root = root.canonicalize().unwrap();
+        std::os::unix::fs::symlink(dir.join("outside.txt"), root.join("link.txt")).unwrap();
+
+        assert!(resolve(&root, "/inside.txt").is_ok());
+        assert!(matches!(
+            resolve(&root, "/link.txt"),
+            Err(Error::Forbidden(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn large_inputs_are_read_in_chunks() {
+        let data = vec![7; CHUNK * 3 + 5];
+        let streamed = digest_reader(&mut &data[..], Algorithm::Sha256).unwrap();
+        assert_eq!(
+            streamed,
+            digest_bytes(&Bytes::from(data), Algorithm::Sha256)
+        );
+    }
+
+    #[test]
+    fn want_digest_picks_the_preferred_known_algorithm() {
+        let wanted = |value| wanted(&HeaderValue::from_static(value));
+        assert_eq!(wanted("SHA-256"), Some(Algorithm::Sha256));
+        assert_eq!(wanted("md5;q=0.3, sha;q=1"), Some(Algorithm::Sha1));
+        assert_eq!(wanted("unixsum, MD5"), Some(Algorithm::Md5));
+        assert_eq!(wanted("sha-256;q=0"), None);
+        assert_eq!(wanted("sha-256;q=2, md5;q=x"), None);
+        assert_eq!(wanted("crc32c"), None);
+    }
+
+    #[test]
+    fn digest_headers() {
+        let hashes = Arc::new(Hashes::default());
+        let artifact = artifact();
+        let add = |delivery, want| {
+            let mut resp = Response::builder()
+                .extension(delivery)
+                .body(Body::empty())
+                .unwrap();
+            let want = HeaderValue::from_static(want);
+            runtime()
+                .block_on(add_digest(&hashes, &artifact, Some(&want), &mut resp))
+                .unwrap();
+            resp
+        };
+
+        let resp = add(Delivery::Full, "SHA-256");
+        assert_eq!(
+            resp.headers()[DIGEST],
+            "SHA-256=ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0="
+        );
+        assert_eq!(resp.headers()[header::VARY], "want-digest");
+        assert_eq!(
+            add(Delivery::Range, "MD5").headers()[DIGEST],
+            "MD5=kAFQmDzST7DWlj99KOF/cg=="
+        );
+        for delivery in &[Delivery::NotModified, Delivery::Transcoded] {
+            assert!(!add(*delivery, "MD5").headers().contains_key(DIGEST));
+        }
+    }
+}
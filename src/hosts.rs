@@ -0,0 +1,285 @@
+//! Refusing requests for hosts this server isn't, against DNS rebinding.
+//!
+//! A web page can point a name it controls at a server on the visitor's
+//! network, and then read from it as if it were its own origin. The browser
+//! still sends that name as `Host`, so requests naming any host other than
+//! an allowed one are answered with a 421 before anything is served.
+//!
+//! Without `--allowed-hosts`, the allowed hosts are `localhost`, this
+//! machine's hostname, and the IP address the connection arrived on. Requests
+//! without a `Host`, like those of HTTP/1.0 clients, aren't browser requests
+//! and are let through.
+
+use http::header::{self, HeaderMap};
+use http::uri::{Authority, Uri};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+/// How often refused hosts are logged at most.
+const WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Parse a `--allowed-hosts` argument: a name, an IPv4 address, or an IPv6
+/// address with or without brackets, and no port.
+pub fn parse_host(s: &str) -> std::result::Result<String, String> {
+    let host = normalize(s);
+    let valid = match host.parse::<IpAddr>() {
+        Ok(_) => true,
+        Err(_) => {
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+        }
+    };
+    if !valid {
+        return Err(format!(
+            "expected a host name or IP address without a port, got '{}'",
+            s
+        ));
+    }
+
+    Ok(host)
+}
+
+/// The hosts a request may name.
+pub struct AllowedHosts {
+    /// The `--allowed-hosts`, or if there are none the defaults besides the
+    /// connection's own address.
+    names: Vec<String>,
+    /// Whether the IP address a connection arrived on is also allowed.
+    local_ip: bool,
+    log: RefusalLog,
+}
+
+impl AllowedHosts {
+    pub fn new(allowed: &[String]) -> AllowedHosts {
+        if !allowed.is_empty() {
+            return AllowedHosts {
+                names: allowed.to_vec(),
+                local_ip: false,
+                log: RefusalLog::default(),
+            };
+        }
+
+        let mut names = vec!["localhost".to_string()];
+        match hostname::get() {
+            Ok(name) => match name.into_string() {
+                Ok(name) => names.push(normalize(&name)),
+                Err(name) => warn!("ignoring non-UTF-8 hostname {:?}", name),
+            },
+            Err(e) => warn!("failed to get the hostname: {}", e),
+        }
+
+        AllowedHosts {
+            names,
+            local_ip: true,
+            log: RefusalLog::default(),
+        }
+    }
+
+    /// The first host named by the request that isn't allowed for a
+    /// connection from `peer` to `local`, which is logged. Both an
+    /// absolute-form target and `Host` must name an allowed host.
+    pub fn refused(
+        &self,
+        uri: &Uri,
+        headers: &HeaderMap,
+        peer: SocketAddr,
+        local: IpAddr,
+    ) -> Option<String> {
+        let host_header = headers.get(header::HOST).map(|value| {
+            match value
+                .to_str()
+                .ok()
+                .and_then(|v| v.parse::<Authority>().ok())
+                // `Authority` takes any port, even one that isn't a number.
+                .filter(|authority| {
+                    authority.port_u16().is_some()
+                        || authority.as_str().trim_end_matches(':') == authority.host()
+                }) {
+                Some(authority) => authority.host().to_string(),
+                None => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            }
+        });
+
+        // No `Host` and no absolute-form target is let through on purpose:
+        // browsers always send one, and rebinding needs a browser.
+        let host = uri
+            .authority()
+            .map(|authority| authority.host().to_string())
+            .into_iter()
+            .chain(host_header)
+            .find(|host| !self.allows(host, local))?;
+        self.log.refused(&host, peer);
+        Some(host)
+    }
+
+    fn allows(&self, host: &str, local: IpAddr) -> bool {
+        let host = normalize(host);
+        if self.names.contains(&host) {
+            return true;
+        }
+
+        self.local_ip
+            && match host.parse::<IpAddr>() {
+                Ok(ip) => canonical(ip) == canonical(local),
+                Err(_) => false,
+            }
+    }
+}
+
+/// Lowercase a host and drop IPv6 brackets and a trailing root `.`.
+fn normalize(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(ip) => ip,
+        None => host.strip_suffix('.').unwrap_or(host),
+    };
+    host.to_ascii_lowercase()
+}
+
+/// An IPv4-mapped IPv6 address as the IPv4 address it stands for.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Warns about refused hosts, at most once per `WARN_INTERVAL`, so a page
+/// probing in a loop can't flood the log.
+#[derive(Default)]
+struct RefusalLog {
+    last: Mutex<Option<Instant>>,
+    suppressed: AtomicU64,
+}
+
+impl RefusalLog {
+    fn refused(&self, host: &str, peer: SocketAddr) {
+        let mut last = self.last.lock().unwrap();
+        let now = Instant::now();
+        if matches!(*last, Some(at) if now.duration_since(at) < WARN_INTERVAL) {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            debug!("refusing request from {} for host {}", peer, host);
+            return;
+        }
+        *last = Some(now);
+
+        let suppressed = self.suppressed.swap(0, Ordering::Relaxed);
+        if suppressed > 0 {
+            warn!(
+                "refusing request from {} for host {} ({} more refused since the last warning)",
+                peer, host, suppressed
+            );
+        } else {
+            warn!("refusing request from {} for host {}", peer, host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+
+    fn peer() -> SocketAddr {
+        "192.0.2.9:50000".parse().unwrap()
+    }
+
+    /// The host `hosts` refuses for a request to `target` with `host` as its
+    /// `Host`, arriving on `local`.
+    fn refused(
+        hosts: &AllowedHosts,
+        target: &str,
+        host: Option<&str>,
+        local: &str,
+    ) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        if let Some(host) = host {
+            headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+        }
+        let uri: Uri = target.parse().unwrap();
+        hosts.refused(&uri, &headers, peer(), local.parse().unwrap())
+    }
+
+    #[test]
+    fn the_defaults_are_localhost_the_hostname_and_the_local_address() {
+        let hosts = AllowedHosts::new(&[]);
+        let allowed = |host: &str, local: &str| refused(&hosts, "/", Some(host), local).is_none();
+
+        assert!(allowed("localhost", "127.0.0.1"));
+        assert!(allowed("LOCALHOST.", "127.0.0.1"));
+        let name = hostname::get().unwrap().into_string().unwrap();
+        assert!(allowed(&name, "127.0.0.1"));
+        assert!(allowed(&name.to_ascii_uppercase(), "127.0.0.1"));
+
+        // The address the connection arrived on, however it is written.
+        assert!(allowed("192.0.2.1", "192.0.2.1"));
+        assert!(allowed("192.0.2.1", "::ffff:192.0.2.1"));
+        assert!(allowed("[2001:db8::1]", "2001:db8::1"));
+        assert!(allowed("[2001:DB8:0::1]", "2001:db8::1"));
+        // But not another of this machine's addresses.
+        assert!(!allowed("192.0.2.2", "192.0.2.1"));
+        assert!(!allowed("127.0.0.1", "192.0.2.1"));
+
+        assert_eq!(
+            refused(&hosts, "/", Some("attacker.example"), "127.0.0.1"),
+            Some("attacker.example".to_string())
+        );
+    }
+
+    #[test]
+    fn ports_and_brackets_are_ignored() {
+        let hosts = AllowedHosts::new(&["example.com".to_string(), parse_host("[::1]").unwrap()]);
+        let allowed = |host: &str| refused(&hosts, "/", Some(host), "192.0.2.1").is_none();
+        assert!(allowed("example.com:8080"));
+        assert!(allowed("Example.COM:80"));
+        assert!(allowed("[::1]:8080"));
+        assert!(allowed("[::1]"));
+        assert!(!allowed("example.org:8080"));
+        assert!(!allowed("example.com.evil:8080"));
+        // The given list replaces the defaults.
+        assert!(!allowed("localhost"));
+        assert!(!allowed("192.0.2.1"));
+        // A Host that isn't a host at all is refused too.
+        assert!(!allowed("example.com:port"));
+        assert!(allowed("example.com:"));
+    }
+
+    #[test]
+    fn absolute_targets_have_to_be_allowed_too() {
+        let hosts = AllowedHosts::new(&["example.com".to_string()]);
+        let local = "192.0.2.1";
+        assert!(refused(&hosts, "http://example.com/", Some("example.com"), local).is_none());
+        assert_eq!(
+            refused(&hosts, "http://evil.example/", Some("example.com"), local),
+            Some("evil.example".to_string())
+        );
+        assert_eq!(
+            refused(&hosts, "http://example.com/", Some("evil.example"), local),
+            Some("evil.example".to_string())
+        );
+    }
+
+    #[test]
+    fn requests_without_a_host_are_let_through() {
+        let hosts = AllowedHosts::new(&["example.com".to_string()]);
+        assert!(refused(&hosts, "/", None, "192.0.2.1").is_none());
+        assert!(refused(&hosts, "http://example.com/", None, "192.0.2.1").is_none());
+        assert!(refused(&hosts, "http://evil.example/", None, "192.0.2.1").is_some());
+    }
+
+    #[test]
+    fn allowed_hosts_are_parsed_without_ports() {
+        assert_eq!(parse_host("Example.COM.").unwrap(), "example.com");
+        assert_eq!(parse_host("[2001:db8::1]").unwrap(), "2001:db8::1");
+        assert_eq!(parse_host("192.0.2.1").unwrap(), "192.0.2.1");
+        assert!(parse_host("example.com:8080").is_err());
+        assert!(parse_host("").is_err());
+        assert!(parse_host("exa mple.com").is_err());
+    }
+}
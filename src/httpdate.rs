@@ -0,0 +1,337 @@
+//! HTTP dates: parsing the three formats of RFC 7231 section 7.1.1.1,
+//! formatting IMF-fixdate, and comparing client dates against our own
+//! modification times.
+//!
+//! Clients' clocks are rarely exactly ours, and modification times are only
+//! sent to the second, so conditional requests are compared with a tolerance
+//! of `--date-skew-tolerance`.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const LONG_DAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format a time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    // 1970-01-01 was a Thursday.
+    let weekday = DAYS[((days + 3) % 7) as usize];
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        weekday,
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// The UTC year, month, day, hour and minute of `time`.
+pub fn utc(time: SystemTime) -> (i64, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let hour = (secs % 86400 / 3600) as u32;
+    let minute = (secs % 3600 / 60) as u32;
+    (year, month, day, hour, minute)
+}
+
+/// Parse an HTTP date in any of its three formats: IMF-fixdate, the obsolete
+/// RFC 850 format, or asctime. Returns `None` for anything else.
+pub fn parse(s: &str) -> Option<SystemTime> {
+    let s = s.trim();
+    parse_imf_fixdate(s)
+        .or_else(|| parse_rfc850(s))
+        .or_else(|| parse_asctime(s))
+}
+
+/// Whether a resource last modified at `modified` is unchanged since the
+/// client's `since`.
+///
+/// A `since` further in the future than `tolerance` is a client clock that
+/// can't be trusted, and is ignored. A resource modified within `tolerance`
+/// of now may still change within the same second, and is never reported
+/// unchanged.
+pub fn unmodified_since(
+    modified: SystemTime,
+    since: SystemTime,
+    now: SystemTime,
+    tolerance: Duration,
+) -> bool {
+    if since > now + tolerance {
+        return false;
+    }
+    match now.duration_since(modified) {
+        Ok(age) if age >= tolerance => {}
+        _ => return false,
+    }
+
+    // Last-Modified only carries whole seconds.
+    let modified = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let since = since
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    modified <= since
+}
+
+/// `Sun, 06 Nov 1994 08:49:37 GMT`
+fn parse_imf_fixdate(s: &str) -> Option<SystemTime> {
+    let (weekday, rest) = s.split_at(s.find(", ")?);
+    if !DAYS.contains(&weekday) {
+        return None;
+    }
+    let fields: Vec<&str> = rest[2..].split(' ').collect();
+    match fields.as_slice() {
+        [day, month, year, time, "GMT"] if day.len() == 2 && year.len() == 4 => {
+            to_time(year.parse().ok()?, month, day, time)
+        }
+        _ => None,
+    }
+}
+
+/// `Sunday, 06-Nov-94 08:49:37 GMT`
+fn parse_rfc850(s: &str) -> Option<SystemTime> {
+    let (weekday, rest) = s.split_at(s.find(", ")?);
+    if !LONG_DAYS.contains(&weekday) {
+        return None;
+    }
+    let fields: Vec<&str> = rest[2..].split(' ').collect();
+    let (date, time) = match fields.as_slice() {
+        [date, time, "GMT"] => (date, time),
+        _ => return None,
+    };
+    let date: Vec<&str> = date.split('-').collect();
+    match date.as_slice() {
+        [day, month, year] if day.len() == 2 && year.len() == 2 => {
+            to_time(full_year(year.parse().ok()?), month, day, time)
+        }
+        _ => None,
+    }
+}
+
+/// `Sun Nov  6 08:49:37 1994`
+fn parse_asctime(s: &str) -> Option<SystemTime> {
+    let fields: Vec<&str> = s.split(' ').filter(|field| !field.is_empty()).collect();
+    match fields.as_slice() {
+        [weekday, month, day, time, year]
+            if DAYS.contains(weekday) && day.len() <= 2 && year.len() == 4 =>
+        {
+            to_time(year.parse().ok()?, month, day, time)
+        }
+        _ => None,
+    }
+}
+
+/// The year a two-digit RFC 850 year stands for: the most recent one with
+/// those digits that isn't more than 50 years in the future.
+fn full_year(yy: i64) -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let this_year = civil_from_days((now / 86400) as i64).0;
+
+    let mut year = this_year - this_year % 100 + yy;
+    if year > this_year + 50 {
+        year -= 100;
+    }
+    year
+}
+
+fn to_time(year: i64, month: &str, day: &str, time: &str) -> Option<SystemTime> {
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let day: u32 = day.parse().ok()?;
+    if !day_exists(year, month, day) || year < 1970 {
+        return None;
+    }
+
+    let parts: Vec<&str> = time.split(':').collect();
+    let (hour, minute, second) = match parts.as_slice() {
+        [h, m, s] if h.len() == 2 && m.len() == 2 && s.len() == 2 => (
+            h.parse::<u64>().ok()?,
+            m.parse::<u64>().ok()?,
+            s.parse::<u64>().ok()?,
+        ),
+        _ => return None,
+    };
+    // A leap second is sent as :60.
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day) as u64;
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn day_exists(year: i64, month: u32, day: u32) -> bool {
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    (1..=days).contains(&day)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, after Howard
+/// Hinnant's `days_from_civil`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// The inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example date of RFC 9110 section 5.6.7.
+    fn example() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(784111777)
+    }
+
+    #[test]
+    fn parses_the_rfc_examples() {
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(example()));
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), Some(example()));
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), Some(example()));
+    }
+
+    #[test]
+    fn formats_imf_fixdate() {
+        assert_eq!(format(example()), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            format(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        for &secs in &[0, 784111777, 951782400, 1_700_000_000, 4_102_444_799] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs);
+            assert_eq!(parse(&format(time)), Some(time));
+        }
+    }
+
+    #[test]
+    fn refuses_garbage() {
+        for s in &[
+            "",
+            "not-a-date",
+            "Sun, 06 Nov 1994 08:49:37",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 6 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 94 08:49:37 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 31 Nov 1994 08:49:37 GMT",
+            "Sun, 29 Feb 1900 00:00:00 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:61:37 GMT",
+            "Sun, 06 Nov 1994 8:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Xyz, 06 Nov 1994 08:49:37 GMT",
+            "Sun,06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-1994 08:49:37 GMT",
+            "Sun, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 94",
+            "Sun Nov 123 08:49:37 1994",
+            "Sun, 06 Nov 1994 08:49:37 GMT extra",
+            "Sun, -6 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 99999999999999999999 08:49:37 GMT",
+            "\u{fffd}, 06 Nov 1994 08:49:37 GMT",
+        ] {
+            assert_eq!(parse(s), None, "{:?} parsed", s);
+        }
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        assert_eq!(parse("  Sun, 06 Nov 1994 08:49:37 GMT "), Some(example()));
+    }
+
+    #[test]
+    fn leap_seconds_are_accepted() {
+        assert!(parse("Sat, 31 Dec 2016 23:59:60 GMT").is_some());
+    }
+
+    #[test]
+    fn unmodified_since_allows_for_skew() {
+        let tolerance = Duration::from_secs(2);
+        let modified = example();
+        let now = modified + Duration::from_secs(60);
+        let second = Duration::from_secs(1);
+
+        assert!(unmodified_since(modified, modified, now, tolerance));
+        assert!(unmodified_since(
+            modified,
+            modified + second,
+            now,
+            tolerance
+        ));
+        // Whole seconds only: a fraction after Last-Modified is the same time.
+        let fraction = modified + Duration::from_millis(500);
+        assert!(unmodified_since(fraction, modified, now, tolerance));
+        assert!(!unmodified_since(
+            modified,
+            modified - second,
+            now,
+            tolerance
+        ));
+        // A client date too far in the future isn't trusted.
+        assert!(!unmodified_since(
+            modified,
+            now + 3 * second,
+            now,
+            tolerance
+        ));
+        // Something modified within the tolerance of now may change again.
+        assert!(!unmodified_since(now - second, now, now, tolerance));
+    }
+}
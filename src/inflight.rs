@@ -0,0 +1,353 @@
+//! The requests in flight, for reporting what shutdown is waiting on.
+//!
+//! Every request is registered when it arrives and forgotten once its
+//! response is sent or the client goes away. Requests are spread over a few
+//! separately locked shards, so registering one rarely waits on another.
+//!
+//! During a graceful shutdown each server logs what it still has in flight
+//! every `REPORT_EVERY`, and which requests it cut off when
+//! `--shutdown-timeout` ran out.
+
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::{HeaderMap, Response};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::units::{Elapsed, Size};
+
+/// How often shutdown reports the requests it is waiting on.
+pub const REPORT_EVERY: Duration = Duration::from_secs(2);
+
+const SHARDS: usize = 16;
+
+/// The most of a body handed on at once.
+const CHUNK: usize = 64 * 1024;
+
+/// Response lengths that aren't known yet, or at all.
+const NO_RESPONSE: u64 = u64::MAX;
+const UNSIZED: u64 = u64::MAX - 1;
+
+#[derive(Default)]
+pub struct Registry {
+    next: AtomicU64,
+    shards: [Mutex<HashMap<u64, Entry>>; SHARDS],
+}
+
+struct Entry {
+    /// The server handling it, when there are several.
+    worker: Option<usize>,
+    peer: SocketAddr,
+    path: String,
+    started: Instant,
+    progress: Arc<Progress>,
+}
+
+struct Progress {
+    /// The response's length, or `NO_RESPONSE` or `UNSIZED`.
+    length: AtomicU64,
+    sent: AtomicU64,
+}
+
+/// One request's place in the registry, given up when it is dropped.
+pub struct InFlight {
+    id: u64,
+    registry: Arc<Registry>,
+    progress: Arc<Progress>,
+}
+
+impl Registry {
+    /// Register a request from `peer` for `path`, handled by `worker`.
+    pub fn start(
+        self: &Arc<Registry>,
+        worker: Option<usize>,
+        peer: SocketAddr,
+        path: &str,
+    ) -> InFlight {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let progress = Arc::new(Progress {
+            length: AtomicU64::new(NO_RESPONSE),
+            sent: AtomicU64::new(0),
+        });
+        let entry = Entry {
+            worker,
+            peer,
+            path: path.to_string(),
+            started: Instant::now(),
+            progress: progress.clone(),
+        };
+        self.shard(id).lock().unwrap().insert(id, entry);
+        InFlight {
+            id,
+            registry: self.clone(),
+            progress,
+        }
+    }
+
+    /// The requests `worker` has in flight, longest running first.
+    pub fn active(&self, worker: Option<usize>) -> Vec<Active> {
+        let mut active: Vec<Active> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard
+                    .values()
+                    .filter(|entry| entry.worker == worker)
+                    .map(Active::of)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        active.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+        active
+    }
+
+    /// Log what `worker` still has in flight every `REPORT_EVERY`, starting
+    /// now, for as long as it is polled.
+    pub async fn report(self: Arc<Registry>, worker: Option<usize>) {
+        let mut ticks = tokio::time::interval(REPORT_EVERY);
+        loop {
+            ticks.tick().await;
+            let active = self.active(worker);
+            if active.is_empty() {
+                continue;
+            }
+            info!("waiting on {} requests in flight", active.len());
+            for request in active {
+                info!("in flight: {}", request);
+            }
+        }
+    }
+
+    /// Log the requests `worker` is about to cut off.
+    pub fn log_cut_off(&self, worker: Option<usize>) {
+        for request in self.active(worker) {
+            warn!("cut off: {}", request);
+        }
+    }
+
+    fn shard(&self, id: u64) -> &Mutex<HashMap<u64, Entry>> {
+        &self.shards[id as usize % SHARDS]
+    }
+}
+
+impl InFlight {
+    /// Follow `resp` as it is sent, keeping the request registered until it
+    /// has been.
+    pub fn track<B: HttpBody>(self, resp: Response<B>) -> Response<Tracked<B>> {
+        let length = resp.body().size_hint().exact().unwrap_or(UNSIZED);
+        self.progress.length.store(length, Ordering::Relaxed);
+        resp.map(|body| Tracked {
+            body,
+            rest: Bytes::new(),
+            request: self,
+        })
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.registry
+            .shard(self.id)
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+    }
+}
+
+/// A request in flight, as reported.
+#[derive(Debug)]
+pub struct Active {
+    pub peer: SocketAddr,
+    pub path: String,
+    pub elapsed: Duration,
+    pub progress: Sent,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Sent {
+    NoResponse,
+    /// So much of a response of unknown length.
+    Unsized(u64),
+    /// So much of a response of this length.
+    Of(u64, u64),
+}
+
+impl Active {
+    fn of(entry: &Entry) -> Active {
+        let length = entry.progress.length.load(Ordering::Relaxed);
+        let sent = entry.progress.sent.load(Ordering::Relaxed);
+        Active {
+            peer: entry.peer,
+            path: entry.path.clone(),
+            elapsed: entry.started.elapsed(),
+            progress: match length {
+                NO_RESPONSE => Sent::NoResponse,
+                UNSIZED => Sent::Unsized(sent),
+                length => Sent::Of(sent, length),
+            },
+        }
+    }
+}
+
+/// E.g. `127.0.0.1:41234 /big.iso, 3 MiB of 4 GiB left after 12s 5ms`.
+impl fmt::Display for Active {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}, ", self.peer, self.path)?;
+        match self.progress {
+            Sent::NoResponse => write!(f, "no response yet")?,
+            Sent::Unsized(sent) => write!(f, "{} sent", Size(sent))?,
+            Sent::Of(sent, length) => write!(
+                f,
+                "{} of {} left",
+                Size(length.saturating_sub(sent)),
+                Size(length)
+            )?,
+        }
+        write!(f, " after {}", Elapsed(self.elapsed))
+    }
+}
+
+/// A response body that counts what has been sent of it.
+///
+/// Data is handed on at most `CHUNK` at a time, since hyper only takes more
+/// once it has written most of what it has. Counting what it was handed then
+/// stays close to what has been sent, even for a body that is one big chunk.
+pub struct Tracked<B> {
+    body: B,
+    /// What is left of the last chunk of `body`.
+    rest: Bytes,
+    request: InFlight,
+}
+
+impl<B: HttpBody<Data = Bytes> + Unpin> HttpBody for Tracked<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Option<std::result::Result<Bytes, B::Error>>> {
+        if self.rest.is_empty() {
+            match Pin::new(&mut self.body).poll_data(cx) {
+                Poll::Ready(Some(Ok(data))) => self.rest = data,
+                polled => return polled,
+            }
+        }
+
+        let len = CHUNK.min(self.rest.len());
+        let data = self.rest.split_to(len);
+        let sent = &self.request.progress.sent;
+        sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Poll::Ready(Some(Ok(data)))
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<std::result::Result<Option<HeaderMap>, B::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.rest.is_empty() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let body = self.body.size_hint();
+        let rest = self.rest.len() as u64;
+        let mut hint = SizeHint::new();
+        hint.set_lower(body.lower() + rest);
+        if let Some(upper) = body.upper() {
+            hint.set_upper(upper + rest);
+        }
+        hint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Body;
+
+    fn peer() -> SocketAddr {
+        "192.0.2.1:41234".parse().unwrap()
+    }
+
+    #[test]
+    fn requests_are_registered_until_their_response_is_sent() {
+        let registry = Arc::new(Registry::default());
+        let request = registry.start(None, peer(), "/data.txt");
+        let other = registry.start(Some(1), peer(), "/other");
+
+        let active = registry.active(None);
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].path, "/data.txt");
+        assert_eq!(active[0].progress, Sent::NoResponse);
+        assert!(active[0]
+            .to_string()
+            .starts_with("192.0.2.1:41234 /data.txt, no response yet after "));
+
+        let resp = request.track(Response::new(Body::from("0123456789")));
+        assert_eq!(registry.active(None)[0].progress, Sent::Of(0, 10));
+        // A client gone before the response is sent.
+        drop(resp);
+        assert!(registry.active(None).is_empty());
+
+        let request = registry.start(None, peer(), "/data.txt");
+        let mut resp = request.track(Response::new(Body::from(vec![b'x'; CHUNK + 10])));
+        let sent = futures::executor::block_on(resp.body_mut().data());
+        assert_eq!(sent.unwrap().unwrap().len(), CHUNK);
+        assert_eq!(resp.body().size_hint().exact(), Some(10));
+        assert!(!resp.body().is_end_stream());
+        let active = registry.active(None);
+        assert_eq!(
+            active[0].progress,
+            Sent::Of(CHUNK as u64, CHUNK as u64 + 10)
+        );
+        assert!(active[0]
+            .to_string()
+            .contains(", 10 B of 64 KiB left after "));
+        let sent = futures::executor::block_on(resp.body_mut().data());
+        assert_eq!(sent.unwrap().unwrap().len(), 10);
+        assert!(resp.body().is_end_stream());
+        drop(resp);
+
+        let request = registry.start(None, peer(), "/stream");
+        let chunks = futures::stream::iter(vec![Ok::<_, std::io::Error>("abc")]);
+        let mut resp = request.track(Response::new(Body::wrap_stream(chunks)));
+        assert_eq!(registry.active(None)[0].progress, Sent::Unsized(0));
+        futures::executor::block_on(resp.body_mut().data());
+        let active = registry.active(None);
+        assert_eq!(active[0].progress, Sent::Unsized(3));
+        assert!(active[0].to_string().contains(", 3 B sent after "));
+
+        drop(resp);
+        assert!(registry.active(None).is_empty());
+        assert_eq!(registry.active(Some(1)).len(), 1);
+        drop(other);
+        assert!(registry.active(Some(1)).is_empty());
+    }
+
+    #[test]
+    fn reports_put_the_longest_running_first() {
+        let registry = Arc::new(Registry::default());
+        let _first = registry.start(None, peer(), "/first");
+        std::thread::sleep(Duration::from_millis(10));
+        let _second = registry.start(None, peer(), "/second");
+        let paths: Vec<_> = registry
+            .active(None)
+            .into_iter()
+            .map(|active| active.path)
+            .collect();
+        assert_eq!(paths, ["/first", "/second"]);
+    }
+}
@@ -0,0 +1,253 @@
+//! Per-client-IP concurrency limits.
+//!
+//! Each client address gets a semaphore with `--max-per-ip` permits, created
+//! on its first request and removed again once the client has nothing in
+//! flight, so the map only ever holds currently active clients.
+
+use http::header::HeaderMap;
+use http::status::StatusCode;
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::{Body, Response};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::Result;
+
+#[derive(Clone)]
+pub struct IpLimiter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    max: usize,
+    wait: Duration,
+    clients: Mutex<HashMap<IpAddr, Arc<Semaphore>>>,
+}
+
+/// A slot held by one request. Dropping it frees the slot, and forgets the
+/// client entirely if this was its last one.
+pub struct IpPermit {
+    ip: IpAddr,
+    permit: Option<OwnedSemaphorePermit>,
+    inner: Arc<Inner>,
+}
+
+impl IpLimiter {
+    pub fn new(max: usize, wait: Duration) -> IpLimiter {
+        IpLimiter {
+            inner: Arc::new(Inner {
+                max,
+                wait,
+                clients: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Take a slot for `ip`, waiting up to the configured queue time for one
+    /// to free up. Returns `None` if the client is still over its limit.
+    pub async fn acquire(&self, ip: IpAddr) -> Option<IpPermit> {
+        let semaphore = {
+            let mut clients = self.inner.clients.lock().unwrap();
+            let max = self.inner.max;
+            clients
+                .entry(ip)
+                .or_insert_with(|| Arc::new(Semaphore::new(max)))
+                .clone()
+        };
+
+        let permit = if self.inner.wait == Duration::from_millis(0) {
+            semaphore.try_acquire_owned().ok()
+        } else {
+            tokio::time::timeout(self.inner.wait, semaphore.acquire_owned())
+                .await
+                .ok()
+                .and_then(|permit| permit.ok())
+        };
+
+        // Acquiring took the semaphore clone by value, so by now it is either
+        // in the permit or dropped, and an empty permit below finds the map
+        // holding the last reference when it checks whether the client is idle.
+        let permit = IpPermit {
+            ip,
+            permit,
+            inner: self.inner.clone(),
+        };
+        if permit.permit.is_some() {
+            Some(permit)
+        } else {
+            // Dropping the empty permit evicts the entry if nobody holds it.
+            None
+        }
+    }
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        self.permit.take();
+
+        // Every clone of a client's semaphore is taken under this lock, so if
+        // the map holds the only reference and all permits are back, no other
+        // request can be using it.
+        let mut clients = self.inner.clients.lock().unwrap();
+        let idle = match clients.get(&self.ip) {
+            Some(semaphore) => {
+                Arc::strong_count(semaphore) == 1 && semaphore.available_permits() == self.inner.max
+            }
+            None => false,
+        };
+        if idle {
+            clients.remove(&self.ip);
+        }
+    }
+}
+
+/// Keep the slot `check_and_serve` left on the response, if any, until the
+/// response body has been fully sent (or the connection dropped), since that
+/// is where a download spends its time.
+pub fn hold_until_sent<B>(mut resp: Response<B>) -> Response<Held<B>> {
+    let permit = resp.extensions_mut().remove::<IpPermit>();
+    resp.map(|body| Held {
+        body,
+        _permit: permit,
+    })
+}
+
+/// A response body holding on to a client's slot. Everything else is passed
+/// through, the size hint included, so hyper can still send the body with a
+/// `Content-Length`.
+pub struct Held<B = Body> {
+    body: B,
+    _permit: Option<IpPermit>,
+}
+
+impl<B> HttpBody for Held<B>
+where
+    B: HttpBody<Data = Bytes, Error = hyper::Error> + Unpin,
+{
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Bytes, hyper::Error>>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::result::Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Parse a `--max-per-ip` argument, which has to allow at least one request.
+pub fn parse_max(s: &str) -> std::result::Result<usize, String> {
+    match s.trim().parse::<usize>() {
+        Ok(0) => Err("must allow at least 1 request".to_string()),
+        Ok(max) => Ok(max),
+        Err(_) => Err(format!("'{}' isn't a number of requests", s)),
+    }
+}
+
+/// The 429 response for a client that is over its limit.
+pub fn too_many_requests() -> Result<Response<Body>> {
+    let resp = Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .body(Body::empty())
+        .map_err(crate::Error::Http)?;
+
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use tokio::runtime::Runtime;
+
+    fn clients(limiter: &IpLimiter) -> usize {
+        limiter.inner.clients.lock().unwrap().len()
+    }
+
+    #[test]
+    fn max_must_be_positive() {
+        assert_eq!(parse_max("4"), Ok(4));
+        assert!(parse_max("0").is_err());
+        assert!(parse_max("-1").is_err());
+        assert!(parse_max("many").is_err());
+    }
+
+    #[test]
+    fn clients_are_refused_over_the_limit_and_forgotten_after() {
+        let runtime = Runtime::new().unwrap();
+        let limiter = IpLimiter::new(1, Duration::from_millis(0));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let held = runtime.block_on(limiter.acquire(ip)).unwrap();
+        assert!(runtime.block_on(limiter.acquire(ip)).is_none());
+        assert!(runtime.block_on(limiter.acquire(other)).is_some());
+        assert_eq!(clients(&limiter), 1);
+
+        drop(held);
+        assert_eq!(clients(&limiter), 0);
+        assert!(runtime.block_on(limiter.acquire(ip)).is_some());
+        assert_eq!(clients(&limiter), 0);
+    }
+
+    #[test]
+    fn a_client_that_timed_out_is_forgotten_too() {
+        let runtime = Runtime::new().unwrap();
+        let limiter = IpLimiter::new(1, Duration::from_millis(10));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let held = runtime.block_on(limiter.acquire(ip)).unwrap();
+        assert!(runtime.block_on(limiter.acquire(ip)).is_none());
+        drop(held);
+        assert_eq!(clients(&limiter), 0);
+    }
+
+    #[test]
+    fn the_slot_is_held_until_the_body_is_sent() {
+        let runtime = Runtime::new().unwrap();
+        let limiter = IpLimiter::new(1, Duration::from_millis(0));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let mut resp = Response::new(Body::from("hello"));
+        let permit = runtime.block_on(limiter.acquire(ip)).unwrap();
+        resp.extensions_mut().insert(permit);
+        let resp = hold_until_sent(resp);
+        assert!(resp.extensions().get::<IpPermit>().is_none());
+        assert!(runtime.block_on(limiter.acquire(ip)).is_none());
+
+        let body = block_on(hyper::body::to_bytes(resp.into_body())).unwrap();
+        assert_eq!(body, "hello");
+        assert!(runtime.block_on(limiter.acquire(ip)).is_some());
+    }
+
+    #[test]
+    fn held_bodies_keep_their_size_hint() {
+        let resp = hold_until_sent(Response::new(Body::from("hello")));
+        assert_eq!(resp.body().size_hint().exact(), Some(5));
+        assert!(!resp.body().is_end_stream());
+
+        let resp = hold_until_sent(Response::new(Body::empty()));
+        assert_eq!(resp.body().size_hint().exact(), Some(0));
+        assert!(resp.body().is_end_stream());
+    }
+}
@@ -0,0 +1,272 @@
+//! Log files that can't hold up startup.
+//!
+//! Opening a log file on a hung network mount blocks indefinitely, so files
+//! are opened on a thread of their own. Startup waits up to
+//! `OPEN_TIMEOUT` for that; if the file isn't open by then, the log goes to
+//! stderr until it is, and the thread keeps retrying in the background.
+//!
+//! Once open, lines are handed to `tracing_appender`'s writer thread, which
+//! either drops them or makes the logging thread wait when it falls behind,
+//! as chosen with `--log-backpressure`.
+
+use crate::httpdate;
+use clap::ValueEnum;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// How long startup waits for a log file to open.
+const OPEN_TIMEOUT: Duration = Duration::from_secs(2);
+
+const MIN_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// What to do with log lines when the file can't keep up.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Backpressure {
+    /// Drop them, so serving never waits on the log.
+    Drop,
+    /// Make the thread that logs wait, so no line is lost.
+    Block,
+}
+
+/// How far opening a log file got before startup went on without it.
+pub enum Opened {
+    Ready,
+    /// The file didn't open in time, and is still being waited for.
+    Pending,
+    /// The file couldn't be opened, and is being retried.
+    Failed(io::Error),
+}
+
+/// A `MakeWriter` that writes to its file once the file is open, and to
+/// stderr until then.
+#[derive(Clone)]
+pub struct LogFile {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    writer: RwLock<Option<NonBlocking>>,
+    guard: Mutex<Option<WorkerGuard>>,
+}
+
+impl LogFile {
+    /// Start opening the log file `name` in `dir`, rotated per `rotation`.
+    pub fn open(
+        dir: &Path,
+        name: &OsStr,
+        rotation: Rotation,
+        backpressure: Backpressure,
+    ) -> (LogFile, Opened) {
+        let file = LogFile::new(dir.join(name));
+        let (first, first_done) = mpsc::channel();
+        let dir = dir.to_path_buf();
+        let name = name.to_os_string();
+
+        let background = file.clone();
+        thread::spawn(move || {
+            let mut retry = MIN_RETRY;
+            loop {
+                match probe(&dir, &name, &rotation) {
+                    Ok(()) => {
+                        let appender = RollingFileAppender::new(rotation, &dir, &name);
+                        background.attach(backpressure, appender);
+                        // Gone once startup stopped waiting.
+                        if first.send(Ok(())).is_err() {
+                            info!("now logging to {}", background.inner.path.display());
+                        }
+                        return;
+                    }
+                    Err(e) => {
+                        if first.send(Err(e)).is_err() {
+                            debug!(
+                                "still failing to open {}, retrying in {:?}",
+                                background.inner.path.display(),
+                                retry
+                            );
+                        }
+                        thread::sleep(retry);
+                        retry = (retry * 2).min(MAX_RETRY);
+                    }
+                }
+            }
+        });
+
+        let opened = match first_done.recv_timeout(OPEN_TIMEOUT) {
+            Ok(Ok(())) => Opened::Ready,
+            Ok(Err(e)) => Opened::Failed(e),
+            Err(_) => Opened::Pending,
+        };
+        (file, opened)
+    }
+
+    /// A log going to `writer`, which needs no opening.
+    pub fn ready<W: Write + Send + Sync + 'static>(
+        path: &Path,
+        backpressure: Backpressure,
+        writer: W,
+    ) -> LogFile {
+        let file = LogFile::new(path.to_path_buf());
+        file.attach(backpressure, writer);
+        file
+    }
+
+    /// A log that only ever goes to stderr.
+    pub fn stderr() -> LogFile {
+        LogFile::new(PathBuf::from("stderr"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// A guard that flushes the file when it is dropped, which must be held
+    /// until the server exits.
+    pub fn flush_on_drop(&self) -> FlushOnDrop {
+        FlushOnDrop(self.clone())
+    }
+
+    fn new(path: PathBuf) -> LogFile {
+        LogFile {
+            inner: Arc::new(Inner {
+                path,
+                writer: RwLock::new(None),
+                guard: Mutex::new(None),
+            }),
+        }
+    }
+
+    fn attach<W: Write + Send + Sync + 'static>(&self, backpressure: Backpressure, writer: W) {
+        let (writer, guard) = NonBlockingBuilder::default()
+            .lossy(matches!(backpressure, Backpressure::Drop))
+            .finish(writer);
+        *self.inner.guard.lock().unwrap() = Some(guard);
+        *self.inner.writer.write().unwrap() = Some(writer);
+    }
+}
+
+impl MakeWriter for LogFile {
+    type Writer = Writer;
+
+    fn make_writer(&self) -> Writer {
+        match &*self.inner.writer.read().unwrap() {
+            Some(writer) => Writer::File(writer.clone()),
+            None => Writer::Stderr(io::stderr()),
+        }
+    }
+}
+
+pub enum Writer {
+    File(NonBlocking),
+    Stderr(io::Stderr),
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::File(writer) => writer.write(buf),
+            Writer::Stderr(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::File(writer) => writer.flush(),
+            Writer::Stderr(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Flushes a `LogFile` when dropped.
+pub struct FlushOnDrop(LogFile);
+
+impl Drop for FlushOnDrop {
+    fn drop(&mut self) {
+        // Dropping the worker guard flushes whatever is still buffered.
+        self.0.inner.writer.write().unwrap().take();
+        self.0.inner.guard.lock().unwrap().take();
+    }
+}
+
+/// Open the file `RollingFileAppender` is about to open, the same way it
+/// does, since it panics if it can't: appending, creating it and then its
+/// directory if they are missing.
+fn probe(dir: &Path, name: &OsStr, rotation: &Rotation) -> io::Result<()> {
+    let path = dir.join(file_name(name, rotation, SystemTime::now()));
+    let open = || OpenOptions::new().create(true).append(true).open(&path);
+    match open() {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::create_dir_all(dir)?;
+            open().map(drop)
+        }
+        opened => opened.map(drop),
+    }
+}
+
+/// The name `RollingFileAppender` gives the file it writes to at `now`.
+fn file_name(name: &OsStr, rotation: &Rotation, now: SystemTime) -> OsString {
+    let (year, month, day, hour, minute) = httpdate::utc(now);
+    let suffix = if *rotation == Rotation::NEVER {
+        String::new()
+    } else if *rotation == Rotation::DAILY {
+        format!(".{}-{:02}-{:02}", year, month, day)
+    } else if *rotation == Rotation::HOURLY {
+        format!(".{}-{:02}-{:02}-{:02}", year, month, day, hour)
+    } else {
+        format!(
+            ".{}-{:02}-{:02}-{:02}-{:02}",
+            year, month, day, hour, minute
+        )
+    };
+    let mut file_name = name.to_os_string();
+    file_name.push(suffix);
+    file_name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn file_names_match_the_rolling_appender() {
+        // 1994-11-06 08:49:37 UTC.
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        let name = OsStr::new("access.log");
+        let named = |rotation| file_name(name, &rotation, now);
+        assert_eq!(named(Rotation::NEVER), "access.log");
+        assert_eq!(named(Rotation::DAILY), "access.log.1994-11-06");
+        assert_eq!(named(Rotation::HOURLY), "access.log.1994-11-06-08");
+        assert_eq!(named(Rotation::MINUTELY), "access.log.1994-11-06-08-49");
+    }
+
+    #[test]
+    fn probing_opens_only_the_log_itself() {
+        let dir = std::env::temp_dir().join(format!("sufficient-probe-{}", std::process::id()));
+        let logs = dir.join("logs");
+        let name = OsStr::new("access.log");
+        probe(&logs, name, &Rotation::NEVER).unwrap();
+        fs::write(logs.join(name), b"kept\n").unwrap();
+        probe(&logs, name, &Rotation::NEVER).unwrap();
+
+        let entries: Vec<_> = fs::read_dir(&logs)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec![name.to_os_string()]);
+        assert_eq!(fs::read(logs.join(name)).unwrap(), b"kept\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,24 +1,107 @@
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
 use futures::future;
-use futures::stream::StreamExt;
+use futures::stream::{Stream, StreamExt};
 use futures::FutureExt;
-use http::header::{HeaderMap, HeaderValue};
+use http::header::{HeaderName, HeaderValue};
 use http::status::StatusCode;
 use http::Uri;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{header, Body, Method, Request, Response, Server};
 use std::error::Error as StdError;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::{env, io};
-use structopt::StructOpt;
+use std::ffi::OsStr;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use std::{env, fs, io, process};
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Runtime;
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
+use tracing::{info_span, Instrument, Span};
+use tracing_appender::rolling::Rotation;
+use tracing_subscriber::filter::{self, EnvFilter};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+
+mod accept;
+mod addr;
+mod areas;
+mod capabilities;
+mod chaos;
+mod findings;
+mod framing;
+mod hash;
+mod hosts;
+mod httpdate;
+mod inflight;
+mod limit;
+mod logfile;
+mod memory;
+mod mimetype;
+mod panics;
+mod pathheader;
+mod preconditions;
+mod preload;
+mod protect;
+mod proxyproto;
+mod query;
+#[cfg(feature = "record")]
+mod record;
+mod rewrite;
+mod shutdown;
+mod signing;
+mod stdin;
+mod templates;
+#[cfg(test)]
+mod test_support;
+mod units;
+mod urlpath;
+mod utf16;
+mod wire;
+mod workers;
+
+use accept::Connection;
+use addr::ListenAddr;
+use capabilities::Capabilities;
+use chaos::{Chaos, DelayRange, Injected};
+use findings::{Finding, Output};
+use globset::GlobMatcher;
+use hash::Hashes;
+use hosts::AllowedHosts;
+use limit::IpLimiter;
+use logfile::{Backpressure, LogFile, Opened};
+use memory::Budget;
+use mime_guess::mime::Mime;
+use mimetype::MimeOverride;
+use pathheader::PathHeaderRule;
+use preload::PreloadRule;
+use protect::{HeaderCheck, HeaderRule, ProtectRule};
+use proxyproto::TrustedProxy;
+use query::QueryParams;
+use rewrite::{Rewrite, RewriteRule};
+use signing::Signature;
+use stdin::{Artifact, Delivery};
+use templates::Templates;
+use wire::WireLog;
+
+/// The tracing target that request log events are emitted under.
+const ACCESS_TARGET: &str = "sufficient::access";
+
+/// The tracing target of debug events about handling a request.
+const REQUEST_TARGET: &str = "sufficient::request";
 
 fn main() {
     // Set up error handling immediately
     if let Err(e) = run() {
         log_error_chain(&e);
+        process::exit(e.exit_code());
     }
 }
 
@@ -32,91 +115,1769 @@ fn log_error_chain(mut e: &dyn StdError) {
     }
 }
 
-#[derive(Clone, StructOpt)]
-#[structopt(about = "A basic HTTP file server")]
+#[derive(Clone, Parser)]
+#[command(
+    about = "A basic HTTP file server",
+    version,
+    after_help = "Every option can also be set through the environment variable \
+                  named next to it. A command line flag takes precedence over its \
+                  environment variable, which takes precedence over the default."
+)]
 pub struct Config {
-    /// The IP:PORT combination.
-    #[structopt(
-        name = "ADDR",
-        short = "a",
+    /// Where to listen: PORT (localhost only), :PORT (every interface, IPv4
+    /// and IPv6), HOST:PORT or [IPV6]:PORT.
+    #[arg(
+        short = 'a',
         long = "addr",
-        parse(try_from_str),
+        value_name = "ADDR",
+        env = "SUFFICIENT_ADDR",
+        value_parser = addr::parse,
         default_value = "127.0.0.1:4000"
     )]
-    addr: SocketAddr,
+    addr: ListenAddr,
+
+    /// Listen on IPv6 only, and don't accept IPv4-mapped clients on IPv6
+    /// sockets.
+    #[arg(long = "ipv6-only", env = "SUFFICIENT_IPV6_ONLY")]
+    ipv6_only: bool,
 
     /// The root directory for serving files.
-    #[structopt(name = "ROOT", parse(from_os_str), default_value = ".")]
+    #[arg(value_name = "ROOT", env = "SUFFICIENT_ROOT", default_value = ".")]
     root_dir: PathBuf,
+
+    /// Start even if the root directory doesn't exist yet, e.g. a volume that
+    /// is mounted later.
+    #[arg(long = "allow-missing-root", env = "SUFFICIENT_ALLOW_MISSING_ROOT")]
+    allow_missing_root: bool,
+
+    /// Write the access log to this file instead of the main log. Use "-" for
+    /// stdout.
+    #[arg(
+        long = "access-log",
+        env = "SUFFICIENT_ACCESS_LOG",
+        help_heading = "Logging"
+    )]
+    access_log: Option<PathBuf>,
+
+    /// How often to rotate the access log: minutely, hourly, daily or never.
+    #[arg(
+        long = "access-log-rotation",
+        env = "SUFFICIENT_ACCESS_LOG_ROTATION",
+        value_parser = parse_rotation,
+        default_value = "daily",
+        help_heading = "Logging"
+    )]
+    access_log_rotation: Rotation,
+
+    /// What to do when a log file can't keep up: drop lines, or make the
+    /// server wait until they are written.
+    #[arg(
+        long = "log-backpressure",
+        env = "SUFFICIENT_LOG_BACKPRESSURE",
+        value_enum,
+        default_value = "drop",
+        help_heading = "Logging"
+    )]
+    log_backpressure: Backpressure,
+
+    /// Require `Authorization: Bearer <TOKEN>` for paths matching GLOB, given
+    /// as <GLOB>=<TOKEN>, or Basic credentials, given as
+    /// <GLOB>=<USER>:<PASSWORD>. May be repeated; the longest matching glob
+    /// wins.
+    #[arg(
+        long = "protect",
+        value_name = "GLOB=TOKEN",
+        env = "SUFFICIENT_PROTECT",
+        value_parser = protect::parse_rule,
+        help_heading = "Auth"
+    )]
+    protect: Vec<ProtectRule>,
+
+    /// Refuse requests that don't carry header NAME, or if VALUE is given,
+    /// don't carry it with that value. Repeat a NAME to allow several values.
+    /// Checked before --protect tokens and signed links, which don't bypass
+    /// it.
+    #[arg(
+        long = "require-header",
+        value_name = "NAME[=VALUE]",
+        env = "SUFFICIENT_REQUIRE_HEADER",
+        hide_env_values = true,
+        value_parser = protect::parse_header_rule,
+        help_heading = "Auth"
+    )]
+    require_header: Vec<HeaderRule>,
+
+    /// Paths matching GLOB don't need the --require-header headers. May be
+    /// repeated.
+    #[arg(
+        long = "require-header-exempt",
+        value_name = "GLOB",
+        env = "SUFFICIENT_REQUIRE_HEADER_EXEMPT",
+        value_parser = protect::parse_glob,
+        help_heading = "Auth"
+    )]
+    require_header_exempt: Vec<GlobMatcher>,
+
+    /// The secret for signing expiring links that bypass --protect.
+    #[arg(
+        long = "signing-key",
+        env = "SUFFICIENT_SIGNING_KEY",
+        hide_env_values = true,
+        help_heading = "Auth"
+    )]
+    signing_key: Option<String>,
+
+    /// Rewrite request paths matching PATTERN, a glob or a regex starting
+    /// with ^, to TARGET. Captures are substituted for $1, $2 and so on.
+    /// Internal rewrites (the default) serve TARGET in place of the request,
+    /// redirects send the client there. May be repeated; the first matching
+    /// rule applies.
+    #[arg(
+        long = "rewrite",
+        value_name = "PATTERN=TARGET[,internal|redirect]",
+        env = "SUFFICIENT_REWRITE",
+        value_parser = rewrite::parse_rule,
+        help_heading = "Rewrites"
+    )]
+    rewrite: Vec<RewriteRule>,
+
+    /// Add LINK as a `Link` header to HTML pages matching GLOB, given as
+    /// <GLOB>=<LINK>, e.g. '/**/*.html=</app.css>; rel=preload; as=style'.
+    /// May be repeated; every matching rule adds its header.
+    #[arg(
+        long = "preload",
+        value_name = "GLOB=LINK",
+        env = "SUFFICIENT_PRELOAD",
+        value_parser = preload::parse_rule
+    )]
+    preload: Vec<PreloadRule>,
+
+    /// Set header NAME to VALUE on successful responses for paths matching
+    /// GLOB, given as <GLOB>:<NAME>:<VALUE>, e.g.
+    /// '/drafts/**:X-Robots-Tag:noindex'. May be repeated.
+    #[arg(
+        long = "path-header",
+        value_name = "GLOB:NAME:VALUE",
+        env = "SUFFICIENT_PATH_HEADER",
+        value_parser = pathheader::parse_rule
+    )]
+    path_header: Vec<PathHeaderRule>,
+
+    /// Whether every --path-header rule matching a path applies, or only the
+    /// first.
+    #[arg(
+        long = "path-header-mode",
+        value_enum,
+        env = "SUFFICIENT_PATH_HEADER_MODE",
+        default_value = "all"
+    )]
+    path_header_mode: pathheader::Mode,
+
+    /// The most requests a single client IP may have in flight at once.
+    #[arg(
+        long = "max-per-ip",
+        env = "SUFFICIENT_MAX_PER_IP",
+        value_parser = limit::parse_max,
+        help_heading = "Limits"
+    )]
+    max_per_ip: Option<usize>,
+
+    /// How long a request over the per-IP limit waits for a free slot before
+    /// getting a 429, e.g. 250ms.
+    #[arg(
+        long = "max-per-ip-wait",
+        value_name = "DURATION",
+        env = "SUFFICIENT_MAX_PER_IP_WAIT",
+        value_parser = units::parse_duration,
+        default_value = "0",
+        help_heading = "Limits"
+    )]
+    max_per_ip_wait: Duration,
+
+    /// Reject absolute-form requests whose authority disagrees with the Host
+    /// header.
+    #[arg(
+        long = "strict-host",
+        env = "SUFFICIENT_STRICT_HOST",
+        help_heading = "Limits"
+    )]
+    strict_host: bool,
+
+    /// Only answer requests whose Host is HOST, to keep web pages from
+    /// reaching the server through DNS rebinding. May be repeated. Defaults to
+    /// localhost, this machine's hostname and the address the client
+    /// connected to.
+    #[arg(
+        long = "allowed-hosts",
+        value_name = "HOST",
+        env = "SUFFICIENT_ALLOWED_HOSTS",
+        value_parser = hosts::parse_host,
+        help_heading = "Limits"
+    )]
+    allowed_hosts: Vec<String>,
+
+    /// Answer requests for any Host, e.g. for a public deployment behind
+    /// several names.
+    #[arg(
+        long = "allow-any-host",
+        env = "SUFFICIENT_ALLOW_ANY_HOST",
+        conflicts_with = "allowed_hosts",
+        help_heading = "Limits"
+    )]
+    allow_any_host: bool,
+
+    /// Expect every connection to start with a PROXY protocol v1 or v2
+    /// preamble, and take the client's address from it. Requires
+    /// --trusted-proxy.
+    #[arg(
+        long = "proxy-protocol",
+        env = "SUFFICIENT_PROXY_PROTOCOL",
+        requires = "trusted_proxy",
+        help_heading = "Limits"
+    )]
+    proxy_protocol: bool,
+
+    /// A proxy address or network, e.g. 10.0.0.0/8, allowed to send PROXY
+    /// preambles. Connections from anywhere else are closed. May be
+    /// repeated.
+    #[arg(
+        long = "trusted-proxy",
+        value_name = "ADDR[/PREFIX]",
+        env = "SUFFICIENT_TRUSTED_PROXY",
+        value_parser = proxyproto::parse_trusted,
+        requires = "proxy_protocol",
+        help_heading = "Limits"
+    )]
+    trusted_proxy: Vec<TrustedProxy>,
+
+    /// Run this many independent servers sharing the port via SO_REUSEPORT,
+    /// letting the kernel balance connections between them.
+    #[arg(
+        long = "workers",
+        env = "SUFFICIENT_WORKERS",
+        default_value = "1",
+        help_heading = "Workers"
+    )]
+    workers: usize,
+
+    /// How many times crashed workers are restarted before giving up.
+    #[arg(
+        long = "worker-restarts",
+        env = "SUFFICIENT_WORKER_RESTARTS",
+        default_value = "5",
+        help_heading = "Workers"
+    )]
+    worker_restarts: usize,
+
+    /// How long requests still in flight get to finish after SIGTERM or
+    /// Ctrl-C before they are cut off.
+    #[arg(
+        long = "shutdown-timeout",
+        value_name = "DURATION",
+        env = "SUFFICIENT_SHUTDOWN_TIMEOUT",
+        value_parser = units::parse_duration,
+        default_value = "30s",
+        help_heading = "Workers"
+    )]
+    shutdown_timeout: Duration,
+
+    /// Abort the whole process when handling a request panics, instead of
+    /// answering it with a 500 and carrying on.
+    #[arg(long = "abort-on-panic", env = "SUFFICIENT_ABORT_ON_PANIC")]
+    abort_on_panic: bool,
+
+    /// Panic while handling requests for this path, for testing how panics
+    /// are reported. Only in debug builds.
+    #[cfg(debug_assertions)]
+    #[arg(long = "debug-panic-path", hide = true)]
+    debug_panic_path: Option<String>,
+
+    /// Serve standard input, read fully at startup, at /NAME and at / instead
+    /// of serving a directory.
+    #[arg(
+        long = "stdin",
+        value_name = "NAME",
+        env = "SUFFICIENT_STDIN",
+        conflicts_with = "root_dir"
+    )]
+    stdin: Option<String>,
+
+    /// The most memory large buffers may take in total, e.g. 256MiB: the
+    /// --stdin data and --record bodies. What doesn't fit is refused or
+    /// skipped.
+    #[arg(
+        long = "memory-budget",
+        value_name = "SIZE",
+        env = "SUFFICIENT_MEMORY_BUDGET",
+        value_parser = units::parse_size,
+        help_heading = "Limits"
+    )]
+    memory_budget: Option<u64>,
+
+    /// The most --stdin reads before giving up, e.g. 64MiB or 100MB.
+    #[arg(
+        long = "max-stdin-size",
+        value_name = "SIZE",
+        env = "SUFFICIENT_MAX_STDIN_SIZE",
+        value_parser = units::parse_size,
+        default_value = "64MiB"
+    )]
+    max_stdin_size: u64,
+
+    /// Serve --stdin text that starts with a UTF-16 byte order mark as
+    /// UTF-8, converting it as it is sent. Without this it is served as it
+    /// is, with the charset the byte order mark gives.
+    #[arg(
+        long = "transcode-utf16",
+        env = "SUFFICIENT_TRANSCODE_UTF16",
+        requires = "stdin"
+    )]
+    transcode_utf16: bool,
+
+    /// Add X-File-Mtime, in unix seconds, and X-File-Size headers to --stdin
+    /// responses, for sync tools that would otherwise HEAD each file.
+    #[arg(
+        long = "expose-metadata",
+        env = "SUFFICIENT_EXPOSE_METADATA",
+        requires = "stdin"
+    )]
+    expose_metadata: bool,
+
+    /// Serve files with extension EXT as TYPE instead of the guessed type,
+    /// given as <EXT>=<TYPE>, e.g. ts=text/typescript. May be repeated.
+    #[arg(
+        long = "mime",
+        value_name = "EXT=TYPE",
+        env = "SUFFICIENT_MIME",
+        value_parser = mimetype::parse_override
+    )]
+    mime: Vec<MimeOverride>,
+
+    /// The type for files whose extension is unknown, instead of
+    /// application/octet-stream.
+    #[arg(
+        long = "mime-default",
+        value_name = "TYPE",
+        env = "SUFFICIENT_MIME_DEFAULT",
+        value_parser = mimetype::parse_mime
+    )]
+    mime_default: Option<Mime>,
+
+    /// Send the Cross-Origin-Opener-Policy, -Embedder-Policy and
+    /// -Resource-Policy headers a page needs to be cross-origin isolated, as
+    /// WASM threads and SharedArrayBuffer require. A --path-header rule for
+    /// one of them takes precedence.
+    #[arg(
+        long = "cross-origin-isolated",
+        env = "SUFFICIENT_CROSS_ORIGIN_ISOLATED"
+    )]
+    cross_origin_isolated: bool,
+
+    /// How far client clocks may be off from ours when comparing
+    /// If-Modified-Since dates. Files changed more recently than this are
+    /// always sent in full.
+    #[arg(
+        long = "date-skew-tolerance",
+        value_name = "DURATION",
+        env = "SUFFICIENT_DATE_SKEW_TOLERANCE",
+        value_parser = units::parse_duration,
+        default_value = "2s"
+    )]
+    date_skew_tolerance: Duration,
+
+    /// Inject delays, errors and slow bodies into responses, for testing how
+    /// clients cope. Only allowed on loopback addresses without --chaos-force.
+    #[arg(long = "chaos", env = "SUFFICIENT_CHAOS", help_heading = "Chaos")]
+    chaos: bool,
+
+    /// Allow --chaos on addresses other than loopback ones.
+    #[arg(
+        long = "chaos-force",
+        env = "SUFFICIENT_CHAOS_FORCE",
+        requires = "chaos",
+        help_heading = "Chaos"
+    )]
+    chaos_force: bool,
+
+    /// Delay each response by a random time in this range, e.g. '200ms-2s',
+    /// or by exactly this long, e.g. '500ms'.
+    #[arg(
+        long = "chaos-delay",
+        value_name = "RANGE",
+        env = "SUFFICIENT_CHAOS_DELAY",
+        value_parser = chaos::parse_delay,
+        requires = "chaos",
+        help_heading = "Chaos"
+    )]
+    chaos_delay: Option<DelayRange>,
+
+    /// The fraction of responses to replace with an error, from 0 to 1.
+    #[arg(
+        long = "chaos-error-rate",
+        value_name = "RATE",
+        env = "SUFFICIENT_CHAOS_ERROR_RATE",
+        value_parser = chaos::parse_rate,
+        default_value = "0",
+        requires = "chaos",
+        help_heading = "Chaos"
+    )]
+    chaos_error_rate: f64,
+
+    /// A status to inject as an error, picked at random among those given.
+    /// May be repeated; defaults to 500, 502 and 503.
+    #[arg(
+        long = "chaos-status",
+        value_name = "STATUS",
+        env = "SUFFICIENT_CHAOS_STATUS",
+        value_parser = chaos::parse_status,
+        requires = "chaos",
+        help_heading = "Chaos"
+    )]
+    chaos_status: Vec<StatusCode>,
+
+    /// Send each response body at no more than SIZE a second, e.g. '64KiB'.
+    #[arg(
+        long = "chaos-throttle",
+        value_name = "SIZE",
+        env = "SUFFICIENT_CHAOS_THROTTLE",
+        value_parser = chaos::parse_throttle,
+        requires = "chaos",
+        help_heading = "Chaos"
+    )]
+    chaos_throttle: Option<u64>,
+
+    /// Only inject into requests for paths matching GLOB. May be repeated.
+    #[arg(
+        long = "chaos-path",
+        value_name = "GLOB",
+        env = "SUFFICIENT_CHAOS_PATH",
+        value_parser = protect::parse_glob,
+        requires = "chaos",
+        help_heading = "Chaos"
+    )]
+    chaos_path: Vec<GlobMatcher>,
+
+    /// Write nothing to disk, for a read-only filesystem: the main log goes
+    /// to stderr, and options that write files are refused.
+    #[arg(long = "read-only", env = "SUFFICIENT_READ_ONLY")]
+    read_only: bool,
+
+    /// Serve a JSON description of this server's features and limits at
+    /// /_api/capabilities. Its api_version only changes when fields are
+    /// removed or change meaning; new fields may appear at any time. Also
+    /// serves file checksums at /_api/hash/PATH?algo=sha256|sha1|md5.
+    #[arg(long = "api", env = "SUFFICIENT_API")]
+    api: bool,
+
+    /// Record every exchange to a file in DIR, with credentials redacted.
+    #[cfg(feature = "record")]
+    #[arg(
+        long = "record",
+        value_name = "DIR",
+        env = "SUFFICIENT_RECORD",
+        help_heading = "Recording"
+    )]
+    record: Option<PathBuf>,
+
+    /// Only record requests for paths matching GLOB. May be repeated.
+    #[cfg(feature = "record")]
+    #[arg(
+        long = "record-filter",
+        value_name = "GLOB",
+        env = "SUFFICIENT_RECORD_FILTER",
+        value_parser = protect::parse_glob,
+        help_heading = "Recording"
+    )]
+    record_filter: Vec<GlobMatcher>,
+
+    /// Stop recording after this many exchanges.
+    #[cfg(feature = "record")]
+    #[arg(
+        long = "record-max",
+        env = "SUFFICIENT_RECORD_MAX",
+        default_value = "1000",
+        help_heading = "Recording"
+    )]
+    record_max: u64,
+
+    /// How much of each response body to record.
+    #[cfg(feature = "record")]
+    #[arg(
+        long = "record-body-size",
+        value_name = "SIZE",
+        env = "SUFFICIENT_RECORD_BODY_SIZE",
+        value_parser = units::parse_size,
+        default_value = "64KiB",
+        help_heading = "Recording"
+    )]
+    record_body_size: u64,
+
+    /// Log debug events for AREA, on top of whatever RUST_LOG turns on. May
+    /// be repeated.
+    #[arg(
+        long = "debug",
+        value_name = "AREA",
+        env = "SUFFICIENT_DEBUG",
+        value_parser = areas::parser(),
+        help_heading = "Logging"
+    )]
+    debug: Vec<String>,
+
+    /// Log every byte sent and received, as trace events under
+    /// sufficient::wire, for debugging clients. Authorization values in
+    /// request heads are masked, up to the first chunked request body on a
+    /// connection.
+    #[arg(
+        long = "wire-log",
+        env = "SUFFICIENT_WIRE_LOG",
+        help_heading = "Logging"
+    )]
+    wire_log: bool,
+
+    /// Load error.html from this directory to override the built-in error
+    /// page. Re-read on SIGHUP.
+    #[arg(long = "template-dir", env = "SUFFICIENT_TEMPLATE_DIR")]
+    template_dir: Option<PathBuf>,
+
+    /// Validate the configuration and exit without serving anything.
+    #[arg(long = "check", env = "SUFFICIENT_CHECK")]
+    check: bool,
+
+    /// How --check prints what it finds.
+    #[arg(
+        long = "output",
+        value_enum,
+        env = "SUFFICIENT_OUTPUT",
+        default_value = "human"
+    )]
+    output: Output,
+
+    /// Print the effective configuration, and where each value came from, then
+    /// exit.
+    #[arg(long = "print-config", env = "SUFFICIENT_PRINT_CONFIG")]
+    print_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn run() -> Result<()> {
-    // Initialize logging, and log the "info" level for this crate only, unless
-    // the environment contains `RUST_LOG`.
-    let file_appender = tracing_appender::rolling::hourly("/var/log", "sufficient.log");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-    let mut base_config = tracing_subscriber::fmt();
+#[derive(Clone, Subcommand)]
+pub enum Command {
+    /// Print a signed link to PATH that expires after TTL.
+    Sign {
+        /// The request path to sign, e.g. /private/report.pdf
+        #[arg(value_name = "PATH")]
+        path: String,
+
+        /// How long the link stays valid, e.g. 30m or 1h.
+        #[arg(long = "ttl", value_parser = units::parse_duration, default_value = "1h")]
+        ttl: Duration,
+    },
+
+    /// Write the built-in templates into DIR as a starting point for
+    /// --template-dir.
+    DumpTemplates {
+        #[arg(value_name = "DIR")]
+        dir: PathBuf,
+    },
+
+    /// Print a shell completion script.
+    Completions {
+        #[arg(value_name = "SHELL")]
+        shell: Shell,
+    },
+}
+
+impl Config {
+    /// Check everything about the configuration that can be checked before
+    /// serving, returning every problem found rather than just the first.
+    fn validate(mut self) -> std::result::Result<ValidatedConfig, Vec<Error>> {
+        let mut problems = Vec::new();
 
-    if env::var("NO_ANSI").is_ok() {
-        base_config = base_config.with_ansi(false);
+        // There's no root to check when serving stdin.
+        if self.stdin.is_none() {
+            match self.checked_root() {
+                Ok(root) => self.root_dir = root,
+                Err(e) => problems.push(e),
+            }
+        }
+        let templates = match Templates::load(self.template_dir.as_deref()) {
+            Ok(templates) => Some(templates),
+            Err(e) => {
+                problems.push(e);
+                None
+            }
+        };
+        if self.workers > 1 && !workers::SUPPORTED {
+            problems.push(Error::ReusePortUnsupported);
+        }
+        match self.addr.resolve() {
+            Ok(addr) => {
+                self.addr = addr;
+                if self.addr.listeners(self.ipv6_only).is_empty() {
+                    problems.push(Error::NoListenAddr);
+                }
+            }
+            Err(e) => problems.push(e),
+        }
+        for rule in self.rewrite.iter().filter(|rule| rule.escapes_root()) {
+            problems.push(Error::RewriteEscapesRoot(rule.to_string()));
+        }
+        if self.read_only {
+            let mut writers = Vec::new();
+            if matches!(&self.access_log, Some(path) if path != Path::new("-")) {
+                writers.push("--access-log to a file");
+            }
+            #[cfg(feature = "record")]
+            if self.record.is_some() {
+                writers.push("--record");
+            }
+            problems.extend(writers.into_iter().map(Error::ReadOnlyConflict));
+        }
+        if self.chaos && !self.chaos_force {
+            for listener in self.addr.listeners(self.ipv6_only) {
+                if !listener.addr.ip().is_loopback() {
+                    problems.push(Error::ChaosNotLoopback(listener.addr));
+                }
+            }
+        }
+
+        match templates {
+            Some(templates) if problems.is_empty() => Ok(ValidatedConfig {
+                config: self,
+                templates,
+            }),
+            _ => Err(problems),
+        }
+    }
+
+    /// The canonical root directory, or the configured one if it is allowed
+    /// to be missing and is.
+    fn checked_root(&self) -> Result<PathBuf> {
+        match canonical_root(&self.root_dir) {
+            Err(Error::InvalidRoot(root, ref e))
+                if self.allow_missing_root && e.kind() == io::ErrorKind::NotFound =>
+            {
+                Ok(root)
+            }
+            root => root,
+        }
     }
-    let base_config = base_config.with_writer(non_blocking);
+}
+
+/// A configuration that passed `Config::validate`, with the root directory
+/// made canonical and the templates it had to load along the way.
+struct ValidatedConfig {
+    config: Config,
+    templates: Templates,
+}
 
-    base_config.init();
+/// State shared by every connection for the life of the server.
+#[derive(Clone)]
+struct Shared {
+    ip_limiter: Option<IpLimiter>,
+    /// The hosts requests may name, unless any host is allowed.
+    hosts: Option<Arc<AllowedHosts>>,
+    templates: Arc<RwLock<Templates>>,
+    /// What is served instead of the root directory in `--stdin` mode.
+    stdin: Option<Arc<Artifact>>,
+    chaos: Option<Arc<Chaos>>,
+    /// The `--api` capabilities document.
+    capabilities: Option<Arc<Capabilities>>,
+    /// The digests worked out for `--api` hash requests.
+    hashes: Option<Arc<Hashes>>,
+    #[cfg(feature = "record")]
+    recorder: Option<Arc<record::Recorder>>,
+    /// Fires when the server is asked to stop.
+    shutdown: shutdown::Signal,
+    /// The requests being handled, for what shutdown reports waiting on.
+    inflight: Arc<inflight::Registry>,
+}
+
+impl Shared {
+    /// What `config` makes of the state every connection shares, stopped by
+    /// `shutdown`.
+    fn new(
+        config: &Config,
+        templates: Templates,
+        stdin: Option<Arc<Artifact>>,
+        #[cfg_attr(not(feature = "record"), allow(unused_variables))] memory: Option<Arc<Budget>>,
+        shutdown: shutdown::Signal,
+    ) -> Result<Shared> {
+        Ok(Shared {
+            ip_limiter: config
+                .max_per_ip
+                .map(|max| IpLimiter::new(max, config.max_per_ip_wait)),
+            hosts: if config.allow_any_host {
+                None
+            } else {
+                Some(Arc::new(AllowedHosts::new(&config.allowed_hosts)))
+            },
+            templates: Arc::new(RwLock::new(templates)),
+            stdin,
+            capabilities: if config.api {
+                Some(Arc::new(Capabilities::new(config)))
+            } else {
+                None
+            },
+            hashes: if config.api {
+                Some(Arc::new(Hashes::default()))
+            } else {
+                None
+            },
+            chaos: if config.chaos {
+                Some(Arc::new(Chaos {
+                    delay: config.chaos_delay,
+                    error_rate: config.chaos_error_rate,
+                    statuses: if config.chaos_status.is_empty() {
+                        chaos::DEFAULT_STATUSES.to_vec()
+                    } else {
+                        config.chaos_status.clone()
+                    },
+                    throttle: config.chaos_throttle,
+                    paths: config.chaos_path.clone(),
+                }))
+            } else {
+                None
+            },
+            #[cfg(feature = "record")]
+            recorder: match &config.record {
+                Some(dir) => Some(Arc::new(record::Recorder::new(
+                    dir,
+                    config.record_filter.clone(),
+                    config.record_max,
+                    config.record_body_size,
+                    config
+                        .require_header
+                        .iter()
+                        .map(|rule| rule.name().clone())
+                        .collect(),
+                    memory,
+                )?)),
+                None => None,
+            },
+            shutdown,
+            inflight: Arc::default(),
+        })
+    }
+}
 
+fn parse_rotation(s: &str) -> std::result::Result<Rotation, String> {
+    match s {
+        "minutely" => Ok(Rotation::MINUTELY),
+        "hourly" => Ok(Rotation::HOURLY),
+        "daily" => Ok(Rotation::DAILY),
+        "never" => Ok(Rotation::NEVER),
+        _ => Err(format!(
+            "unknown rotation '{}', expected minutely, hourly, daily or never",
+            s
+        )),
+    }
+}
+
+fn run() -> Result<()> {
     // Create the configuration from the command line arguments. It
     // includes the IP address and port to listen on and the path to use
     // as the HTTP server's root directory.
-    let config = Config::from_args();
+    let matches = Config::command().get_matches();
+    let config = Config::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // Completion scripts are generated from the argument definitions alone.
+    if let Some(Command::Completions { shell }) = config.command {
+        clap_complete::generate(
+            shell,
+            &mut Config::command(),
+            "sufficient",
+            &mut io::stdout(),
+        );
+        return Ok(());
+    }
+
+    if let Some(Command::DumpTemplates { dir }) = &config.command {
+        init_stderr_logging();
+        return templates::dump(dir);
+    }
+
+    if config.print_config {
+        print_config(&matches);
+        return Ok(());
+    }
+
+    // Signing a link needs nothing but the key and address.
+    if let Some(Command::Sign { path, ttl }) = &config.command {
+        init_stderr_logging();
+        let key = config
+            .signing_key
+            .as_ref()
+            .ok_or(Error::MissingSigningKey)?;
+        println!(
+            "{}",
+            signing::signed_url(key, &config.addr.url_authority(), path, *ttl)
+        );
+        return Ok(());
+    }
+
+    // In check mode only validate the configuration, printing what was found
+    // in the requested format. Errors among the findings exit with 2, like
+    // any bad configuration; a failure to run the check at all exits with 1.
+    if config.check {
+        init_stderr_logging();
+        let output = config.output;
+        let mut findings = Vec::new();
+        if config.stdin.is_none() && config.allow_missing_root && !config.root_dir.exists() {
+            let message = "root directory does not exist yet".to_string();
+            findings.push(Finding::warning(Some(config.root_dir.clone()), message));
+        }
+        if let Err(problems) = config.validate() {
+            findings.extend(problems.iter().map(Finding::from_error));
+        }
+        findings::print(&findings, output);
+
+        let errors = findings
+            .iter()
+            .filter(|finding| finding.severity == findings::Severity::Error)
+            .count();
+        if errors > 0 {
+            return Err(Error::InvalidConfig(errors));
+        }
+        info!("configuration ok");
+        return Ok(());
+    }
+
+    // Check the configuration once at startup instead of surfacing problems
+    // as errors on every request. This happens before logging goes to files,
+    // so that every problem is reported together on stderr.
+    let validated = match config.validate() {
+        Ok(validated) => validated,
+        Err(problems) => {
+            init_stderr_logging();
+            return Err(report_problems(problems));
+        }
+    };
+
+    // Read piped-in data up front too, so a failure ends up on stderr.
+    let memory = validated.config.memory_budget.map(Budget::new);
+    let stdin = match read_stdin(&validated.config, io::stdin(), memory.as_ref()) {
+        Ok(stdin) => stdin,
+        Err(e) => {
+            init_stderr_logging();
+            return Err(e);
+        }
+    };
+
+    serve_validated(validated, stdin, memory)
+}
+
+/// The `--stdin` artifact, read from `input`, if there is one.
+fn read_stdin(
+    config: &Config,
+    input: impl io::Read,
+    memory: Option<&Arc<Budget>>,
+) -> Result<Option<Arc<Artifact>>> {
+    let name = match &config.stdin {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let content_type = mimetype::guess(Path::new(name), &config.mime, config.mime_default.as_ref());
+    let mut artifact = stdin::read(input, name, config.max_stdin_size, &content_type, memory)?;
+    if config.transcode_utf16 {
+        artifact.transcode_utf16();
+    }
+    if config.expose_metadata {
+        artifact.expose_metadata();
+    }
+    Ok(Some(Arc::new(artifact)))
+}
+
+/// Set up logging and serve a validated configuration until the server fails.
+fn serve_validated(
+    validated: ValidatedConfig,
+    stdin: Option<Arc<Artifact>>,
+    memory: Option<Arc<Budget>>,
+) -> Result<()> {
+    let ValidatedConfig { config, templates } = validated;
+
+    // Initialize logging at the levels `log_levels` picks, which honor
+    // `RUST_LOG`. When an access log is configured, request events go only to
+    // it and everything else to the main log. Log files are opened in the
+    // background and written to stderr until they are; both flush guards must
+    // live until the server exits.
+    // With --read-only there is no main log file at all.
+    let (main_log, main_opened) = if config.read_only {
+        (LogFile::stderr(), Opened::Ready)
+    } else {
+        LogFile::open(
+            Path::new("/var/log"),
+            OsStr::new("sufficient.log"),
+            Rotation::HOURLY,
+            config.log_backpressure,
+        )
+    };
+    let _main_flush = main_log.flush_on_drop();
+    let ansi = env::var("NO_ANSI").is_err();
+    let split_access = config.access_log.is_some();
+
+    let main_layer = fmt::layer()
+        .with_ansi(ansi)
+        .with_writer(main_log.clone())
+        .with_filter(filter::filter_fn(move |meta| {
+            !(split_access && meta.target() == ACCESS_TARGET)
+        }));
+
+    let access_log = config.access_log.as_ref().map(|path| {
+        access_log_writer(
+            path,
+            config.access_log_rotation.clone(),
+            config.log_backpressure,
+        )
+    });
+    let _access_flush = access_log.as_ref().map(|(log, _)| log.flush_on_drop());
+    let access_layer = access_log.as_ref().map(|(log, _)| {
+        fmt::layer()
+            .with_ansi(ansi)
+            .with_writer(log.clone())
+            .with_filter(filter::filter_fn(|meta| meta.target() == ACCESS_TARGET))
+    });
+
+    let levels = log_levels(
+        env::var("RUST_LOG").ok().as_deref(),
+        &config.debug,
+        config.wire_log,
+    );
+
+    tracing_subscriber::registry()
+        .with(levels)
+        .with(main_layer)
+        .with(access_layer)
+        .init();
+    panics::install_hook(config.abort_on_panic);
+
+    report_log_file(&main_log, main_opened);
+    if let Some((log, opened)) = access_log {
+        report_log_file(&log, opened);
+    }
+
+    if config.stdin.is_none() && !config.root_dir.exists() {
+        warn!("root dir {} does not exist yet", config.root_dir.display());
+    }
 
     // Display the configuration to be helpful
     info!("sufficient {}", env!("CARGO_PKG_VERSION"));
-    info!("addr: http://{}", config.addr);
-    info!("root dir: {}", config.root_dir.display());
+    for listener in config.addr.listeners(config.ipv6_only) {
+        info!("listening on {}", listener);
+    }
+    match &stdin {
+        Some(artifact) => info!(
+            "serving stdin ({}) at {}",
+            units::Size(artifact.size() as u64),
+            artifact.path()
+        ),
+        None => info!("root dir: {}", config.root_dir.display()),
+    }
+    if let Some(path) = &config.access_log {
+        info!("access log: {}", path.display());
+    }
+    if config.read_only {
+        info!("read-only: logging to stderr instead of /var/log");
+    }
+    if let Some(max) = config.max_per_ip {
+        info!(
+            "max requests per ip: {} (waiting up to {})",
+            max,
+            units::Elapsed(config.max_per_ip_wait)
+        );
+    }
+    if let Some(memory) = &memory {
+        info!("memory budget: {}", memory);
+    }
+    if config.workers > 1 {
+        info!("workers: {}", config.workers);
+    }
+
+    let (stop, stopping) = shutdown::channel();
+    let shared = Shared::new(&config, templates, stdin, memory, stopping)?;
+
+    // In worker mode each worker runs its own copy of the server on its own
+    // runtime, all bound to the same port.
+    if config.workers > 1 {
+        return workers::run(config, shared, stop);
+    }
+
+    // Create a Tokio runtime and block on Hyper until asked to stop.
+    let rt = Runtime::new().map_err(Error::Io)?;
+    rt.block_on(async {
+        tokio::spawn(async move {
+            shutdown::requested().await;
+            stop.fire();
+        });
+        // Bind every socket the address asks for, and serve them all.
+        let listeners = addr::bind_all(&config.addr.listeners(config.ipv6_only), false)?;
+        listen(config, shared, listeners, None).await
+    })
+}
+
+/// Serve connections accepted on `listeners` until one of the servers fails,
+/// or until shutdown, once the requests in flight are done or
+/// `--shutdown-timeout` has passed. `worker` identifies this server in the
+/// logs when several are running.
+async fn listen(
+    config: Config,
+    shared: Shared,
+    listeners: Vec<TcpListener>,
+    worker: Option<usize>,
+) -> Result<()> {
+    // Only one server per process watches for reloads.
+    if worker.unwrap_or(0) == 0 {
+        tokio::spawn(reload_on_hangup(
+            config.template_dir.clone(),
+            shared.templates.clone(),
+        ));
+    }
+
+    // Once shutdown starts, report what it waits on until it gives up.
+    let timeout = config.shutdown_timeout;
+    let report = shared.inflight.clone().report(worker);
+    let deadline = shared
+        .shutdown
+        .clone()
+        .wait()
+        .then(move |()| tokio::time::timeout(timeout, report).map(drop));
+    let span = match worker {
+        Some(id) => info_span!("worker", id),
+        None => Span::none(),
+    };
+    let deadline = deadline.instrument(span.clone());
+    let servers = listeners
+        .into_iter()
+        .map(|listener| serve_listener(config.clone(), shared.clone(), listener, worker));
+    let servers = future::try_join_all(servers);
+
+    match future::select(Box::pin(servers), Box::pin(deadline)).await {
+        future::Either::Left((served, _)) => served.map(drop),
+        future::Either::Right(((), _)) => {
+            let _entered = span.enter();
+            warn!(
+                "requests still in flight after {}, cutting them off",
+                units::Elapsed(timeout)
+            );
+            shared.inflight.log_cut_off(worker);
+            Ok(())
+        }
+    }
+}
+
+/// Serve the connections accepted on a single listening socket.
+async fn serve_listener(
+    config: Config,
+    shared: Shared,
+    listener: TcpListener,
+    worker: Option<usize>,
+) -> Result<()> {
+    let local = listener.local_addr().map_err(Error::Io)?;
+    let incoming = accept::incoming(listener).map_err(Error::Io)?;
+
+    // Wire logging and the PROXY protocol are decided here rather than per
+    // read, so they cost nothing when they are off. The wire log sees the
+    // preamble too.
+    let trusted = Arc::new(config.trusted_proxy.clone());
+    match (config.wire_log, config.proxy_protocol) {
+        (false, false) => serve_connections(config, shared, incoming, local, worker).await,
+        (true, false) => {
+            let incoming = incoming.map(|stream| stream.map(WireLog::new));
+            serve_connections(config, shared, incoming, local, worker).await
+        }
+        (false, true) => {
+            let incoming = proxyproto::accept(incoming, trusted);
+            serve_connections(config, shared, incoming, local, worker).await
+        }
+        (true, true) => {
+            let incoming = incoming.map(|stream| stream.map(WireLog::new));
+            let incoming = proxyproto::accept(incoming, trusted);
+            serve_connections(config, shared, incoming, local, worker).await
+        }
+    }
+}
+
+async fn serve_connections<I, C>(
+    config: Config,
+    shared: Shared,
+    incoming: I,
+    local: SocketAddr,
+    worker: Option<usize>,
+) -> Result<()>
+where
+    I: Stream<Item = io::Result<C>>,
+    C: Connection + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let builder = Server::builder(hyper::server::accept::from_stream(incoming));
 
     // Create the MakeService object that creates a new Hyper service for every
     // connection. Both these closures need to return a Future of Result, and we
     // use two different mechanisms to achieve that.
-    let make_service = make_service_fn(|_| {
+    let make_service = make_service_fn(|conn: &C| {
         let config = config.clone();
+        let shared = shared.clone();
+        // A connection reset right after it was accepted has no peer left,
+        // and is dropped.
+        let peer = match conn.peer_addr() {
+            Ok(peer) => peer,
+            Err(e) => return future::err(Error::Io(e)),
+        };
+        // The address the client connected to, which is the listener's own
+        // unless it listens on every interface.
+        let local_ip = conn.local_addr().map_or(local.ip(), |addr| addr.ip());
+        let conn_id = conn.id();
 
         let service = service_fn(move |req| {
             let config = config.clone();
+            let shared = shared.clone();
+            let request = shared.inflight.start(worker, peer, req.uri().path());
+            let span = match worker {
+                Some(id) => info_span!("worker", id),
+                None => Span::none(),
+            };
+            let span = match conn_id {
+                Some(conn) => info_span!(parent: &span, "conn", conn),
+                None => span,
+            };
 
             // Handle the request, returning a Future of Response,
             // and map it to a Future of Result of Response.
-            serve(config, req).map(Ok::<_, Error>)
+            serve(config, shared, peer, local, local_ip, req)
+                .instrument(span)
+                .map(move |resp| {
+                    #[cfg(feature = "record")]
+                    let resp = record::tap(resp);
+                    Ok::<_, Error>(request.track(limit::hold_until_sent(resp)))
+                })
         });
 
         // Convert the concrete (non-future) service function to a Future of Result.
         future::ok::<_, Error>(service)
     });
 
-    // Create a Hyper Server, binding to an address, and use
-    // our service builder.
-    let server = Server::bind(&config.addr).serve(make_service);
+    builder
+        .serve(make_service)
+        .with_graceful_shutdown(shared.shutdown.clone().wait())
+        .await
+        .map_err(Error::Hyper)
+}
 
-    // Create a Tokio runtime and block on Hyper forever.
-    let rt = Runtime::new()?;
-    rt.block_on(server)?;
+/// What to log: `rust_log`, the value of `RUST_LOG`, or everything at
+/// "info" without it, plus debug events for each `--debug` area and the wire
+/// log's trace events with `--wire-log`. A `RUST_LOG` that doesn't parse is
+/// treated as unset.
+fn log_levels(rust_log: Option<&str>, debug: &[String], wire_log: bool) -> EnvFilter {
+    let levels = rust_log
+        .and_then(|directives| EnvFilter::try_new(directives).ok())
+        .unwrap_or_else(|| EnvFilter::new("info"));
 
-    Ok(())
+    let mut directives: Vec<String> = debug
+        .iter()
+        .map(|area| format!("{}=debug", areas::target(area)))
+        .collect();
+    if wire_log {
+        directives.push(format!("{}=trace", wire::WIRE_TARGET));
+    }
+    directives.iter().fold(levels, |levels, directive| {
+        levels.add_directive(directive.parse().expect("valid directive"))
+    })
 }
 
-/// Create an HTTP Response future for each Request.
+/// Re-read the templates every time the process gets SIGHUP, keeping the
+/// current ones if the new ones fail to load.
+#[cfg(unix)]
+async fn reload_on_hangup(dir: Option<PathBuf>, templates: Arc<RwLock<Templates>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!(
+                "can't listen for SIGHUP, templates won't be reloaded: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match Templates::load(dir.as_deref()) {
+            Ok(loaded) => {
+                *templates.write().unwrap() = loaded;
+                info!("reloaded templates");
+            }
+            Err(e) => log_error_chain(&e),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_dir: Option<PathBuf>, _templates: Arc<RwLock<Templates>>) {}
+
+/// Print every option's effective value and whether it came from the command
+/// line, the environment or the default.
+fn print_config(matches: &ArgMatches) {
+    // These can carry secrets.
+    const REDACTED: &[&str] = &["protect", "require_header", "signing_key"];
+
+    for arg in Config::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        let name = match (arg.get_long(), arg.get_value_names()) {
+            (Some(long), _) => format!("--{}", long),
+            (None, Some([name, ..])) => name.to_string(),
+            (None, _) => id.to_string(),
+        };
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => "command line",
+            Some(ValueSource::EnvVariable) => "environment",
+            Some(ValueSource::DefaultValue) => "default",
+            Some(_) | None => continue,
+        };
+        let values: Vec<String> = match matches.get_raw(id) {
+            Some(_) if REDACTED.contains(&id) => vec!["<redacted>".to_string()],
+            Some(values) => values.map(|v| v.to_string_lossy().into_owned()).collect(),
+            None => Vec::new(),
+        };
+
+        println!("{} = {} ({})", name, values.join(", "), source);
+    }
+}
+
+/// Log to stderr only, for commands that exit without serving.
+fn init_stderr_logging() {
+    tracing_subscriber::fmt()
+        .with_ansi(env::var("NO_ANSI").is_err())
+        .with_writer(io::stderr)
+        .init();
+}
+
+/// Log every problem found by `Config::validate`, returning the error to exit
+/// with.
+fn report_problems(problems: Vec<Error>) -> Error {
+    for problem in &problems {
+        log_error_chain(problem);
+    }
+
+    Error::InvalidConfig(problems.len())
+}
+
+/// Canonicalize the root directory and check that it can be served: it must
+/// exist, be a directory, and be readable.
+fn canonical_root(root: &Path) -> Result<PathBuf> {
+    let invalid = |e| Error::InvalidRoot(root.to_path_buf(), e);
+
+    let canonical = root.canonicalize().map_err(invalid)?;
+    if !canonical.is_dir() {
+        let e = io::Error::other("not a directory");
+        return Err(invalid(e));
+    }
+    fs::read_dir(&canonical).map_err(invalid)?;
+
+    Ok(canonical)
+}
+
+/// Start opening the access log. A path of "-" means stdout, anything else
+/// is a file rotated according to `rotation`.
+fn access_log_writer(
+    path: &Path,
+    rotation: Rotation,
+    backpressure: Backpressure,
+) -> (LogFile, Opened) {
+    if path == Path::new("-") {
+        let log = LogFile::ready(path, backpressure, io::stdout());
+        return (log, Opened::Ready);
+    }
+
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let file_name = path.file_name().unwrap_or_else(|| OsStr::new("access.log"));
+
+    LogFile::open(dir, file_name, rotation, backpressure)
+}
+
+/// Warn about a log file that is still going to stderr.
+fn report_log_file(log: &LogFile, opened: Opened) {
+    match opened {
+        Opened::Ready => {}
+        Opened::Pending => warn!(
+            "{} is taking too long to open, logging to stderr until it does",
+            log.path().display()
+        ),
+        Opened::Failed(e) if e.kind() == io::ErrorKind::ReadOnlyFilesystem => warn!(
+            "{} is on a read-only filesystem, logging to stderr while retrying (--read-only logs \
+             to stderr from the start)",
+            log.path().display()
+        ),
+        Opened::Failed(e) => warn!(
+            "failed to open {}, logging to stderr while retrying: {}",
+            log.path().display(),
+            e
+        ),
+    }
+}
+
+/// Create an HTTP Response future for each Request. `local` is the address of
+/// the listener that accepted the connection.
 ///
 /// Errors are turned into an appropriate HTTP error response, and never
 /// propagated upward for hyper to deal with.
-async fn serve(config: Config, req: Request<Body>) -> Response<Body> {
+async fn serve(
+    config: Config,
+    shared: Shared,
+    peer: SocketAddr,
+    local: SocketAddr,
+    local_ip: IpAddr,
+    req: Request<Body>,
+) -> Response<Body> {
+    // Remember what was asked for so it can be written to the access log.
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let version = req.version();
+    let isolated = config.cross_origin_isolated;
+
+    // Access rules apply to the path that is actually served, so an internal
+    // rewrite can't be used to reach a protected area without its token.
+    let rewrite = rewrite::find(&config.rewrite, uri.path());
+    let path = match &rewrite {
+        Some(rewrite) if rewrite.kind == rewrite::Kind::Internal => {
+            urlpath::encode(&rewrite.target)
+        }
+        _ => uri.path().to_string(),
+    };
+    let rule = match &rewrite {
+        Some(rewrite) if rewrite.kind == rewrite::Kind::Redirect => None,
+        _ => protect::matching_rule(&config.protect, &path).cloned(),
+    };
+    let headers = protect::check_headers(
+        &config.require_header,
+        &config.require_header_exempt,
+        &path,
+        req.headers(),
+    );
+
+    #[cfg(feature = "record")]
+    let recording = shared.recorder.as_ref().and_then(|r| r.start(&req));
+
+    let injected = match &shared.chaos {
+        Some(chaos) => chaos.pick(uri.path()),
+        None => Injected::default(),
+    };
+    if let Some(delay) = injected.delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    // Every request gets an id, which is in its log events and, if handling
+    // it panics, its 500, so a report of one can be matched to the backtrace.
+    let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+
     // Serve the requested file.
-    let resp = serve_or_error(config, req).await;
+    let access = Access {
+        rewrite: rewrite.as_ref(),
+        rule: rule.as_ref(),
+        headers: &headers,
+    };
+    let resp = match injected.status {
+        Some(status) => Err(Error::Injected(status)),
+        None => {
+            let handler = check_and_serve(config, &shared, peer, local_ip, access, req);
+            catch_panics(handler)
+                .instrument(info_span!("request", id))
+                .await
+        }
+    };
 
     // Transform internal errors to error responses.
-    let resp = transform_error(resp);
+    let panicked = matches!(resp, Err(Error::Panicked));
+    let mut resp = transform_error(resp, &shared.templates, uri.path());
+    if panicked {
+        resp.headers_mut().insert(REQUEST_ID, HeaderValue::from(id));
+    }
+    if isolated {
+        cross_origin_isolate(&mut resp);
+    }
+
+    let resp = injected.apply(resp);
+
+    // How the body was delivered, for telling resumed downloads and
+    // revalidations apart from full transfers.
+    let delivery = match resp.extensions().get::<Delivery>() {
+        Some(delivery) => delivery.as_str(),
+        None if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE => "unsatisfiable",
+        None => "-",
+    };
+
+    #[cfg(feature = "record")]
+    let resp = match recording {
+        Some(recording) => recording.finish(resp),
+        None => resp,
+    };
+
+    info!(
+        target: ACCESS_TARGET,
+        request = id,
+        %peer,
+        listener = %local,
+        %method,
+        %uri,
+        ?version,
+        status = resp.status().as_u16(),
+        protect = rule.as_ref().map_or("-", |rule| rule.pattern()),
+        rewrite = rewrite.as_ref().map_or("-", |rewrite| rewrite.pattern.as_str()),
+        delivery,
+        require_header = %headers,
+        chaos = injected.to_string().as_str(),
+        "request"
+    );
 
     resp
 }
 
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The header a panicking request's 500 names its id in.
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Run a request handler, turning a panic in it into `Error::Panicked` so the
+/// client gets an answer and the connection lives on. The panic has already
+/// been logged by the panic hook.
+async fn catch_panics(
+    handler: impl Future<Output = Result<Response<Body>>>,
+) -> Result<Response<Body>> {
+    AssertUnwindSafe(handler)
+        .catch_unwind()
+        .await
+        .unwrap_or(Err(Error::Panicked))
+}
+
+/// What the access rules say about a request, worked out up front so it can
+/// be logged.
+struct Access<'a> {
+    rewrite: Option<&'a Rewrite>,
+    rule: Option<&'a ProtectRule>,
+    headers: &'a HeaderCheck,
+}
+
+/// Add the headers that make a page cross-origin isolated. Every response
+/// gets them, since an error page can be a document too, except where a
+/// `--path-header` rule has already set one: that is a deliberate exception,
+/// like letting other sites embed some assets.
+fn cross_origin_isolate(resp: &mut Response<Body>) {
+    let headers = resp.headers_mut();
+    headers
+        .entry(HeaderName::from_static("cross-origin-opener-policy"))
+        .or_insert(HeaderValue::from_static("same-origin"));
+    headers
+        .entry(HeaderName::from_static("cross-origin-embedder-policy"))
+        .or_insert(HeaderValue::from_static("require-corp"));
+    headers
+        .entry(HeaderName::from_static("cross-origin-resource-policy"))
+        .or_insert(HeaderValue::from_static("same-origin"));
+}
+
+/// Turn away requests that fail the server's admission checks, and serve the
+/// rest.
+///
+/// The checks run in a fixed order: framing, allowed hosts, the per-IP
+/// limit, required headers, rewrites, signed links and finally protected
+/// areas.
+async fn check_and_serve(
+    config: Config,
+    shared: &Shared,
+    peer: SocketAddr,
+    local_ip: IpAddr,
+    access: Access<'_>,
+    mut req: Request<Body>,
+) -> Result<Response<Body>> {
+    // Refuse requests whose framing a proxy in front of us might read
+    // differently than hyper does.
+    if let Err(violation) = framing::check(req.uri(), req.headers(), config.strict_host) {
+        warn!(
+            "rejecting request from {}: {} ({})",
+            peer,
+            violation,
+            framing::describe(req.headers())
+        );
+        return Err(Error::BadRequestHeader(violation.header()));
+    }
+
+    // Refuse hosts this server doesn't answer for before anything is read.
+    if let Some(hosts) = &shared.hosts {
+        if let Some(host) = hosts.refused(req.uri(), req.headers(), peer, local_ip) {
+            return Err(Error::HostNotAllowed(host));
+        }
+    }
+
+    // Hold one of the client's slots until the response body has been sent.
+    let permit = match &shared.ip_limiter {
+        Some(limiter) => match limiter.acquire(peer.ip()).await {
+            Some(permit) => Some(permit),
+            None => return limit::too_many_requests(),
+        },
+        None => None,
+    };
+
+    // `OPTIONS *` asks about the server as a whole rather than any file, so
+    // answer it before the request target is treated as a path.
+    if req.method() == Method::OPTIONS && req.uri().path() == "*" {
+        return server_options();
+    }
+
+    // Required headers gate everything else, signed links and tokens included.
+    if !access.headers.passed() {
+        debug!(target: REQUEST_TARGET, "refusing request from {}: {}", peer, access.headers);
+        return Err(Error::Forbidden(PathBuf::from(req.uri().path())));
+    }
+
+    // The capabilities document describes the server, not the tree.
+    if let Some(capabilities) = &shared.capabilities {
+        if req.uri().path() == capabilities::PATH {
+            return capabilities.serve(&req);
+        }
+    }
+
+    // Hashes are of the path named after the prefix, so that is the path
+    // whose protected area applies.
+    if let Some(hashes) = &shared.hashes {
+        if let Some(path) = hash::target(req.uri().path()) {
+            if let Some(rule) = protect::matching_rule(&config.protect, path) {
+                if !rule.authorizes(req.headers()) {
+                    return rule.unauthorized();
+                }
+            }
+            let stdin = shared.stdin.as_ref();
+            return hash::serve(hashes, &config.root_dir, stdin, &req, path).await;
+        }
+    }
+
+    // Redirects need nothing from the tree, so they go out before the
+    // remaining access checks. Internal rewrites change the request to the
+    // new path.
+    if let Some(rewrite) = access.rewrite {
+        match rewrite.kind {
+            rewrite::Kind::Redirect => {
+                return rewrite::redirect(rewrite, req.method(), req.uri().query());
+            }
+            rewrite::Kind::Internal => {
+                debug!(target: REQUEST_TARGET, "rewriting {} to {}", req.uri().path(), rewrite.target);
+                rewrite::set_path(&mut req, rewrite)?;
+            }
+        }
+    }
+
+    // A valid signed link stands in for the token of a protected area. A bad
+    // one is refused whether or not the path is protected.
+    let params = QueryParams::parse(req.uri().query())?;
+    let signature = signing::check(config.signing_key.as_deref(), req.uri().path(), &params);
+    if let Signature::Invalid = signature {
+        return Err(Error::Forbidden(PathBuf::from(req.uri().path())));
+    }
+
+    // Refuse requests into a protected area that don't carry its token.
+    if let Some(rule) = access.rule {
+        if !matches!(signature, Signature::Valid) && !rule.authorizes(req.headers()) {
+            return rule.unauthorized();
+        }
+    }
+
+    // The path being served, so after any internal rewrite: preload links
+    // and path headers are those of the rewritten path.
+    let path = req.uri().path().to_string();
+    #[cfg(debug_assertions)]
+    if config.debug_panic_path.as_deref() == Some(path.as_str()) {
+        panic!("--debug-panic-path {}", path);
+    }
+    let preload = config.preload.clone();
+    let path_headers = config.path_header.clone();
+    let path_header_mode = config.path_header_mode;
+    let want_digest = hash::want_digest(&req);
+    let mut resp = match &shared.stdin {
+        Some(artifact) => stdin::serve(artifact, &req, config.date_skew_tolerance)?,
+        None => serve_or_error(config, req).await?,
+    };
+    if let (Some(hashes), Some(artifact)) = (&shared.hashes, &shared.stdin) {
+        hash::add_digest(hashes, artifact, want_digest.as_ref(), &mut resp).await?;
+    }
+    preload::add_links(&preload, &path, &mut resp);
+    pathheader::apply(&path_headers, path_header_mode, &path, &mut resp);
+
+    // The slot is held until the body has been sent; see `limit::hold_until_sent`.
+    if let Some(permit) = permit {
+        resp.extensions_mut().insert(permit);
+    }
+
+    Ok(resp)
+}
+
+/// The methods this server handles for any resource, as advertised in `Allow`.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Respond to `OPTIONS *` with the methods enabled server-wide.
+fn server_options() -> Result<Response<Body>> {
+    let resp = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ALLOW, HeaderValue::from_static(ALLOWED_METHODS))
+        .body(Body::empty())
+        .map_err(Error::Http)?;
+
+    Ok(resp)
+}
+
+/// Respond to a request for a resource with a method other than GET and
+/// HEAD: `OPTIONS` is told what the resource allows, anything else is refused.
+fn other_method(method: &Method) -> Result<Response<Body>> {
+    let status = if method == Method::OPTIONS {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::METHOD_NOT_ALLOWED
+    };
+    let resp = Response::builder()
+        .status(status)
+        .header(header::ALLOW, HeaderValue::from_static(ALLOWED_METHODS))
+        .body(Body::empty())
+        .map_err(Error::Http)?;
+
+    Ok(resp)
+}
+
+/// Turn any errors into an HTTP error response.
+fn transform_error(
+    resp: Result<Response<Body>>,
+    templates: &RwLock<Templates>,
+    path: &str,
+) -> Response<Body> {
+    match resp {
+        Ok(r) => r,
+        Err(e) => {
+            let resp = make_error_response(e, templates, path);
+            match resp {
+                Ok(r) => r,
+                Err(e) => {
+                    // Last-ditch error reporting if even making the error response failed.
+                    error!("unexpected internal error: {}", e);
+                    let mut resp =
+                        Response::new(Body::from(format!("unexpected internal error: {}", e)));
+                    *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    resp
+                }
+            }
+        }
+    }
+}
+
+/// Pick the status for an error, log it at the level it deserves, and render
+/// the error page for it.
+fn make_error_response(
+    e: Error,
+    templates: &RwLock<Templates>,
+    path: &str,
+) -> Result<Response<Body>> {
+    // There is deliberately no catch-all arm here, so adding an error variant
+    // forces a decision about how it is reported.
+    let status = match &e {
+        // Problems with the request are routine and logged quietly.
+        Error::Io(io) if io.kind() == io::ErrorKind::NotFound => {
+            debug!(target: REQUEST_TARGET, "{}", io);
+            StatusCode::NOT_FOUND
+        }
+        Error::Io(io) if io.kind() == io::ErrorKind::PermissionDenied => {
+            info!("{}", io);
+            StatusCode::FORBIDDEN
+        }
+        Error::Forbidden(_) => {
+            info!("{}", e);
+            StatusCode::FORBIDDEN
+        }
+        Error::RangeNotSatisfiable { .. } => {
+            debug!(target: REQUEST_TARGET, "{}", e);
+            StatusCode::RANGE_NOT_SATISFIABLE
+        }
+        Error::PayloadTooLarge { .. } => {
+            info!("{}", e);
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        Error::HostNotAllowed(_) => {
+            debug!(target: REQUEST_TARGET, "{}", e);
+            StatusCode::MISDIRECTED_REQUEST
+        }
+        Error::BadRequestHeader(_)
+        | Error::BadQuery(_)
+        | Error::UriNotAbsolute
+        | Error::UriNotUtf8 => {
+            debug!(target: REQUEST_TARGET, "{}", e);
+            StatusCode::BAD_REQUEST
+        }
+
+        // Problems on our side of the connection get the full cause chain.
+        Error::UpstreamUnavailable(_) => {
+            log_error_chain(&e);
+            StatusCode::BAD_GATEWAY
+        }
+        Error::Panicked => StatusCode::INTERNAL_SERVER_ERROR,
+        // Already in the access log.
+        Error::Injected(status) => *status,
+        #[cfg(feature = "record")]
+        Error::RecordDir(..) => {
+            log_error_chain(&e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        Error::Http(_)
+        | Error::Hyper(_)
+        | Error::Io(_)
+        | Error::AddrParse(_)
+        | Error::InvalidRoot(..)
+        | Error::InvalidConfig(_)
+        | Error::NoListenAddr
+        | Error::StdinTooLarge { .. }
+        | Error::OverMemoryBudget { .. }
+        | Error::RewriteEscapesRoot(_)
+        | Error::ChaosNotLoopback(_)
+        | Error::ReadOnlyConflict(_)
+        | Error::Bind(..)
+        | Error::Resolve(..)
+        | Error::ReusePortUnsupported
+        | Error::WorkerRestartsExhausted
+        | Error::MissingSigningKey
+        | Error::TemplateIo(..)
+        | Error::TemplateParse(..) => {
+            log_error_chain(&e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+
+    let detail = match &e {
+        Error::BadRequestHeader(name) => format!("Bad {} header.", name),
+        _ => String::new(),
+    };
+    let body = templates.read().unwrap().error.render(&[
+        ("status", &status.to_string()),
+        ("detail", &detail),
+        ("path", path),
+    ]);
+
+    let mut resp = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8");
+    if let Error::RangeNotSatisfiable { len } = e {
+        resp = resp.header(header::CONTENT_RANGE, format!("bytes */{}", len));
+    }
+    let resp = resp.body(Body::from(body)).map_err(Error::Http)?;
+
+    Ok(resp)
+}
+
+impl Error {
+    /// The process exit code for this error: 2 when the configuration is at
+    /// fault, 1 when serving failed.
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::InvalidConfig(_) | Error::MissingSigningKey => 2,
+            _ => 1,
+        }
+    }
+}
+
 /// A custom `Result` typedef
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -153,9 +1914,703 @@ pub enum Error {
     #[error("failed to parse IP address")]
     AddrParse(std::net::AddrParseError),
 
+    #[error("invalid root directory {}", .0.display())]
+    InvalidRoot(PathBuf, #[source] io::Error),
+
+    #[error("invalid configuration ({0} problem(s) found)")]
+    InvalidConfig(usize),
+
+    #[error("--ipv6-only leaves no address to listen on")]
+    NoListenAddr,
+
+    #[error("failed to bind {0}")]
+    Bind(SocketAddr, #[source] io::Error),
+
+    #[error("failed to resolve {0}")]
+    Resolve(String, #[source] io::Error),
+
+    #[error("standard input exceeds the {} --max-stdin-size", units::Size(*.limit))]
+    StdinTooLarge { limit: u64 },
+
+    #[error("{what} doesn't fit in the {} --memory-budget", units::Size(*.budget))]
+    OverMemoryBudget { what: &'static str, budget: u64 },
+
+    #[error("internal rewrite {0} leads outside the root directory")]
+    RewriteEscapesRoot(String),
+
+    #[error("--read-only can't be combined with {0}, which writes files")]
+    ReadOnlyConflict(&'static str),
+
+    #[error("--chaos on {0} needs --chaos-force, since it isn't a loopback address")]
+    ChaosNotLoopback(SocketAddr),
+
+    #[cfg(feature = "record")]
+    #[error("failed to create record directory {}", .0.display())]
+    RecordDir(PathBuf, #[source] io::Error),
+
+    #[error("--workers needs SO_REUSEPORT, which this platform does not support")]
+    ReusePortUnsupported,
+
+    #[error("worker restart limit reached")]
+    WorkerRestartsExhausted,
+
+    #[error("signing a link requires --signing-key")]
+    MissingSigningKey,
+
     #[error("requested URI is not an absolute path")]
     UriNotAbsolute,
 
     #[error("requested URI is not UTF-8")]
     UriNotUtf8,
+
+    #[error("access to {} is forbidden", .0.display())]
+    Forbidden(PathBuf),
+
+    #[error("range not satisfiable for a {} resource", units::Size(*.len))]
+    RangeNotSatisfiable { len: u64 },
+
+    #[error("request body exceeds the {} limit", units::Size(*.limit))]
+    PayloadTooLarge { limit: u64 },
+
+    #[error("upstream {0} is unavailable")]
+    UpstreamUnavailable(Uri),
+
+    #[error("host {0} is not allowed")]
+    HostNotAllowed(String),
+
+    #[error("request handler panicked")]
+    Panicked,
+
+    #[error("injected {0} (--chaos)")]
+    Injected(StatusCode),
+
+    #[error("bad {0} header")]
+    BadRequestHeader(&'static str),
+
+    #[error("bad query string: {0}")]
+    BadQuery(String),
+
+    #[error("failed to access template {}", .0.display())]
+    TemplateIo(PathBuf, #[source] io::Error),
+
+    #[error("invalid template {}: {1}", .0.display())]
+    TemplateParse(PathBuf, templates::ParseError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestServer;
+    use futures::executor::block_on;
+
+    /// Marks the parts of an error that must not reach the client.
+    const SECRET: &str = "s3cret";
+
+    fn secret_io() -> io::Error {
+        io::Error::other(format!("{} internals", SECRET))
+    }
+
+    fn hyper_error() -> hyper::Error {
+        let (sender, body) = Body::channel();
+        sender.abort();
+        block_on(hyper::body::to_bytes(body)).unwrap_err()
+    }
+
+    fn secret_path() -> PathBuf {
+        PathBuf::from(format!("/srv/{}", SECRET))
+    }
+
+    /// Every error variant, with the status it should be answered with.
+    fn every_error() -> Vec<(Error, StatusCode)> {
+        let server_error = StatusCode::INTERNAL_SERVER_ERROR;
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        vec![
+            (
+                Error::Http(Response::builder().status(1000).body(()).unwrap_err()),
+                server_error,
+            ),
+            (Error::Hyper(hyper_error()), server_error),
+            (
+                Error::Io(io::Error::new(io::ErrorKind::NotFound, SECRET)),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                Error::Io(io::Error::new(io::ErrorKind::PermissionDenied, SECRET)),
+                StatusCode::FORBIDDEN,
+            ),
+            (Error::Io(secret_io()), server_error),
+            (
+                Error::AddrParse(SECRET.parse::<IpAddr>().unwrap_err()),
+                server_error,
+            ),
+            (Error::InvalidRoot(secret_path(), secret_io()), server_error),
+            (Error::InvalidConfig(1), server_error),
+            (Error::NoListenAddr, server_error),
+            (Error::Bind(addr, secret_io()), server_error),
+            (
+                Error::Resolve(SECRET.to_string(), secret_io()),
+                server_error,
+            ),
+            (Error::StdinTooLarge { limit: 1 }, server_error),
+            (
+                Error::OverMemoryBudget {
+                    what: "standard input",
+                    budget: 1,
+                },
+                server_error,
+            ),
+            (Error::RewriteEscapesRoot(SECRET.to_string()), server_error),
+            (Error::ReadOnlyConflict("--record"), server_error),
+            (Error::ChaosNotLoopback(addr), server_error),
+            #[cfg(feature = "record")]
+            (Error::RecordDir(secret_path(), secret_io()), server_error),
+            (Error::ReusePortUnsupported, server_error),
+            (Error::WorkerRestartsExhausted, server_error),
+            (Error::MissingSigningKey, server_error),
+            (Error::UriNotAbsolute, StatusCode::BAD_REQUEST),
+            (Error::UriNotUtf8, StatusCode::BAD_REQUEST),
+            (Error::Forbidden(secret_path()), StatusCode::FORBIDDEN),
+            (
+                Error::RangeNotSatisfiable { len: 10 },
+                StatusCode::RANGE_NOT_SATISFIABLE,
+            ),
+            (
+                Error::PayloadTooLarge { limit: 1 },
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                Error::UpstreamUnavailable(Uri::from_static("http://s3cret.internal/")),
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                Error::HostNotAllowed(format!("{}.example", SECRET)),
+                StatusCode::MISDIRECTED_REQUEST,
+            ),
+            (Error::Panicked, server_error),
+            (
+                Error::Injected(StatusCode::SERVICE_UNAVAILABLE),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (Error::BadRequestHeader("Host"), StatusCode::BAD_REQUEST),
+            (Error::BadQuery(SECRET.to_string()), StatusCode::BAD_REQUEST),
+            (Error::TemplateIo(secret_path(), secret_io()), server_error),
+            (
+                Error::TemplateParse(
+                    secret_path(),
+                    templates::ParseError {
+                        line: 1,
+                        message: SECRET.to_string(),
+                    },
+                ),
+                server_error,
+            ),
+        ]
+    }
+
+    fn config(args: &[&str]) -> Config {
+        Config::try_parse_from(Some("sufficient").iter().chain(args)).unwrap()
+    }
+
+    /// What the server shares between requests, serving `artifact` as if
+    /// with `--stdin`.
+    fn shared(artifact: Artifact) -> Shared {
+        Shared {
+            ip_limiter: None,
+            hosts: None,
+            templates: Arc::new(RwLock::new(Templates::load(None).unwrap())),
+            stdin: Some(Arc::new(artifact)),
+            chaos: None,
+            capabilities: None,
+            hashes: None,
+            #[cfg(feature = "record")]
+            recorder: None,
+            shutdown: shutdown::channel().1,
+            inflight: Arc::default(),
+        }
+    }
+
+    fn request(method: Method, target: &str) -> Response<Body> {
+        let artifact = Artifact::new(
+            "data.txt",
+            b"data".to_vec(),
+            &mime_guess::mime::TEXT_PLAIN,
+            None,
+        );
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let req = Request::builder()
+            .method(method)
+            .uri(target)
+            .body(Body::empty())
+            .unwrap();
+        block_on(serve(
+            config(&[]),
+            shared(artifact),
+            addr,
+            addr,
+            addr.ip(),
+            req,
+        ))
+    }
+
+    #[test]
+    fn hashing_a_protected_path_takes_its_token() {
+        let artifact = Artifact::new(
+            "data.txt",
+            b"data".to_vec(),
+            &mime_guess::mime::TEXT_PLAIN,
+            None,
+        );
+        let mut shared = shared(artifact);
+        shared.hashes = Some(Arc::new(Hashes::default()));
+        let runtime = Runtime::new().unwrap();
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let hash = |authorization: &str| {
+            let req = Request::builder()
+                .uri("/_api/hash/data.txt")
+                .header(header::AUTHORIZATION, authorization)
+                .body(Body::empty())
+                .unwrap();
+            let config = config(&["--protect", "/data.txt=token"]);
+            let resp = serve(config, shared.clone(), addr, addr, addr.ip(), req);
+            runtime.block_on(resp).status()
+        };
+
+        assert_eq!(hash("Bearer wrong"), StatusCode::UNAUTHORIZED);
+        assert_eq!(hash("Bearer token"), StatusCode::OK);
+    }
+
+    #[test]
+    fn options_star_describes_the_server() {
+        let resp = request(Method::OPTIONS, "*");
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()[header::ALLOW], ALLOWED_METHODS);
+    }
+
+    #[test]
+    fn options_on_a_path_is_about_that_resource() {
+        let resp = request(Method::OPTIONS, "/data.txt");
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers()[header::ALLOW], ALLOWED_METHODS);
+        assert!(resp.headers().get(header::ETAG).is_none());
+
+        let resp = request(Method::OPTIONS, "/missing");
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = request(Method::DELETE, "/data.txt");
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()[header::ALLOW], ALLOWED_METHODS);
+    }
+
+    /// Send each of `paths` as a GET on one connection to `addr`, returning
+    /// the status line of each response.
+    fn get_all(addr: SocketAddr, paths: &[&str]) -> Vec<String> {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+
+        let mut statuses = Vec::new();
+        for path in paths {
+            write!(writer, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut status = String::new();
+            reader.read_line(&mut status).unwrap();
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                    len = value.trim().parse().unwrap();
+                }
+            }
+            reader.read_exact(&mut vec![0; len]).unwrap();
+            statuses.push(status.trim_end().to_string());
+        }
+        statuses
+    }
+
+    #[test]
+    fn a_panicking_handler_gets_a_500_and_serving_goes_on() {
+        let runtime = Runtime::new().unwrap();
+        let templates = Arc::new(RwLock::new(Templates::load(None).unwrap()));
+        let addr = runtime.block_on(async {
+            let make_service = make_service_fn(move |_| {
+                let templates = templates.clone();
+                future::ok::<_, Error>(service_fn(move |req: Request<Body>| {
+                    let templates = templates.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let resp = catch_panics(async {
+                            if path == "/panic" {
+                                panic!("handler panicked on purpose");
+                            }
+                            Ok(Response::new(Body::from("ok")))
+                        })
+                        .await;
+                        Ok::<_, Error>(transform_error(resp, &templates, &path))
+                    }
+                }))
+            });
+            let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+            let addr = server.local_addr();
+            tokio::spawn(server);
+            addr
+        });
+
+        let statuses = get_all(addr, &["/panic", "/ok", "/panic", "/ok"]);
+        assert_eq!(
+            statuses,
+            [
+                "HTTP/1.1 500 Internal Server Error",
+                "HTTP/1.1 200 OK",
+                "HTTP/1.1 500 Internal Server Error",
+                "HTTP/1.1 200 OK",
+            ]
+        );
+        // A fresh connection is served too.
+        assert_eq!(get_all(addr, &["/ok"]), ["HTTP/1.1 200 OK"]);
+    }
+
+    #[test]
+    fn stdin_is_served_at_its_name_and_at_the_root() {
+        let server = TestServer::spawn(&["--stdin", "notes.txt"], b"piped in");
+        for path in &["/notes.txt", "/", "/./notes.txt"] {
+            let resp = server.exchange("GET", path, "");
+            assert_eq!(resp.status, "HTTP/1.1 200 OK", "{}", path);
+            assert_eq!(resp.header("content-type"), Some("text/plain"), "{}", path);
+            assert_eq!(resp.header("content-length"), Some("8"), "{}", path);
+            assert_eq!(resp.body, b"piped in", "{}", path);
+        }
+        assert!(server.base_url().starts_with("http://127.0.0.1:"));
+    }
+
+    #[test]
+    fn other_paths_get_the_not_found_page() {
+        let server = TestServer::spawn(&["--stdin", "notes.txt"], b"piped in");
+        for path in &["/other.txt", "/notes.txt/more", "/NOTES.TXT"] {
+            let resp = server.exchange("GET", path, "");
+            assert_eq!(resp.status, "HTTP/1.1 404 Not Found", "{}", path);
+            assert!(resp
+                .header("content-type")
+                .unwrap()
+                .starts_with("text/html"));
+            let body = String::from_utf8(resp.body).unwrap();
+            assert!(body.contains("404"), "{}", body);
+        }
+    }
+
+    #[test]
+    fn ranges_are_served_from_stdin() {
+        let server = TestServer::spawn(&["--stdin", "digits.txt"], b"0123456789");
+        let ranged =
+            |range: &str| server.exchange("GET", "/digits.txt", &format!("Range: {}\r\n", range));
+
+        let resp = ranged("bytes=2-4");
+        assert_eq!(resp.status, "HTTP/1.1 206 Partial Content");
+        assert_eq!(resp.header("content-range"), Some("bytes 2-4/10"));
+        assert_eq!(resp.body, b"234");
+        assert_eq!(ranged("bytes=-3").body, b"789");
+        assert_eq!(ranged("bytes=7-").body, b"789");
+
+        let resp = ranged("bytes=10-");
+        assert_eq!(resp.status, "HTTP/1.1 416 Range Not Satisfiable");
+        assert_eq!(resp.header("content-range"), Some("bytes */10"));
+        // Several ranges get the whole thing.
+        let resp = ranged("bytes=0-1,4-5");
+        assert_eq!(resp.status, "HTTP/1.1 200 OK");
+        assert_eq!(resp.body, b"0123456789");
+    }
+
+    #[test]
+    fn only_successful_and_not_modified_responses_carry_validators() {
+        let validators = ["etag", "last-modified", "x-file-mtime", "x-file-size"];
+        let server = TestServer::spawn(&["--stdin", "data.txt", "--expose-metadata"], b"data");
+        let etag = server
+            .exchange("GET", "/data.txt", "")
+            .header("etag")
+            .unwrap()
+            .to_string();
+
+        let with = [
+            ("GET", "", 200),
+            ("GET", "Range: bytes=1-2\r\n", 206),
+            ("GET", &*format!("If-None-Match: {}\r\n", etag), 304),
+        ];
+        for (method, extra, status) in with.iter() {
+            let resp = server.exchange(method, "/data.txt", extra);
+            assert!(resp.status.contains(&status.to_string()), "{:?}", resp);
+            for name in &validators {
+                assert!(resp.header(name).is_some(), "{} in {:?}", name, resp);
+            }
+        }
+
+        let without = [
+            ("GET", "/data.txt", "Range: bytes=10-\r\n", 416),
+            ("GET", "/missing", "", 404),
+            ("POST", "/data.txt", "Content-Length: 0\r\n", 405),
+            ("DELETE", "/data.txt", "", 405),
+            ("OPTIONS", "/data.txt", "", 204),
+        ];
+        for (method, path, extra, status) in without.iter() {
+            let resp = server.exchange(method, path, extra);
+            assert!(resp.status.contains(&status.to_string()), "{:?}", resp);
+            for name in &validators {
+                assert!(resp.header(name).is_none(), "{} in {:?}", name, resp);
+            }
+        }
+    }
+
+    #[test]
+    fn head_gets_the_same_headers_as_get() {
+        for transcode in &[false, true] {
+            let mut args = vec!["--stdin", "page.html", "--expose-metadata"];
+            if *transcode {
+                args.push("--transcode-utf16");
+            }
+            let server = TestServer::spawn(&args, b"\xff\xfeh\0i\0");
+            let etag = server
+                .exchange("GET", "/page.html", "")
+                .header("etag")
+                .unwrap()
+                .to_string();
+            let not_modified = format!("If-None-Match: {}\r\n", etag);
+
+            let cases = [
+                ("/page.html", "", 200),
+                ("/", "", 200),
+                ("/missing", "", 404),
+                ("/page.html", not_modified.as_str(), 304),
+            ];
+            // hyper never says Transfer-Encoding on HEAD, so HEAD of a
+            // chunked response says nothing about its length.
+            let framing = |headers: &[String]| {
+                headers
+                    .iter()
+                    .filter(|line| !line.starts_with("transfer-encoding:"))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            for (path, extra, status) in cases.iter() {
+                let case = format!("{} {} {}", transcode, path, extra);
+                let get = server.exchange("GET", path, extra);
+                let head = server.exchange("HEAD", path, extra);
+                let status = format!("HTTP/1.1 {} ", status);
+                assert!(get.status.starts_with(&status), "{} {:?}", case, get);
+                assert_eq!(get.status, head.status, "{}", case);
+                assert_eq!(framing(&get.headers), head.headers, "{}", case);
+                assert!(head.body.is_empty(), "{}", case);
+            }
+
+            // Ranges are only for GET, so HEAD is answered as if for all of it.
+            let full = server.exchange("GET", "/page.html", "");
+            for (range, status) in &[("bytes=2-3", 206), ("bytes=20-30", 416)] {
+                let extra = format!("Range: {}\r\n", range);
+                let get = server.exchange("GET", "/page.html", &extra);
+                let head = server.exchange("HEAD", "/page.html", &extra);
+                let status = format!("HTTP/1.1 {} ", status);
+                assert!(get.status.starts_with(&status), "{} {:?}", range, get);
+                assert_eq!(full.status, head.status, "{}", range);
+                assert_eq!(framing(&full.headers), head.headers, "{}", range);
+            }
+        }
+    }
+
+    #[test]
+    fn shutting_down_closes_idle_connections_and_stops_listening() {
+        use std::io::{Read, Write};
+
+        let mut server = TestServer::spawn(&["--stdin", "data.txt"], b"data");
+        // A connection kept alive after its request, with nothing in flight.
+        let mut idle = std::net::TcpStream::connect(server.addr()).unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(idle, "GET /data.txt HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut resp = Vec::new();
+        while !resp.ends_with(b"\r\n\r\ndata") {
+            let mut buf = [0; 1024];
+            let n = idle.read(&mut buf).unwrap();
+            assert!(n > 0, "{}", String::from_utf8_lossy(&resp));
+            resp.extend_from_slice(&buf[..n]);
+        }
+
+        let addr = server.addr();
+        server.stop().unwrap();
+        assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+        assert!(std::net::TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn shutting_down_gives_up_on_requests_after_the_timeout() {
+        use std::io::Write;
+
+        // Far more than the socket buffers hold, so it is still being sent.
+        let data = vec![b'x'; 32 << 20];
+        let args = ["--stdin", "data.txt", "--shutdown-timeout", "200ms"];
+        let mut server = TestServer::spawn(&args, &data);
+        let mut stalled = std::net::TcpStream::connect(server.addr()).unwrap();
+        write!(stalled, "GET /data.txt HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let started = std::time::Instant::now();
+        server.stop().unwrap();
+        let log = server.log();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(log.contains("waiting on 1 requests in flight"), "{}", log);
+        assert!(log.contains("in flight: 127.0.0.1:"), "{}", log);
+        assert!(log.contains(" /data.txt, "), "{}", log);
+        assert!(log.contains(" of 32 MiB left after "), "{}", log);
+        assert!(log.contains("cut off: 127.0.0.1:"), "{}", log);
+    }
+
+    #[test]
+    fn path_headers_take_precedence_over_cross_origin_isolation() {
+        let artifact = Artifact::new(
+            "data.txt",
+            b"data".to_vec(),
+            &mime_guess::mime::TEXT_PLAIN,
+            None,
+        );
+        let config = config(&[
+            "--cross-origin-isolated",
+            "--path-header",
+            "/data.txt:Cross-Origin-Resource-Policy:cross-origin",
+        ]);
+        let shared = shared(artifact);
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let get = |path| {
+            let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+            let resp = serve(config.clone(), shared.clone(), addr, addr, addr.ip(), req);
+            block_on(resp)
+        };
+
+        let resp = get("/data.txt");
+        let headers = resp.headers();
+        let corp: Vec<_> = headers
+            .get_all("cross-origin-resource-policy")
+            .iter()
+            .collect();
+        assert_eq!(corp, ["cross-origin"]);
+        assert_eq!(headers["cross-origin-opener-policy"], "same-origin");
+        assert_eq!(headers["cross-origin-embedder-policy"], "require-corp");
+
+        // Paths without a rule, and errors, are isolated as usual.
+        for path in &["/", "/missing"] {
+            let resp = get(path);
+            let headers = resp.headers();
+            assert_eq!(headers["cross-origin-resource-policy"], "same-origin");
+            assert_eq!(headers["cross-origin-opener-policy"], "same-origin");
+        }
+    }
+
+    #[test]
+    fn a_panic_in_serving_is_answered_logged_and_isolated() {
+        let artifact = Artifact::new(
+            "data.txt",
+            b"data".to_vec(),
+            &mime_guess::mime::TEXT_PLAIN,
+            None,
+        );
+        let shared = shared(artifact);
+        let config = config(&["--debug-panic-path", "/data.txt", "--cross-origin-isolated"]);
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let log = test_support::Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let log = log.clone();
+                move || log.clone()
+            })
+            .finish();
+        let dispatch = tracing::Dispatch::new(subscriber);
+        let get = |path| {
+            let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+            let resp = serve(config.clone(), shared.clone(), addr, addr, addr.ip(), req);
+            tracing::dispatcher::with_default(&dispatch, || block_on(resp))
+        };
+
+        let resp = get("/data.txt");
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let id = resp.headers()[REQUEST_ID].to_str().unwrap().to_string();
+        assert!(id.parse::<u64>().is_ok(), "{}", id);
+        // What comes after the handler still happens: the error page, and
+        // the headers every response gets.
+        assert!(resp.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        assert_eq!(resp.headers()["cross-origin-opener-policy"], "same-origin");
+
+        let log = log.contents();
+        let access = log
+            .lines()
+            .find(|line| line.contains(ACCESS_TARGET))
+            .unwrap_or_else(|| panic!("no access log in {}", log));
+        assert!(access.contains(&format!("request={}", id)), "{}", access);
+        assert!(access.contains("status=500"), "{}", access);
+
+        // Other requests are served as usual, without an id header.
+        let resp = get("/");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(REQUEST_ID).is_none());
+    }
+
+    #[test]
+    fn log_levels_combine_rust_log_and_areas() {
+        let levels = |rust_log, debug: &[&str], wire_log| {
+            let debug: Vec<String> = debug.iter().map(|area| area.to_string()).collect();
+            log_levels(rust_log, &debug, wire_log).to_string()
+        };
+
+        assert_eq!(levels(None, &[], false), "info");
+        assert_eq!(levels(Some("not a = directive ="), &[], false), "info");
+
+        let both = levels(Some("warn,hyper=debug"), &["request"], true);
+        for directive in &[
+            "warn",
+            "hyper=debug",
+            "sufficient::request=debug",
+            "sufficient::wire=trace",
+        ] {
+            assert!(both.split(',').any(|d| d == *directive), "{}", both);
+        }
+    }
+
+    #[test]
+    fn every_error_gets_its_status_and_no_details() {
+        let templates = RwLock::new(Templates::load(None).unwrap());
+        for (e, status) in every_error() {
+            let described = format!("{:?}", e);
+            let message = e.to_string();
+            let resp = transform_error(Err(e), &templates, "/requested");
+            assert_eq!(resp.status(), status, "{}", described);
+
+            let body = block_on(hyper::body::to_bytes(resp.into_body())).unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains(status.as_str()), "{}", described);
+            assert!(body.contains("/requested"), "{}", described);
+            assert!(!body.contains(SECRET), "{} leaks: {}", described, body);
+            assert!(!body.contains(&message), "{} leaks: {}", described, body);
+        }
+    }
+
+    #[test]
+    fn bad_headers_are_named() {
+        let templates = RwLock::new(Templates::load(None).unwrap());
+        let resp = transform_error(Err(Error::BadRequestHeader("Host")), &templates, "/");
+        let body = block_on(hyper::body::to_bytes(resp.into_body())).unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Bad Host header."));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_carry_the_length() {
+        let templates = RwLock::new(Templates::load(None).unwrap());
+        let e = Error::RangeNotSatisfiable { len: 10 };
+        let resp = transform_error(Err(e), &templates, "/");
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes */10");
+    }
 }
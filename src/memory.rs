@@ -0,0 +1,153 @@
+//! `--memory-budget`: a cap on the memory that large buffers may take,
+//! shared by every feature that keeps one.
+//!
+//! Before keeping a large buffer, a feature reserves its size with
+//! `Budget::reserve` and holds the `Reservation` for as long as the buffer
+//! lives; dropping it gives the bytes back. A feature that can't get a
+//! reservation does without rather than growing the process: standard input
+//! that doesn't fit is refused at startup, and a request that can't be
+//! recorded isn't. Small per-request allocations aren't counted.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::units;
+
+/// What a reservation is for, to show where the budget went.
+#[derive(Clone, Copy, Debug)]
+pub enum Category {
+    /// The `--stdin` artifact.
+    Stdin,
+    /// The response bodies of `--record` recordings in progress.
+    #[cfg_attr(not(feature = "record"), allow(dead_code))]
+    Record,
+}
+
+const CATEGORIES: [Category; 2] = [Category::Stdin, Category::Record];
+
+impl Category {
+    fn as_str(self) -> &'static str {
+        match self {
+            Category::Stdin => "stdin",
+            Category::Record => "record",
+        }
+    }
+}
+
+pub struct Budget {
+    limit: u64,
+    /// The bytes reserved, by category.
+    used: Mutex<[u64; CATEGORIES.len()]>,
+}
+
+/// Bytes taken from a budget, given back when dropped.
+pub struct Reservation {
+    budget: Arc<Budget>,
+    category: Category,
+    bytes: u64,
+}
+
+impl Budget {
+    pub fn new(limit: u64) -> Arc<Budget> {
+        Arc::new(Budget {
+            limit,
+            used: Mutex::new([0; CATEGORIES.len()]),
+        })
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// The bytes not yet reserved.
+    pub fn available(&self) -> u64 {
+        let used: u64 = self.used.lock().unwrap().iter().sum();
+        self.limit.saturating_sub(used)
+    }
+
+    /// Reserve `bytes` for `category`, unless that would go over the budget.
+    pub fn reserve(self: &Arc<Self>, category: Category, bytes: u64) -> Option<Reservation> {
+        let mut used = self.used.lock().unwrap();
+        let total: u64 = used.iter().sum();
+        if total.saturating_add(bytes) > self.limit {
+            return None;
+        }
+        used[category as usize] += bytes;
+
+        Some(Reservation {
+            budget: self.clone(),
+            category,
+            bytes,
+        })
+    }
+}
+
+/// Usage by category, e.g. `stdin 3 MiB, record 0 B of 64 MiB`. This is what
+/// the startup log and the record-skipped debug events show; there is no
+/// metrics endpoint or status page to report it on.
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let used = *self.used.lock().unwrap();
+        for (i, category) in CATEGORIES.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {}", category.as_str(), units::Size(used[i]))?;
+        }
+        write!(f, " of {}", units::Size(self.limit))
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.lock().unwrap()[self.category as usize] -= self.bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_are_given_back_on_drop() {
+        let budget = Budget::new(100);
+        let stdin = budget.reserve(Category::Stdin, 60).unwrap();
+        let record = budget.reserve(Category::Record, 30).unwrap();
+        assert_eq!(budget.available(), 10);
+
+        drop(stdin);
+        assert_eq!(budget.available(), 70);
+        drop(record);
+        assert_eq!(budget.available(), 100);
+    }
+
+    #[test]
+    fn a_full_budget_refuses_without_taking_anything() {
+        let budget = Budget::new(100);
+        let _held = budget.reserve(Category::Stdin, 80).unwrap();
+        assert!(budget.reserve(Category::Record, 21).is_none());
+        assert_eq!(budget.available(), 20);
+
+        // What is left can still be used.
+        let rest = budget.reserve(Category::Record, 20).unwrap();
+        assert_eq!(budget.available(), 0);
+        assert!(budget.reserve(Category::Record, 1).is_none());
+        drop(rest);
+        assert!(budget.reserve(Category::Record, 20).is_some());
+    }
+
+    #[test]
+    fn huge_reservations_dont_overflow() {
+        let budget = Budget::new(100);
+        let _held = budget.reserve(Category::Stdin, 1).unwrap();
+        assert!(budget.reserve(Category::Record, u64::MAX).is_none());
+        assert_eq!(budget.available(), 99);
+    }
+
+    #[test]
+    fn shows_usage_by_category() {
+        let budget = Budget::new(64 << 20);
+        let _stdin = budget.reserve(Category::Stdin, 3 << 20).unwrap();
+        assert_eq!(budget.to_string(), "stdin 3 MiB, record 0 B of 64 MiB");
+    }
+}
@@ -0,0 +1,66 @@
+//! Choosing a `Content-Type` from a file name.
+//!
+//! The type is guessed from the extension with `mime_guess`, unless an
+//! `--mime <EXT>=<TYPE>` option overrides it or it is one of the `FIXED`
+//! types browsers are strict about. Extensions are compared
+//! case-insensitively. Names nothing is known about get `--mime-default`, or
+//! `application/octet-stream`.
+
+use mime_guess::mime::{self, Mime};
+use std::path::Path;
+
+/// Types that must be exactly these for browsers to use the file at all,
+/// whatever `mime_guess` says: streaming WASM compilation requires
+/// `application/wasm`, and module scripts need a JavaScript type.
+const FIXED: &[(&str, &str)] = &[("mjs", "text/javascript"), ("wasm", "application/wasm")];
+
+#[derive(Clone, Debug)]
+pub struct MimeOverride {
+    /// The extension, lowercased and without its dot.
+    ext: String,
+    mime: Mime,
+}
+
+/// Parse an `<EXT>=<TYPE>` command line argument.
+pub fn parse_override(s: &str) -> std::result::Result<MimeOverride, String> {
+    let (ext, mime) = match s.find('=') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(format!("expected <EXT>=<TYPE>, got '{}'", s)),
+    };
+    let ext = ext.trim_start_matches('.');
+    if ext.is_empty() {
+        return Err(format!("expected <EXT>=<TYPE>, got '{}'", s));
+    }
+
+    Ok(MimeOverride {
+        ext: ext.to_ascii_lowercase(),
+        mime: parse_mime(mime)?,
+    })
+}
+
+/// Parse a media type like `text/markdown; charset=utf-8`.
+pub fn parse_mime(s: &str) -> std::result::Result<Mime, String> {
+    s.parse::<Mime>()
+        .map_err(|e| format!("invalid media type '{}': {}", s, e))
+}
+
+/// The content type for `path`.
+pub fn guess(path: &Path, overrides: &[MimeOverride], default: Option<&Mime>) -> Mime {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    if let Some(ext) = &ext {
+        if let Some(over) = overrides.iter().rev().find(|over| &over.ext == ext) {
+            return over.mime.clone();
+        }
+        if let Some((_, mime)) = FIXED.iter().find(|(fixed, _)| fixed == ext) {
+            return mime.parse().unwrap();
+        }
+    }
+
+    match mime_guess::from_path(path).first() {
+        Some(mime) => mime,
+        None => default.cloned().unwrap_or(mime::APPLICATION_OCTET_STREAM),
+    }
+}
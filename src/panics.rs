@@ -0,0 +1,42 @@
+//! Reporting panics through the log.
+//!
+//! The hook installed here logs a panic's message, location and backtrace as
+//! a single error event, inside whatever span was current when it happened,
+//! so a panicking request is reported along with its worker and request id.
+//! Request handling catches the unwind afterwards and answers with a 500
+//! naming that id in `X-Request-Id`. With `--abort-on-panic` the process
+//! aborts right after logging instead.
+
+use std::backtrace::Backtrace;
+use std::panic;
+use std::process;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+/// Replace the default panic hook, which writes to stderr, with one that logs.
+pub fn install_hook(abort: bool) {
+    panic::set_hook(Box::new(move |info| {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Box<dyn Any>".to_string(),
+            },
+        };
+        let location = match info.location() {
+            Some(location) => location.to_string(),
+            None => "unknown location".to_string(),
+        };
+
+        error!(
+            "panicked at {}: {}\n{}",
+            location,
+            message,
+            Backtrace::force_capture()
+        );
+
+        if abort {
+            process::abort();
+        }
+    }));
+}
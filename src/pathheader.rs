@@ -0,0 +1,195 @@
+//! Response headers scoped to paths.
+//!
+//! Each `--path-header <GLOB>:<NAME>:<VALUE>` option becomes a
+//! `PathHeaderRule`. Once a successful response has been built, rules whose
+//! glob matches the decoded request path set their header on it. With
+//! `--path-header-mode all` every matching rule applies, and several rules
+//! for one name each add a value; with `first` only the first matching rule
+//! does.
+//!
+//! Headers that describe the body or the connection can't be set this way,
+//! since overriding them would corrupt the response.
+
+use clap::ValueEnum;
+use globset::GlobMatcher;
+use http::header::{self, HeaderName, HeaderValue};
+use hyper::{Body, Response};
+
+use crate::{protect, urlpath};
+
+/// Headers hyper and the file serving code rely on being their own.
+const STRUCTURAL: &[HeaderName] = &[
+    header::CONNECTION,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::CONTENT_TYPE,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+#[derive(Clone, Debug)]
+pub struct PathHeaderRule {
+    matcher: GlobMatcher,
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+/// Which of the matching rules apply to a response.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Mode {
+    /// Only the first matching rule.
+    First,
+    /// Every matching rule.
+    All,
+}
+
+/// Parse a `<GLOB>:<NAME>:<VALUE>` command line argument.
+pub fn parse_rule(s: &str) -> std::result::Result<PathHeaderRule, String> {
+    let mut parts = s.splitn(3, ':');
+    let (pattern, name, value) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(pattern), Some(name), Some(value)) if !pattern.is_empty() => {
+            (pattern, name.trim(), value.trim())
+        }
+        _ => return Err(format!("expected <GLOB>:<NAME>:<VALUE>, got '{}'", s)),
+    };
+
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name '{}'", name))?;
+    if STRUCTURAL.contains(&name) {
+        return Err(format!("{} can't be set with --path-header", name));
+    }
+    let value =
+        HeaderValue::from_str(value).map_err(|_| format!("invalid header value '{}'", value))?;
+
+    Ok(PathHeaderRule {
+        matcher: protect::parse_glob(pattern)?,
+        name,
+        value,
+    })
+}
+
+/// Set the headers of the rules matching `path` on a successful response,
+/// replacing any the response already has.
+pub fn apply(rules: &[PathHeaderRule], mode: Mode, path: &str, resp: &mut Response<Body>) {
+    if rules.is_empty() || !resp.status().is_success() {
+        return;
+    }
+
+    let path = urlpath::normalize(path);
+    let matching = rules.iter().filter(|rule| rule.matcher.is_match(&path));
+    let matching: Vec<&PathHeaderRule> = match mode {
+        Mode::First => matching.take(1).collect(),
+        Mode::All => matching.collect(),
+    };
+
+    let headers = resp.headers_mut();
+    for (i, rule) in matching.iter().enumerate() {
+        // The first rule for a name replaces the response's own value, later
+        // ones add to it.
+        if matching[..i]
+            .iter()
+            .any(|earlier| earlier.name == rule.name)
+        {
+            headers.append(rule.name.clone(), rule.value.clone());
+        } else {
+            headers.insert(rule.name.clone(), rule.value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    fn rules(specs: &[&str]) -> Vec<PathHeaderRule> {
+        specs.iter().map(|spec| parse_rule(spec).unwrap()).collect()
+    }
+
+    fn applied(rules: &[PathHeaderRule], mode: Mode, path: &str) -> Vec<String> {
+        let mut resp = Response::new(Body::empty());
+        resp.headers_mut()
+            .insert("cache-control", HeaderValue::from_static("no-cache"));
+        apply(rules, mode, path, &mut resp);
+        resp.headers()
+            .get_all("cache-control")
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    /// A specific and a general glob that both match `/assets/app.js`.
+    fn overlapping() -> Vec<PathHeaderRule> {
+        rules(&[
+            "/assets/*.js:Cache-Control:max-age=31536000",
+            "/assets/**:Cache-Control:max-age=60",
+        ])
+    }
+
+    #[test]
+    fn first_mode_takes_the_first_matching_rule_in_order() {
+        let rules = overlapping();
+        assert_eq!(
+            applied(&rules, Mode::First, "/assets/app.js"),
+            ["max-age=31536000"]
+        );
+        assert_eq!(
+            applied(&rules, Mode::First, "/assets/app.css"),
+            ["max-age=60"]
+        );
+
+        // Order decides, not how specific the glob is.
+        let reversed: Vec<_> = rules.into_iter().rev().collect();
+        assert_eq!(
+            applied(&reversed, Mode::First, "/assets/app.js"),
+            ["max-age=60"]
+        );
+    }
+
+    #[test]
+    fn all_mode_replaces_then_adds_in_order() {
+        assert_eq!(
+            applied(&overlapping(), Mode::All, "/assets/app.js"),
+            ["max-age=31536000", "max-age=60"]
+        );
+    }
+
+    #[test]
+    fn unmatched_paths_keep_their_headers() {
+        assert_eq!(
+            applied(&overlapping(), Mode::All, "/index.html"),
+            ["no-cache"]
+        );
+    }
+
+    #[test]
+    fn paths_are_matched_decoded_and_normalized() {
+        assert_eq!(
+            applied(&overlapping(), Mode::First, "/x/../assets/%61pp.js"),
+            ["max-age=31536000"]
+        );
+    }
+
+    #[test]
+    fn error_responses_are_left_alone() {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        apply(&overlapping(), Mode::All, "/assets/app.js", &mut resp);
+        assert!(resp.headers().is_empty());
+    }
+
+    #[test]
+    fn structural_and_malformed_rules_are_refused() {
+        assert!(parse_rule("/**:Content-Length:1").is_err());
+        assert!(parse_rule("/**:Bad Name:x").is_err());
+        assert!(parse_rule("/**:X-A").is_err());
+        assert!(parse_rule(":X-A:b").is_err());
+        // The value may hold colons.
+        assert!(parse_rule("/**:Link:<https://example.com/>; rel=preconnect").is_ok());
+    }
+}
@@ -0,0 +1,601 @@
+//! The conditional and range headers of a request, as typed values.
+//!
+//! `Preconditions::parse` reads If-None-Match, If-Modified-Since, If-Range
+//! and Range once. A header that is present but doesn't parse is treated as
+//! absent, as RFC 9110 asks, so a client sending `Range: bytes=abc` or
+//! `If-Modified-Since: not-a-date` gets the whole representation rather than
+//! an error. The exception is If-Range: a validator that can't be used can't
+//! match either, so the range is ignored along with it.
+//!
+//! `Preconditions::evaluate` then applies the precedence of RFC 9110 section
+//! 13.2.2: If-None-Match, compared weakly, and only without it
+//! If-Modified-Since, decide on a 304; then If-Range, compared strongly,
+//! decides whether Range is looked at at all. Ranges are only served for
+//! GET. A single range is served if it can be satisfied and is a 416 if it
+//! can't; several ranges get the whole body.
+
+use http::header::{self, HeaderMap, HeaderValue};
+use http::Method;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::httpdate;
+
+/// The validators of what is being served.
+pub struct Resource<'a> {
+    /// The strong entity tag, with its quotes.
+    pub etag: &'a str,
+    /// When it last changed, if that is known.
+    pub modified: Option<SystemTime>,
+    /// Its length in bytes.
+    pub len: u64,
+}
+
+/// What to answer with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// 304: the client's copy is current.
+    NotModified,
+    /// 200 with the whole body.
+    Full,
+    /// 206 with the inclusive byte range `start..=end`.
+    Partial { start: u64, end: u64 },
+    /// 416: the range lies wholly outside the body.
+    Unsatisfiable,
+}
+
+#[derive(Debug, Default)]
+pub struct Preconditions {
+    if_none_match: Option<Tags>,
+    if_modified_since: Option<SystemTime>,
+    if_range: Option<IfRange>,
+    range: Option<ByteRange>,
+}
+
+/// The entity tags of an If-None-Match.
+#[derive(Debug, PartialEq)]
+enum Tags {
+    /// `*`, matching anything.
+    Any,
+    List(Vec<EntityTag>),
+}
+
+#[derive(Debug, PartialEq)]
+struct EntityTag {
+    weak: bool,
+    /// The opaque tag, with its quotes.
+    opaque: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum IfRange {
+    Tag(EntityTag),
+    Date(SystemTime),
+    /// A validator that can't be used, which never matches.
+    Unusable,
+}
+
+/// A single range from a `bytes=` Range header.
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// `first-last` or `first-`.
+    From { first: u64, last: Option<u64> },
+    /// `-length`, the last `length` bytes.
+    Suffix(u64),
+}
+
+impl Preconditions {
+    pub fn parse(headers: &HeaderMap) -> Preconditions {
+        Preconditions {
+            if_none_match: parse_tags(headers.get_all(header::IF_NONE_MATCH).iter().collect()),
+            if_modified_since: single(headers, header::IF_MODIFIED_SINCE).and_then(httpdate::parse),
+            if_range: if headers.contains_key(header::IF_RANGE) {
+                Some(
+                    single(headers, header::IF_RANGE)
+                        .and_then(IfRange::parse)
+                        .unwrap_or(IfRange::Unusable),
+                )
+            } else {
+                None
+            },
+            range: single(headers, header::RANGE).and_then(ByteRange::parse),
+        }
+    }
+
+    /// Decide how to answer a `method` request for `resource`, allowing for
+    /// client clocks `skew` off from `now`.
+    pub fn evaluate(
+        &self,
+        method: &Method,
+        resource: &Resource,
+        now: SystemTime,
+        skew: Duration,
+    ) -> Outcome {
+        if (method == Method::GET || method == Method::HEAD)
+            && self.not_modified(resource, now, skew)
+        {
+            return Outcome::NotModified;
+        }
+
+        let range = match &self.range {
+            Some(range) if method == Method::GET => range,
+            _ => return Outcome::Full,
+        };
+        // A stale If-Range means the client wants the whole thing again.
+        if let Some(if_range) = &self.if_range {
+            if !if_range.matches(resource) {
+                return Outcome::Full;
+            }
+        }
+
+        match range.resolve(resource.len) {
+            Some((start, end)) => Outcome::Partial { start, end },
+            None => Outcome::Unsatisfiable,
+        }
+    }
+
+    /// Whether the client's copy is current. If-Modified-Since only counts
+    /// when there is no If-None-Match.
+    pub fn not_modified(&self, resource: &Resource, now: SystemTime, skew: Duration) -> bool {
+        if let Some(tags) = &self.if_none_match {
+            return match tags {
+                Tags::Any => true,
+                Tags::List(tags) => tags.iter().any(|tag| tag.weak_eq(resource.etag)),
+            };
+        }
+
+        match (self.if_modified_since, resource.modified) {
+            (Some(since), Some(modified)) => httpdate::unmodified_since(modified, since, now, skew),
+            _ => false,
+        }
+    }
+}
+
+impl EntityTag {
+    /// Parse a whole `[W/]"opaque"`.
+    fn parse(s: &str) -> Option<EntityTag> {
+        let (weak, opaque) = match s.strip_prefix("W/") {
+            Some(opaque) => (true, opaque),
+            None => (false, s),
+        };
+        let inner = opaque.strip_prefix('"')?.strip_suffix('"')?;
+        if !inner.bytes().all(is_etagc) {
+            return None;
+        }
+
+        Some(EntityTag {
+            weak,
+            opaque: opaque.to_string(),
+        })
+    }
+
+    /// The weak comparison, which ignores `W/` on either side.
+    fn weak_eq(&self, etag: &str) -> bool {
+        self.opaque == etag.trim_start_matches("W/")
+    }
+
+    /// The strong comparison, which never matches a weak tag.
+    fn strong_eq(&self, etag: &str) -> bool {
+        !self.weak && !etag.starts_with("W/") && self.opaque == etag
+    }
+}
+
+impl IfRange {
+    fn parse(s: &str) -> Option<IfRange> {
+        let s = s.trim_matches(is_ows);
+        if s.starts_with("W/") || s.starts_with('"') {
+            EntityTag::parse(s).map(IfRange::Tag)
+        } else {
+            httpdate::parse(s).map(IfRange::Date)
+        }
+    }
+
+    /// Whether the validator still matches, as RFC 9110 section 13.1.5 has
+    /// it: an entity tag compared strongly, so a weak one never matches, or
+    /// a date that is exactly the last modification, to the second.
+    fn matches(&self, resource: &Resource) -> bool {
+        match self {
+            IfRange::Tag(tag) => tag.strong_eq(resource.etag),
+            IfRange::Date(date) => match resource.modified {
+                Some(modified) => unix_secs(*date) == unix_secs(modified),
+                None => false,
+            },
+            IfRange::Unusable => false,
+        }
+    }
+}
+
+impl ByteRange {
+    /// Parse a `bytes=` header holding a single range. Several ranges, other
+    /// units and anything malformed give `None`.
+    fn parse(s: &str) -> Option<ByteRange> {
+        let s = s.trim_matches(is_ows);
+        let (unit, spec) = s.split_at(s.find('=')?);
+        if !unit.eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+        let spec = spec[1..].trim_matches(is_ows);
+        let (first, last) = spec.split_at(spec.find('-')?);
+        let last = &last[1..];
+
+        match (number(first), number(last)) {
+            (Some(first), Some(last)) if first <= last => Some(ByteRange::From {
+                first,
+                last: Some(last),
+            }),
+            (Some(first), None) if last.is_empty() => Some(ByteRange::From { first, last: None }),
+            (None, Some(length)) if first.is_empty() => Some(ByteRange::Suffix(length)),
+            _ => None,
+        }
+    }
+
+    /// The inclusive bounds of the range in a body of `len` bytes, or `None`
+    /// if it can't be satisfied.
+    fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        let end = len.checked_sub(1)?;
+        match *self {
+            ByteRange::From { first, last } if first <= end => {
+                Some((first, last.map_or(end, |last| last.min(end))))
+            }
+            ByteRange::From { .. } | ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(length) => Some((len - length.min(len), end)),
+        }
+    }
+}
+
+/// Parse an If-None-Match, across all its header lines.
+fn parse_tags(values: Vec<&HeaderValue>) -> Option<Tags> {
+    let mut tags = Vec::new();
+    for value in values {
+        let mut rest = value.to_str().ok()?;
+        if rest.trim_matches(is_ows) == "*" {
+            return Some(Tags::Any);
+        }
+        loop {
+            rest = rest.trim_start_matches(|c| is_ows(c) || c == ',');
+            if rest.is_empty() {
+                break;
+            }
+            // The opaque tag may hold commas, so find its closing quote.
+            let open = if rest.starts_with("W/") { 2 } else { 0 };
+            let close = open + 1 + rest.get(open + 1..)?.find('"')?;
+            tags.push(EntityTag::parse(&rest[..=close])?);
+            rest = &rest[close + 1..];
+            if !rest.trim_start_matches(is_ows).is_empty()
+                && !rest.trim_start_matches(is_ows).starts_with(',')
+            {
+                return None;
+            }
+        }
+    }
+
+    if tags.is_empty() {
+        None
+    } else {
+        Some(Tags::List(tags))
+    }
+}
+
+/// The value of a header sent exactly once, as text.
+fn single(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    let mut values = headers.get_all(name).iter();
+    match (values.next(), values.next()) {
+        (Some(value), None) => value.to_str().ok(),
+        _ => None,
+    }
+}
+
+/// A non-negative decimal number with nothing else around it.
+fn number(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn is_ows(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+/// Whether `b` may appear between an entity tag's quotes.
+fn is_etagc(b: u8) -> bool {
+    b == 0x21 || (0x23..=0x7e).contains(&b) || b >= 0x80
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stdin, Error};
+    use hyper::{Body, Request};
+    use mime_guess::mime;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const ETAG: &str = "\"abc\"";
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(784111777)
+    }
+
+    fn resource(len: u64) -> Resource<'static> {
+        Resource {
+            etag: ETAG,
+            modified: Some(modified()),
+            len,
+        }
+    }
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn evaluate(pairs: &[(header::HeaderName, &str)]) -> Outcome {
+        let now = modified() + Duration::from_secs(3600);
+        Preconditions::parse(&headers(pairs)).evaluate(
+            &Method::GET,
+            &resource(10),
+            now,
+            Duration::from_secs(2),
+        )
+    }
+
+    fn range(first: u64, last: u64) -> Outcome {
+        Outcome::Partial {
+            start: first,
+            end: last,
+        }
+    }
+
+    #[test]
+    fn nothing_conditional_gets_everything() {
+        assert_eq!(evaluate(&[]), Outcome::Full);
+    }
+
+    #[test]
+    fn ranges() {
+        let from = |value| evaluate(&[(header::RANGE, value)]);
+        assert_eq!(from("bytes=2-4"), range(2, 4));
+        assert_eq!(from("bytes=2-"), range(2, 9));
+        assert_eq!(from("bytes=2-100"), range(2, 9));
+        assert_eq!(from("bytes=-3"), range(7, 9));
+        assert_eq!(from("bytes=-100"), range(0, 9));
+        assert_eq!(from("Bytes=2-4"), range(2, 4));
+        assert_eq!(from("bytes= 2-4 "), range(2, 4));
+        assert_eq!(from("bytes=10-"), Outcome::Unsatisfiable);
+        assert_eq!(from("bytes=-0"), Outcome::Unsatisfiable);
+    }
+
+    #[test]
+    fn malformed_ranges_are_ignored() {
+        for value in &[
+            "bytes=abc",
+            "bytes=",
+            "bytes=-",
+            "bytes=4-2",
+            "bytes=+2-4",
+            "bytes=2-4,6-8",
+            "bytes=0x2-4",
+            "bytes=99999999999999999999999-",
+            "items=2-4",
+            "2-4",
+            "bytes 2-4",
+            "bytes=2 - 4",
+        ] {
+            assert_eq!(
+                evaluate(&[(header::RANGE, value)]),
+                Outcome::Full,
+                "{}",
+                value
+            );
+        }
+        let twice = [(header::RANGE, "bytes=2-4"), (header::RANGE, "bytes=2-4")];
+        assert_eq!(evaluate(&twice), Outcome::Full);
+    }
+
+    #[test]
+    fn empty_bodies_have_no_ranges() {
+        let preconditions = Preconditions::parse(&headers(&[(header::RANGE, "bytes=-1")]));
+        let outcome = preconditions.evaluate(
+            &Method::GET,
+            &resource(0),
+            modified(),
+            Duration::from_secs(0),
+        );
+        assert_eq!(outcome, Outcome::Unsatisfiable);
+    }
+
+    #[test]
+    fn ranges_are_for_get_only() {
+        let preconditions = Preconditions::parse(&headers(&[(header::RANGE, "bytes=2-4")]));
+        let outcome = preconditions.evaluate(
+            &Method::HEAD,
+            &resource(10),
+            modified(),
+            Duration::from_secs(0),
+        );
+        assert_eq!(outcome, Outcome::Full);
+    }
+
+    #[test]
+    fn if_none_match() {
+        let inm = |value| evaluate(&[(header::IF_NONE_MATCH, value)]);
+        assert_eq!(inm("\"abc\""), Outcome::NotModified);
+        assert_eq!(inm("W/\"abc\""), Outcome::NotModified);
+        assert_eq!(inm("\"x\", \"abc\""), Outcome::NotModified);
+        assert_eq!(inm("*"), Outcome::NotModified);
+        assert_eq!(inm("\"x\""), Outcome::Full);
+        assert_eq!(inm("\"a,bc\""), Outcome::Full);
+    }
+
+    #[test]
+    fn if_none_match_lists_span_header_lines() {
+        let outcome = evaluate(&[
+            (header::IF_NONE_MATCH, "\"x\""),
+            (header::IF_NONE_MATCH, "\"abc\""),
+        ]);
+        assert_eq!(outcome, Outcome::NotModified);
+    }
+
+    #[test]
+    fn if_none_match_overrides_if_modified_since() {
+        let outcome = evaluate(&[
+            (header::IF_NONE_MATCH, "\"x\""),
+            (header::IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT"),
+        ]);
+        assert_eq!(outcome, Outcome::Full);
+    }
+
+    #[test]
+    fn malformed_if_none_match_is_ignored() {
+        for value in &["abc", "\"abc", "\"abc\" junk", "W/abc", "\"a\"b\""] {
+            // Ignored, so If-Modified-Since decides instead.
+            let outcome = evaluate(&[
+                (header::IF_NONE_MATCH, value),
+                (header::IF_MODIFIED_SINCE, "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ]);
+            assert_eq!(outcome, Outcome::NotModified, "{}", value);
+        }
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let ims = |value| evaluate(&[(header::IF_MODIFIED_SINCE, value)]);
+        assert_eq!(ims("Sun, 06 Nov 1994 08:49:37 GMT"), Outcome::NotModified);
+        assert_eq!(ims("Sun, 06 Nov 1994 08:49:36 GMT"), Outcome::Full);
+        assert_eq!(ims("not-a-date"), Outcome::Full);
+    }
+
+    #[test]
+    fn not_modified_comes_before_ranges() {
+        let outcome = evaluate(&[(header::IF_NONE_MATCH, ETAG), (header::RANGE, "bytes=20-")]);
+        assert_eq!(outcome, Outcome::NotModified);
+    }
+
+    #[test]
+    fn if_range() {
+        let if_range = |value| evaluate(&[(header::RANGE, "bytes=2-4"), (header::IF_RANGE, value)]);
+        assert_eq!(if_range("\"abc\""), range(2, 4));
+        assert_eq!(if_range("Sun, 06 Nov 1994 08:49:37 GMT"), range(2, 4));
+        // A weak tag never matches strongly.
+        assert_eq!(if_range("W/\"abc\""), Outcome::Full);
+        assert_eq!(if_range("\"old\""), Outcome::Full);
+        assert_eq!(if_range("Sun, 06 Nov 1994 08:49:38 GMT"), Outcome::Full);
+        // Unusable validators can't match, so the range is ignored too.
+        assert_eq!(if_range("*"), Outcome::Full);
+        assert_eq!(if_range("garbage"), Outcome::Full);
+    }
+
+    #[test]
+    fn a_stale_if_range_ignores_even_an_unsatisfiable_range() {
+        let outcome = evaluate(&[(header::RANGE, "bytes=20-"), (header::IF_RANGE, "\"old\"")]);
+        assert_eq!(outcome, Outcome::Full);
+    }
+
+    #[test]
+    fn if_range_without_range_is_ignored() {
+        assert_eq!(evaluate(&[(header::IF_RANGE, "\"old\"")]), Outcome::Full);
+    }
+
+    /// Random header values built from pieces of real ones and noise.
+    fn random_value(rng: &mut StdRng) -> Vec<u8> {
+        const PIECES: &[&str] = &[
+            "bytes=",
+            "W/",
+            "\"",
+            "abc",
+            "-",
+            ",",
+            " ",
+            "\t",
+            "*",
+            "=",
+            "0",
+            "1",
+            "9",
+            "10",
+            "18446744073709551615",
+            "18446744073709551616",
+            "GMT",
+            "Sun, 06 Nov 1994 ",
+            "08:49:37",
+            "\u{e9}",
+            ";",
+            "bytes",
+            "items=",
+        ];
+        let mut value = Vec::new();
+        for _ in 0..rng.gen_range(0..8) {
+            if rng.gen_bool(0.8) {
+                value.extend_from_slice(PIECES[rng.gen_range(0..PIECES.len())].as_bytes());
+            } else {
+                value.push(rng.gen());
+            }
+        }
+        value
+    }
+
+    fn random_headers(rng: &mut StdRng) -> HeaderMap {
+        let names = [
+            header::IF_NONE_MATCH,
+            header::IF_MODIFIED_SINCE,
+            header::IF_RANGE,
+            header::RANGE,
+        ];
+        let mut headers = HeaderMap::new();
+        for name in &names {
+            for _ in 0..rng.gen_range(0..3) {
+                if let Ok(value) = HeaderValue::from_bytes(&random_value(rng)) {
+                    headers.append(name, value);
+                }
+            }
+        }
+        headers
+    }
+
+    #[test]
+    fn no_header_value_breaks_evaluation() {
+        let mut rng = StdRng::seed_from_u64(105);
+        for _ in 0..20_000 {
+            let headers = random_headers(&mut rng);
+            let len = rng.gen_range(0..20);
+            let outcome = Preconditions::parse(&headers).evaluate(
+                &Method::GET,
+                &resource(len),
+                modified() + Duration::from_secs(60),
+                Duration::from_secs(2),
+            );
+            if let Outcome::Partial { start, end } = outcome {
+                assert!(start <= end && end < len, "{:?} for {:?}", outcome, headers);
+            }
+        }
+    }
+
+    #[test]
+    fn no_header_value_gets_stdin_an_error_but_a_416() {
+        let mut rng = StdRng::seed_from_u64(106);
+        for len in 0..12 {
+            let artifact = stdin::Artifact::new("data", vec![b'x'; len], &mime::TEXT_PLAIN, None);
+            for _ in 0..1_000 {
+                let mut req = Request::builder()
+                    .method(if rng.gen() { Method::GET } else { Method::HEAD })
+                    .uri("/data")
+                    .body(Body::empty())
+                    .unwrap();
+                *req.headers_mut() = random_headers(&mut rng);
+                match stdin::serve(&artifact, &req, Duration::from_secs(2)) {
+                    Ok(_) | Err(Error::RangeNotSatisfiable { .. }) => {}
+                    Err(e) => panic!("{} for {:?}", e, req.headers()),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! `Link` preload headers for HTML pages.
+//!
+//! Each `--preload <GLOB>=<LINK>` option becomes a `PreloadRule`. Successful
+//! HTML responses for paths matching the glob get LINK as a `Link` header, so
+//! browsers can start fetching the page's styles and scripts sooner. Every
+//! matching rule adds its header.
+
+use globset::{GlobBuilder, GlobMatcher};
+use http::header::{self, HeaderValue};
+use hyper::{Body, Response};
+
+use crate::urlpath;
+
+#[derive(Clone, Debug)]
+pub struct PreloadRule {
+    matcher: GlobMatcher,
+    link: HeaderValue,
+}
+
+/// Parse a `<GLOB>=<LINK>` command line argument, checking that LINK is a
+/// usable `Link` header value like `</app.css>; rel=preload; as=style`.
+pub fn parse_rule(s: &str) -> std::result::Result<PreloadRule, String> {
+    let (pattern, link) = match s.find('=') {
+        Some(i) => (&s[..i], s[i + 1..].trim()),
+        None => return Err(format!("expected <GLOB>=<LINK>, got '{}'", s)),
+    };
+    if pattern.is_empty() || link.is_empty() {
+        return Err(format!("expected <GLOB>=<LINK>, got '{}'", s));
+    }
+
+    let target_closed = link.starts_with('<') && link[1..].contains('>');
+    let has_rel = link
+        .split(';')
+        .skip(1)
+        .any(|param| param.trim().to_ascii_lowercase().starts_with("rel="));
+    if !target_closed || !has_rel {
+        return Err(format!(
+            "invalid link '{}', expected e.g. '</app.css>; rel=preload; as=style'",
+            link
+        ));
+    }
+    let link =
+        HeaderValue::from_str(link).map_err(|_| format!("invalid header value '{}'", link))?;
+
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| format!("invalid glob '{}': {}", pattern, e))?
+        .compile_matcher();
+
+    Ok(PreloadRule { matcher, link })
+}
+
+/// Add the `Link` headers of every rule matching `path` to a successful HTML
+/// response.
+pub fn add_links(rules: &[PreloadRule], path: &str, resp: &mut Response<Body>) {
+    if rules.is_empty() || !resp.status().is_success() || !is_html(resp) {
+        return;
+    }
+
+    let path = urlpath::normalize(path);
+    for rule in rules.iter().filter(|rule| rule.matcher.is_match(&path)) {
+        resp.headers_mut().append(header::LINK, rule.link.clone());
+    }
+}
+
+fn is_html(resp: &Response<Body>) -> bool {
+    resp.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .trim_start()
+                .to_ascii_lowercase()
+                .starts_with("text/html")
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    const LINK: &str = "</app.css>; rel=preload; as=style";
+
+    fn links(
+        rules: &[PreloadRule],
+        path: &str,
+        status: StatusCode,
+        content_type: &str,
+    ) -> Vec<String> {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = status;
+        resp.headers_mut()
+            .insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+        add_links(rules, path, &mut resp);
+        resp.headers()
+            .get_all(header::LINK)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn good_rules_add_their_links_to_html_pages() {
+        let rules = vec![
+            parse_rule(&format!("/**/*.html={}", LINK)).unwrap(),
+            parse_rule("/index.html= </app.js>; REL=modulepreload ").unwrap(),
+        ];
+        let ok = StatusCode::OK;
+        assert_eq!(
+            links(&rules, "/index.html", ok, "text/html; charset=utf-8"),
+            [LINK, "</app.js>; REL=modulepreload"]
+        );
+        assert_eq!(links(&rules, "/docs/a.html", ok, "Text/HTML"), [LINK]);
+        assert_eq!(
+            links(&rules, "/x/../%69ndex.html", ok, "text/html").len(),
+            2
+        );
+        assert!(links(&rules, "/docs/a.txt", ok, "text/html").is_empty());
+    }
+
+    #[test]
+    fn only_successful_html_responses_get_links() {
+        let rules = vec![parse_rule(&format!("/**={}", LINK)).unwrap()];
+        assert!(links(&rules, "/app.css", StatusCode::OK, "text/css").is_empty());
+        assert!(links(&rules, "/data.json", StatusCode::OK, "application/json").is_empty());
+        assert!(links(&rules, "/gone.html", StatusCode::NOT_FOUND, "text/html").is_empty());
+        assert!(links(
+            &rules,
+            "/x.html",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "text/html"
+        )
+        .is_empty());
+        assert_eq!(
+            links(
+                &rules,
+                "/part.html",
+                StatusCode::PARTIAL_CONTENT,
+                "text/html"
+            ),
+            [LINK]
+        );
+
+        let mut resp = Response::new(Body::empty());
+        add_links(&rules, "/untyped", &mut resp);
+        assert!(resp.headers().get(header::LINK).is_none());
+    }
+
+    #[test]
+    fn bad_links_are_refused() {
+        // No <target>, or one that isn't closed.
+        assert!(parse_rule("/**=/app.css; rel=preload").is_err());
+        assert!(parse_rule("/**=</app.css; rel=preload").is_err());
+        // No rel, or only something that looks like one in the target.
+        assert!(parse_rule("/**=</app.css>; as=style").is_err());
+        assert!(parse_rule("/**=</rel=x>").is_err());
+        assert!(parse_rule("/**=</app.css>; rel=\"preload\x01\"").is_err());
+        assert!(parse_rule(&format!("={}", LINK)).is_err());
+        assert!(parse_rule("/**=").is_err());
+        assert!(parse_rule("/**").is_err());
+        assert!(parse_rule(&format!("/[={}", LINK)).is_err());
+    }
+}
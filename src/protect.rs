@@ -0,0 +1,363 @@
+//! Access rules: bearer-token protected areas and required headers.
+//!
+//! Each `--protect <GLOB>=<TOKEN>` option becomes a `ProtectRule`. A request
+//! whose decoded path matches a rule must carry `Authorization: Bearer
+//! <TOKEN>`, otherwise it is answered with a 401. Bearer tokens can't hold a
+//! colon, so a `<USER>:<PASSWORD>` in place of the token asks for those Basic
+//! credentials instead, which browsers prompt for.
+//!
+//! Each `--require-header NAME[=VALUE]` option becomes a `HeaderRule`. Every
+//! request, except those for paths matching `--require-header-exempt`, must
+//! carry each required header, with one of its configured values if any were
+//! given, otherwise it is answered with a 403.
+
+use globset::{GlobBuilder, GlobMatcher};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::status::StatusCode;
+use hyper::{Body, Response};
+use std::fmt;
+
+use crate::{urlpath, Result};
+
+#[derive(Clone, Debug)]
+pub struct ProtectRule {
+    pattern: String,
+    matcher: GlobMatcher,
+    credentials: Credentials,
+}
+
+/// What a protected area's requests have to present.
+#[derive(Clone, Debug)]
+enum Credentials {
+    Bearer(String),
+    /// `user:password`, as Basic credentials decode to.
+    Basic(String),
+}
+
+impl ProtectRule {
+    /// The glob this rule was created from, used for logging.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the request headers carry this rule's credentials.
+    pub fn authorizes(&self, headers: &HeaderMap) -> bool {
+        let value = match headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(value) => value,
+            None => return false,
+        };
+        let (sent, expected) = match &self.credentials {
+            Credentials::Bearer(token) => match credentials(value, "bearer") {
+                Some(sent) => (sent.as_bytes().to_vec(), token),
+                None => return false,
+            },
+            Credentials::Basic(user_password) => {
+                match credentials(value, "basic").and_then(|sent| base64::decode(sent).ok()) {
+                    Some(sent) => (sent, user_password),
+                    None => return false,
+                }
+            }
+        };
+        constant_time_eq(&sent, expected.as_bytes())
+    }
+
+    /// The 401 response for a request that lacks this rule's credentials.
+    pub fn unauthorized(&self) -> Result<Response<Body>> {
+        let challenge = match self.credentials {
+            Credentials::Bearer(_) => "Bearer realm=\"sufficient\"",
+            Credentials::Basic(_) => "Basic realm=\"sufficient\"",
+        };
+        let resp = Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(challenge),
+            )
+            .body(Body::empty())
+            .map_err(crate::Error::Http)?;
+
+        Ok(resp)
+    }
+}
+
+/// Parse a `<GLOB>=<TOKEN>` command line argument.
+pub fn parse_rule(s: &str) -> std::result::Result<ProtectRule, String> {
+    let (pattern, token) = match s.find('=') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(format!("expected <GLOB>=<TOKEN>, got '{}'", s)),
+    };
+    if pattern.is_empty() || token.is_empty() {
+        return Err(format!("expected <GLOB>=<TOKEN>, got '{}'", s));
+    }
+
+    let matcher = parse_glob(pattern)?;
+    let credentials = if token.contains(':') {
+        Credentials::Basic(token.to_string())
+    } else {
+        Credentials::Bearer(token.to_string())
+    };
+
+    Ok(ProtectRule {
+        pattern: pattern.to_string(),
+        matcher,
+        credentials,
+    })
+}
+
+/// A header every request has to carry.
+#[derive(Clone, Debug)]
+pub struct HeaderRule {
+    name: HeaderName,
+    value: Option<String>,
+}
+
+impl HeaderRule {
+    /// The header this rule requires.
+    #[cfg(feature = "record")]
+    pub fn name(&self) -> &HeaderName {
+        &self.name
+    }
+}
+
+/// Parse a `NAME[=VALUE]` command line argument.
+pub fn parse_header_rule(s: &str) -> std::result::Result<HeaderRule, String> {
+    let (name, value) = match s.find('=') {
+        Some(i) => (&s[..i], Some(s[i + 1..].to_string())),
+        None => (s, None),
+    };
+    let name = name
+        .parse::<HeaderName>()
+        .map_err(|_| format!("invalid header name '{}'", name))?;
+
+    Ok(HeaderRule { name, value })
+}
+
+/// Parse a glob matched against decoded request paths, such as the paths
+/// that don't need the required headers.
+pub fn parse_glob(s: &str) -> std::result::Result<GlobMatcher, String> {
+    let matcher = GlobBuilder::new(s)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| format!("invalid glob '{}': {}", s, e))?
+        .compile_matcher();
+
+    Ok(matcher)
+}
+
+/// The outcome of checking a request's required headers.
+pub enum HeaderCheck {
+    /// No headers are required.
+    NotRequired,
+    /// The path is exempt from the requirement.
+    Exempt,
+    Passed,
+    /// This header is missing or has none of its allowed values.
+    Failed(HeaderName),
+}
+
+impl HeaderCheck {
+    pub fn passed(&self) -> bool {
+        !matches!(self, HeaderCheck::Failed(_))
+    }
+}
+
+impl fmt::Display for HeaderCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderCheck::NotRequired => f.write_str("-"),
+            HeaderCheck::Exempt => f.write_str("exempt"),
+            HeaderCheck::Passed => f.write_str("ok"),
+            HeaderCheck::Failed(name) => write!(f, "failed {}", name),
+        }
+    }
+}
+
+/// Check that the request carries every required header. Rules naming the
+/// same header are alternatives: any one of their values will do, and a rule
+/// without a value accepts any. `path` is the raw request path.
+pub fn check_headers(
+    rules: &[HeaderRule],
+    exempt: &[GlobMatcher],
+    path: &str,
+    headers: &HeaderMap,
+) -> HeaderCheck {
+    if rules.is_empty() {
+        return HeaderCheck::NotRequired;
+    }
+    let path = urlpath::normalize(path);
+    if exempt.iter().any(|glob| glob.is_match(&path)) {
+        return HeaderCheck::Exempt;
+    }
+
+    for rule in rules {
+        let alternatives = rules.iter().filter(|other| other.name == rule.name);
+        let sent: Vec<&[u8]> = headers
+            .get_all(&rule.name)
+            .iter()
+            .map(|value| value.as_bytes())
+            .collect();
+        let satisfied = alternatives
+            .map(|other| match &other.value {
+                None => !sent.is_empty(),
+                // Check every value, so timing doesn't reveal which matched.
+                Some(value) => sent.iter().fold(false, |matched, sent| {
+                    constant_time_eq(sent, value.as_bytes()) | matched
+                }),
+            })
+            .fold(false, |satisfied, matched| satisfied | matched);
+        if !satisfied {
+            return HeaderCheck::Failed(rule.name.clone());
+        }
+    }
+
+    HeaderCheck::Passed
+}
+
+/// Find the rule protecting `path`, preferring the longest matching pattern.
+///
+/// `path` is the raw request path; it is decoded and has its dot segments
+/// resolved before matching, so `/public/../private/x` can't dodge a rule
+/// for `/private/**`.
+pub fn matching_rule<'a>(rules: &'a [ProtectRule], path: &str) -> Option<&'a ProtectRule> {
+    if rules.is_empty() {
+        return None;
+    }
+
+    let path = urlpath::normalize(path);
+    rules
+        .iter()
+        .filter(|rule| rule.matcher.is_match(&path))
+        .max_by_key(|rule| rule.pattern.len())
+}
+
+/// The credentials in an `Authorization` value using `scheme`.
+fn credentials<'a>(value: &'a str, scheme: &str) -> Option<&'a str> {
+    let (sent, credentials) = value.split_at(value.find(' ')?);
+    if sent.eq_ignore_ascii_case(scheme) {
+        Some(credentials.trim())
+    } else {
+        None
+    }
+}
+
+/// Compare two byte strings without exiting early on the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(specs: &[&str]) -> Vec<ProtectRule> {
+        specs.iter().map(|spec| parse_rule(spec).unwrap()).collect()
+    }
+
+    fn authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn bearer_tokens_are_checked_whole() {
+        let rule = parse_rule("/private/**=s3cret").unwrap();
+        assert!(rule.authorizes(&authorization("Bearer s3cret")));
+        assert!(rule.authorizes(&authorization("bearer s3cret")));
+        assert!(rule.authorizes(&authorization("BEARER   s3cret ")));
+
+        assert!(!rule.authorizes(&authorization("Bearer wrong!")));
+        // Right as far as they go, but too short or too long.
+        assert!(!rule.authorizes(&authorization("Bearer s3cre")));
+        assert!(!rule.authorizes(&authorization("Bearer s3cret2")));
+        assert!(!rule.authorizes(&authorization("Bearer")));
+        assert!(!rule.authorizes(&authorization("Bearer ")));
+        assert!(!rule.authorizes(&authorization("Token s3cret")));
+        assert!(!rule.authorizes(&authorization("s3cret")));
+        assert!(!rule.authorizes(&HeaderMap::new()));
+        // The token's Basic encoding doesn't stand in for it.
+        let basic = format!("Basic {}", base64::encode("s3cret"));
+        assert!(!rule.authorizes(&authorization(&basic)));
+    }
+
+    #[test]
+    fn user_and_password_rules_take_basic_credentials() {
+        let rule = parse_rule("/private/**=alice:pass:word").unwrap();
+        let basic = |credentials: &str| authorization(&format!("Basic {}", credentials));
+        assert!(rule.authorizes(&basic(&base64::encode("alice:pass:word"))));
+        assert!(rule.authorizes(&authorization(&format!(
+            "basic {}",
+            base64::encode("alice:pass:word")
+        ))));
+
+        assert!(!rule.authorizes(&basic(&base64::encode("alice:pass"))));
+        assert!(!rule.authorizes(&basic(&base64::encode("alice:pass:words"))));
+        assert!(!rule.authorizes(&basic(&base64::encode("bob:pass:word"))));
+        assert!(!rule.authorizes(&basic("not base64!")));
+        assert!(!rule.authorizes(&authorization("Bearer alice:pass:word")));
+        assert!(!rule.authorizes(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn the_challenge_names_the_scheme_asked_for() {
+        for (spec, challenge) in &[
+            ("/**=token", "Bearer realm=\"sufficient\""),
+            ("/**=user:password", "Basic realm=\"sufficient\""),
+        ] {
+            let resp = parse_rule(spec).unwrap().unauthorized().unwrap();
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(resp.headers()[header::WWW_AUTHENTICATE], *challenge);
+        }
+    }
+
+    #[test]
+    fn the_longest_matching_glob_wins() {
+        let rules = rules(&[
+            "/docs/**=outer",
+            "/docs/private/**=inner",
+            "/docs/private/*.pdf=pdf",
+        ]);
+        let matching = |path| matching_rule(&rules, path).map(|rule| rule.pattern());
+        assert_eq!(matching("/docs/readme.md"), Some("/docs/**"));
+        assert_eq!(
+            matching("/docs/private/notes.txt"),
+            Some("/docs/private/**")
+        );
+        assert_eq!(
+            matching("/docs/private/report.pdf"),
+            Some("/docs/private/*.pdf")
+        );
+        assert_eq!(
+            matching("/docs/private/a/report.pdf"),
+            Some("/docs/private/**")
+        );
+        assert_eq!(matching("/index.html"), None);
+
+        // Only the winning rule's token opens it.
+        let rule = matching_rule(&rules, "/docs/private/report.pdf").unwrap();
+        assert!(rule.authorizes(&authorization("Bearer pdf")));
+        assert!(!rule.authorizes(&authorization("Bearer outer")));
+    }
+
+    #[test]
+    fn paths_are_matched_decoded_and_normalized() {
+        let rules = rules(&["/private/**=token"]);
+        assert!(matching_rule(&rules, "/public/../private/x").is_some());
+        assert!(matching_rule(&rules, "/%70rivate/x").is_some());
+        assert!(matching_rule(&rules, "/public/x").is_none());
+    }
+
+    #[test]
+    fn malformed_rules_are_refused() {
+        assert!(parse_rule("/private/**").is_err());
+        assert!(parse_rule("=token").is_err());
+        assert!(parse_rule("/private/**=").is_err());
+        assert!(parse_rule("/private/[=token").is_err());
+    }
+}
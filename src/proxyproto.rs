@@ -0,0 +1,568 @@
+//! `--proxy-protocol`: taking the client's address from the PROXY protocol
+//! preamble a load balancer sends before the HTTP traffic.
+//!
+//! Both the text v1 and binary v2 preambles are accepted. The address the
+//! preamble names is used as the peer address everywhere one is, in the
+//! access log, the allowed-hosts check and `--max-per-ip`. Only peers in
+//! `--trusted-proxy` may send one; other connections, and preambles that
+//! don't parse or don't arrive within `PREAMBLE_TIMEOUT`, are closed. A v2
+//! `LOCAL` preamble, or one for an unknown protocol, keeps the socket's own
+//! address, and v2 TLVs are skipped.
+//!
+//! Preambles are read concurrently, up to `MAX_PENDING` at a time, so a slow
+//! one doesn't hold up the connections behind it.
+
+use futures::future;
+use futures::stream::{Stream, StreamExt};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::accept::Connection;
+use crate::hosts;
+
+/// How long a connection has to send its preamble.
+const PREAMBLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most preambles read at once.
+const MAX_PENDING: usize = 256;
+
+/// The longest v1 preamble, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// A network whose peers may send a preamble, as given to `--trusted-proxy`.
+#[derive(Clone, Copy, Debug)]
+pub struct TrustedProxy {
+    net: IpAddr,
+    prefix: u8,
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.net, hosts::canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask(u32::from(net).into(), 32, self.prefix)
+                    == mask(u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(net.into(), 128, self.prefix) == mask(ip.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The first `prefix` bits of a `bits`-bit address.
+fn mask(addr: u128, bits: u8, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        addr >> (bits - prefix)
+    }
+}
+
+/// Parse a `--trusted-proxy` argument, an address or a network like
+/// `10.0.0.0/8`.
+pub fn parse_trusted(s: &str) -> std::result::Result<TrustedProxy, String> {
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s, None),
+    };
+    let net = addr
+        .parse::<IpAddr>()
+        .map_err(|_| format!("'{}' isn't an IP address or network", s))?;
+    let net = hosts::canonical(net);
+    let bits = if net.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => match prefix.parse::<u8>() {
+            Ok(prefix) if prefix <= bits => prefix,
+            _ => return Err(format!("invalid prefix length in '{}'", s)),
+        },
+        None => bits,
+    };
+
+    Ok(TrustedProxy { net, prefix })
+}
+
+/// A connection whose preamble has been read, reporting the client's address
+/// as its peer.
+pub struct ProxyConn<C> {
+    inner: C,
+    client: SocketAddr,
+    /// What was read past the preamble, which is the start of the HTTP
+    /// traffic.
+    rest: Vec<u8>,
+    pos: usize,
+}
+
+/// Read the preamble of every connection in `incoming`, dropping those that
+/// are refused.
+pub fn accept<I, C>(
+    incoming: I,
+    trusted: Arc<Vec<TrustedProxy>>,
+) -> impl Stream<Item = io::Result<ProxyConn<C>>>
+where
+    I: Stream<Item = io::Result<C>>,
+    C: Connection + AsyncRead + Unpin,
+{
+    incoming
+        .map(move |conn| {
+            let trusted = trusted.clone();
+            async move {
+                match conn {
+                    Ok(conn) => handshake(conn, &trusted).await.map(Ok),
+                    Err(e) => Some(Err(e)),
+                }
+            }
+        })
+        .buffer_unordered(MAX_PENDING)
+        .filter_map(future::ready)
+}
+
+async fn handshake<C>(mut conn: C, trusted: &[TrustedProxy]) -> Option<ProxyConn<C>>
+where
+    C: Connection + AsyncRead + Unpin,
+{
+    let peer = conn.peer_addr().ok()?;
+    if !trusted.iter().any(|net| net.contains(peer.ip())) {
+        warn!(
+            "closing connection from {}, which isn't a --trusted-proxy",
+            peer
+        );
+        return None;
+    }
+
+    let read = tokio::time::timeout(PREAMBLE_TIMEOUT, read_preamble(&mut conn)).await;
+    let (source, rest) = match read {
+        Ok(Ok(read)) => read,
+        Ok(Err(e)) => {
+            warn!("closing connection from {}: {}", peer, e);
+            return None;
+        }
+        Err(_) => {
+            warn!(
+                "closing connection from {}: no PROXY preamble in time",
+                peer
+            );
+            return None;
+        }
+    };
+
+    let client = source.unwrap_or(peer);
+    trace!("{} is proxying for {}", peer, client);
+    Some(ProxyConn {
+        inner: conn,
+        client,
+        rest,
+        pos: 0,
+    })
+}
+
+/// Read a preamble, returning the source address it names, if any, and
+/// whatever was read after it.
+async fn read_preamble<C: AsyncRead + Unpin>(
+    conn: &mut C,
+) -> io::Result<(Option<SocketAddr>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(256);
+    loop {
+        if let Some((source, len)) = parse(&buf)? {
+            return Ok((source, buf.split_off(len)));
+        }
+
+        let mut chunk = [0; 256];
+        let n = conn.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(invalid("connection closed before its PROXY preamble ended"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Parse the preamble at the start of `buf`, returning the source address and
+/// the preamble's length, or `None` if more bytes are needed.
+fn parse(buf: &[u8]) -> io::Result<Option<(Option<SocketAddr>, usize)>> {
+    let v2 = V2_SIGNATURE.len().min(buf.len());
+    if buf[..v2] == V2_SIGNATURE[..v2] {
+        return if buf.len() < 16 {
+            Ok(None)
+        } else {
+            parse_v2(buf)
+        };
+    }
+    let v1 = b"PROXY ".len().min(buf.len());
+    if buf[..v1] == b"PROXY "[..v1] {
+        return parse_v1(buf);
+    }
+
+    Err(invalid("connection didn't start with a PROXY preamble"))
+}
+
+/// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`
+fn parse_v1(buf: &[u8]) -> io::Result<Option<(Option<SocketAddr>, usize)>> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        None => return Err(invalid("PROXY v1 preamble is too long")),
+    };
+    if end + 2 > V1_MAX_LEN {
+        return Err(invalid("PROXY v1 preamble is too long"));
+    }
+    let line =
+        std::str::from_utf8(&buf[..end]).map_err(|_| invalid("PROXY v1 preamble isn't text"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let src: IpAddr = src.parse().map_err(|_| invalid("bad PROXY v1 address"))?;
+            let _: IpAddr = dst.parse().map_err(|_| invalid("bad PROXY v1 address"))?;
+            let sport: u16 = sport.parse().map_err(|_| invalid("bad PROXY v1 port"))?;
+            let _: u16 = dport.parse().map_err(|_| invalid("bad PROXY v1 port"))?;
+            if src.is_ipv4() != (*proto == "TCP4") {
+                return Err(invalid("PROXY v1 address doesn't match its protocol"));
+            }
+            Some(SocketAddr::new(src, sport))
+        }
+        _ => return Err(invalid("malformed PROXY v1 preamble")),
+    };
+
+    Ok(Some((source, end + 2)))
+}
+
+/// The binary preamble: the signature, version and command, family and
+/// protocol, the length of the rest, then the addresses and any TLVs.
+fn parse_v2(buf: &[u8]) -> io::Result<Option<(Option<SocketAddr>, usize)>> {
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addrs = &buf[16..len];
+
+    let source = match (version_command & 0x0f, family) {
+        // LOCAL: the proxy's own connection, like a health check.
+        (0, _) => None,
+        // PROXY over TCP or UDP, on IPv4.
+        (1, 0x11) | (1, 0x12) if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        // PROXY over TCP or UDP, on IPv6.
+        (1, 0x21) | (1, 0x22) if addrs.len() >= 36 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        }
+        (1, 0x11) | (1, 0x12) | (1, 0x21) | (1, 0x22) => {
+            return Err(invalid("PROXY v2 addresses are truncated"));
+        }
+        // Unspecified or Unix socket addresses say nothing we can use.
+        (1, _) => None,
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    };
+
+    Ok(Some((source, len)))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<C: Connection> Connection for ProxyConn<C> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.client)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn id(&self) -> Option<u64> {
+        self.inner.id()
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for ProxyConn<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.rest.len() {
+            let n = buf.remaining().min(self.rest.len() - self.pos);
+            let pos = self.pos;
+            buf.put_slice(&self.rest[pos..pos + n]);
+            self.pos += n;
+            if self.pos == self.rest.len() {
+                self.rest = Vec::new();
+                self.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for ProxyConn<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+    use tokio::runtime::Runtime;
+
+    /// One end of an in-memory connection, from `peer`.
+    struct Fake {
+        peer: SocketAddr,
+        stream: DuplexStream,
+    }
+
+    impl Connection for Fake {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.peer)
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok("192.0.2.100:80".parse().unwrap())
+        }
+    }
+
+    impl AsyncRead for Fake {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    /// A v2 preamble with `command`, `family` and `addrs`.
+    fn v2(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family);
+        buf.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+        buf.extend_from_slice(addrs);
+        buf
+    }
+
+    fn v4_addrs() -> Vec<u8> {
+        let mut addrs = vec![192, 0, 2, 1, 192, 0, 2, 2];
+        addrs.extend_from_slice(&56324u16.to_be_bytes());
+        addrs.extend_from_slice(&443u16.to_be_bytes());
+        addrs
+    }
+
+    fn v6_addrs() -> Vec<u8> {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut addrs = src.octets().to_vec();
+        addrs.extend_from_slice(&dst.octets());
+        addrs.extend_from_slice(&56324u16.to_be_bytes());
+        addrs.extend_from_slice(&443u16.to_be_bytes());
+        addrs
+    }
+
+    #[test]
+    fn parses_v1_preambles() {
+        let line = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET";
+        let source = addr("192.0.2.1:56324");
+        assert_eq!(parse(line).unwrap(), Some((source, line.len() - 3)));
+
+        let line = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let source = addr("[2001:db8::1]:56324");
+        assert_eq!(parse(line).unwrap(), Some((source, line.len())));
+
+        let line = b"PROXY UNKNOWN\r\n";
+        assert_eq!(parse(line).unwrap(), Some((None, line.len())));
+        let line = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+        assert_eq!(parse(line).unwrap(), Some((None, line.len())));
+    }
+
+    #[test]
+    fn refuses_bad_v1_preambles() {
+        assert!(parse(b"PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP6 192.0.2.1 192.0.2.2 1 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 70000 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1\r\n").is_err());
+        assert!(parse(b"PROXY UDP4 192.0.2.1 192.0.2.2 1 2\r\n").is_err());
+    }
+
+    #[test]
+    fn v1_preambles_wait_for_their_end_but_not_forever() {
+        assert_eq!(parse(b"PRO").unwrap(), None);
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.1 ").unwrap(), None);
+
+        let long = format!("PROXY UNKNOWN {}", "x".repeat(V1_MAX_LEN));
+        assert!(parse(long.as_bytes()).is_err());
+        let long = format!("{}\r\n", &long[..V1_MAX_LEN - 1]);
+        assert!(parse(long.as_bytes()).is_err());
+        let longest = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LEN - 16));
+        assert_eq!(longest.len(), V1_MAX_LEN);
+        assert!(parse(longest.as_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn parses_v2_preambles() {
+        for family in &[0x11, 0x12] {
+            let buf = v2(1, *family, &v4_addrs());
+            let source = addr("192.0.2.1:56324");
+            assert_eq!(parse(&buf).unwrap(), Some((source, buf.len())));
+        }
+        for family in &[0x21, 0x22] {
+            let buf = v2(1, *family, &v6_addrs());
+            let source = addr("[2001:db8::1]:56324");
+            assert_eq!(parse(&buf).unwrap(), Some((source, buf.len())));
+        }
+
+        // LOCAL keeps the socket's address, whatever the family.
+        let buf = v2(0, 0x11, &v4_addrs());
+        assert_eq!(parse(&buf).unwrap(), Some((None, buf.len())));
+        let buf = v2(0, 0x21, &v6_addrs());
+        assert_eq!(parse(&buf).unwrap(), Some((None, buf.len())));
+        let buf = v2(0, 0x00, &[]);
+        assert_eq!(parse(&buf).unwrap(), Some((None, buf.len())));
+
+        // TLVs after the addresses are skipped.
+        let mut addrs = v4_addrs();
+        addrs.extend_from_slice(&[0x04, 0x00, 0x02, 0xab, 0xcd]);
+        let buf = v2(1, 0x11, &addrs);
+        let source = addr("192.0.2.1:56324");
+        assert_eq!(parse(&buf).unwrap(), Some((source, buf.len())));
+    }
+
+    #[test]
+    fn v2_preambles_wait_for_their_length() {
+        let buf = v2(1, 0x21, &v6_addrs());
+        for end in 1..buf.len() {
+            assert_eq!(parse(&buf[..end]).unwrap(), None, "cut at {}", end);
+        }
+    }
+
+    #[test]
+    fn refuses_bad_v2_preambles() {
+        // Addresses shorter than their family needs.
+        assert!(parse(&v2(1, 0x11, &v4_addrs()[..11])).is_err());
+        assert!(parse(&v2(1, 0x21, &v4_addrs())).is_err());
+
+        let mut buf = v2(1, 0x11, &v4_addrs());
+        buf[12] = 0x11;
+        assert!(parse(&buf).is_err(), "version 1");
+        buf[12] = 0x22;
+        assert!(parse(&buf).is_err(), "command 2");
+
+        let mut buf = v2(1, 0x11, &v4_addrs());
+        buf[9] = b'X';
+        assert!(parse(&buf).is_err(), "bad signature");
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    /// Handshake a connection from `peer` that sends `pieces`, then reads
+    /// the rest of what was sent.
+    fn handshake_with(peer: &str, pieces: &[&[u8]]) -> Option<(SocketAddr, Vec<u8>)> {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (stream, mut client) = tokio::io::duplex(64);
+            let pieces: Vec<Vec<u8>> = pieces.iter().map(|piece| piece.to_vec()).collect();
+            tokio::spawn(async move {
+                for piece in pieces {
+                    client.write_all(&piece).await.unwrap();
+                    tokio::task::yield_now().await;
+                }
+            });
+
+            let fake = Fake {
+                peer: peer.parse().unwrap(),
+                stream,
+            };
+            let trusted = [parse_trusted("10.0.0.0/8").unwrap()];
+            let mut conn = handshake(fake, &trusted).await?;
+            let mut rest = Vec::new();
+            conn.read_to_end(&mut rest).await.unwrap();
+            Some((conn.peer_addr().unwrap(), rest))
+        })
+    }
+
+    #[test]
+    fn the_client_is_the_peer_and_the_rest_is_passed_through() {
+        let preamble = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n";
+        let (peer, rest) = handshake_with(
+            "10.1.2.3:40000",
+            &[
+                &preamble[..10],
+                &preamble[10..],
+                b"GET / HTTP/1.1\r\n",
+                b"\r\n",
+            ],
+        )
+        .unwrap();
+        assert_eq!(peer, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+
+        let mut sent = v2(0, 0x11, &v4_addrs());
+        sent.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        let (peer, rest) = handshake_with("10.1.2.3:40000", &[&sent]).unwrap();
+        assert_eq!(peer, "10.1.2.3:40000".parse().unwrap());
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn refuses_untrusted_peers_and_bad_preambles() {
+        let preamble: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n";
+        assert!(handshake_with("192.0.2.50:40000", &[preamble]).is_none());
+        assert!(handshake_with("10.1.2.3:40000", &[b"GET / HTTP/1.1\r\n\r\n"]).is_none());
+        // Closed before the preamble ended.
+        assert!(handshake_with("10.1.2.3:40000", &[&preamble[..20]]).is_none());
+    }
+
+    #[test]
+    fn trusted_networks() {
+        let net = parse_trusted("10.0.0.0/8").unwrap();
+        assert!(net.contains("10.255.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(!net.contains("2001:db8::1".parse().unwrap()));
+        let net = parse_trusted("2001:db8::/32").unwrap();
+        assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(parse_trusted("0.0.0.0/0")
+            .unwrap()
+            .contains("192.0.2.1".parse().unwrap()));
+        assert!(parse_trusted("10.0.0.0/33").is_err());
+        assert!(parse_trusted("proxy").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+//! The query parameters this server understands, parsed once per request.
+//!
+//! Only `uri.path()` is ever used to find what to serve; the query string is
+//! parsed here into `QueryParams` for the features that read parameters, and
+//! everything else in it is ignored. A known parameter given twice with
+//! different values is refused rather than resolved by position, since a
+//! proxy in front of us might pick the other one.
+
+use crate::urlpath::{self, Decoding};
+use crate::{Error, Result};
+
+/// The longest query string that is parsed.
+const MAX_LEN: usize = 8 * 1024;
+
+#[derive(Debug, Default)]
+pub struct QueryParams {
+    /// `expires`, the expiry of a signed link as a unix timestamp.
+    pub expires: Option<String>,
+    /// `sig`, the signature of a signed link.
+    pub sig: Option<String>,
+    /// `algo`, the digest a `/_api/hash/` request asks for.
+    pub algo: Option<String>,
+}
+
+impl QueryParams {
+    /// Parse a request's query string, if it has one.
+    pub fn parse(query: Option<&str>) -> Result<QueryParams> {
+        let query = match query {
+            Some(query) => query,
+            None => return Ok(QueryParams::default()),
+        };
+        if query.len() > MAX_LEN {
+            return Err(Error::BadQuery(format!(
+                "query string longer than {} bytes",
+                MAX_LEN
+            )));
+        }
+
+        let mut params = QueryParams::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => (pair, ""),
+            };
+            // Keys that don't decode aren't ones we know.
+            let (name, slot) = match urlpath::decode(key, Decoding::Strict).as_deref() {
+                Ok("expires") => ("expires", &mut params.expires),
+                Ok("sig") => ("sig", &mut params.sig),
+                Ok("algo") => ("algo", &mut params.algo),
+                _ => continue,
+            };
+
+            let value = urlpath::decode(value, Decoding::Strict)
+                .map_err(|_| Error::BadQuery(format!("{} doesn't decode to text", name)))?
+                .into_owned();
+            match slot {
+                Some(existing) if *existing != value => {
+                    return Err(Error::BadQuery(format!("conflicting values for {}", name)));
+                }
+                _ => *slot = Some(value),
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Result<QueryParams> {
+        QueryParams::parse(Some(query))
+    }
+
+    #[test]
+    fn known_parameters() {
+        let params = parse("expires=1700000000&sig=abc&other=1").unwrap();
+        assert_eq!(params.expires.as_deref(), Some("1700000000"));
+        assert_eq!(params.sig.as_deref(), Some("abc"));
+
+        let params = QueryParams::parse(None).unwrap();
+        assert!(params.expires.is_none() && params.sig.is_none());
+    }
+
+    #[test]
+    fn keys_and_values_are_decoded() {
+        let params = parse("%73ig=a%2Fb+c").unwrap();
+        assert_eq!(params.sig.as_deref(), Some("a/b+c"));
+    }
+
+    #[test]
+    fn empty_keys_and_pairs_are_ignored() {
+        let params = parse("&&=x&=&sig&").unwrap();
+        assert_eq!(params.sig.as_deref(), Some(""));
+        assert!(params.expires.is_none());
+    }
+
+    #[test]
+    fn repeated_keys_must_agree() {
+        assert_eq!(parse("sig=a&sig=a").unwrap().sig.as_deref(), Some("a"));
+        assert_eq!(parse("sig=a&sig=%61").unwrap().sig.as_deref(), Some("a"));
+        assert!(matches!(parse("sig=a&sig=b"), Err(Error::BadQuery(_))));
+        assert!(matches!(parse("sig=a&%73ig=b"), Err(Error::BadQuery(_))));
+        // Unknown parameters may repeat however they like.
+        assert!(parse("x=1&x=2").is_ok());
+    }
+
+    #[test]
+    fn bad_percent_escapes() {
+        // Escapes that aren't one are kept as they are.
+        assert_eq!(parse("sig=%zz%4").unwrap().sig.as_deref(), Some("%zz%4"));
+        // Ones that decode to something other than text are refused.
+        assert!(matches!(parse("sig=%ff"), Err(Error::BadQuery(_))));
+        assert!(matches!(parse("sig=a%00"), Err(Error::BadQuery(_))));
+        // An unknown key that doesn't decode is skipped, not refused.
+        assert!(parse("%ff=1&x=%ff").is_ok());
+    }
+
+    #[test]
+    fn overlong_queries_are_refused() {
+        let query = format!("sig=a&pad={}", "x".repeat(MAX_LEN));
+        assert!(matches!(parse(&query), Err(Error::BadQuery(_))));
+
+        let query = format!("sig=a&pad={}", "x".repeat(MAX_LEN - "sig=a&pad=".len()));
+        assert_eq!(parse(&query).unwrap().sig.as_deref(), Some("a"));
+    }
+}
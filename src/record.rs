@@ -0,0 +1,346 @@
+//! `--record` mode: capturing whole exchanges for debugging, available with
+//! the `record` feature.
+//!
+//! Every request whose decoded path matches a `--record-filter` (or every
+//! request, without one) is written to its own file in the record directory,
+//! once its response body has been sent or the connection dropped. The file
+//! holds the request head, a blank line, then the response head and the
+//! first `--record-body-size` bytes of its body, in HTTP/1.1 message syntax,
+//! so it can be used as a test fixture as-is. Credentials are replaced by
+//! `[redacted]`, and recording stops after `--record-max` exchanges. Each
+//! recording reserves room for a whole body in the `--memory-budget`, and
+//! requests are not recorded while there is none.
+
+use globset::GlobMatcher;
+use http::header::{self, HeaderMap, HeaderName};
+use hyper::body::{Bytes, HttpBody, SizeHint};
+use hyper::{Body, Request, Response};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::memory::{Budget, Category, Reservation};
+use crate::{urlpath, Error, Result};
+
+/// Headers that carry credentials, and are never written out.
+const SENSITIVE: &[HeaderName] = &[
+    header::AUTHORIZATION,
+    header::COOKIE,
+    header::PROXY_AUTHORIZATION,
+    header::SET_COOKIE,
+];
+
+pub struct Recorder {
+    dir: PathBuf,
+    filters: Vec<GlobMatcher>,
+    max: u64,
+    body_limit: usize,
+    /// Headers to redact besides `SENSITIVE`, like the --require-header ones.
+    sensitive: Vec<HeaderName>,
+    memory: Option<Arc<Budget>>,
+    started: AtomicU64,
+}
+
+impl Recorder {
+    /// A recorder writing into `dir`, which is created if it doesn't exist.
+    pub fn new(
+        dir: &Path,
+        filters: Vec<GlobMatcher>,
+        max: u64,
+        body_limit: u64,
+        sensitive: Vec<HeaderName>,
+        memory: Option<Arc<Budget>>,
+    ) -> Result<Recorder> {
+        // Find out now rather than on the first request if the directory
+        // can't be written to, without leaving anything in it.
+        fs::create_dir_all(dir)
+            .and_then(|()| fs::metadata(dir))
+            .and_then(|meta| {
+                if meta.permissions().readonly() {
+                    Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                } else {
+                    Ok(())
+                }
+            })
+            .map_err(|e| Error::RecordDir(dir.to_path_buf(), e))?;
+
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            filters,
+            max,
+            body_limit: body_limit as usize,
+            sensitive,
+            memory,
+            started: AtomicU64::new(0),
+        })
+    }
+
+    /// Start recording `req`, if it is to be recorded.
+    pub fn start(self: &Arc<Self>, req: &Request<Body>) -> Option<Recording> {
+        let path = urlpath::normalize(req.uri().path());
+        if !self.filters.is_empty() && !self.filters.iter().any(|f| f.is_match(&path)) {
+            return None;
+        }
+
+        let memory = match &self.memory {
+            Some(budget) => match budget.reserve(Category::Record, self.body_limit as u64) {
+                Some(reservation) => Some(reservation),
+                None => {
+                    debug!("not recording {}: memory budget used up ({})", path, budget);
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        let seq = self.started.fetch_add(1, Ordering::Relaxed);
+        if seq >= self.max {
+            if seq == self.max {
+                warn!(
+                    "stopped recording after {} requests (--record-max)",
+                    self.max
+                );
+            }
+            return None;
+        }
+
+        let mut text = format!("{} {} {:?}\r\n", req.method(), req.uri(), req.version());
+        self.write_headers(&mut text, req.headers());
+        text.push_str("\r\n");
+
+        Some(Recording {
+            recorder: self.clone(),
+            seq,
+            text,
+            body: Vec::new(),
+            total: 0,
+            _memory: memory,
+        })
+    }
+
+    fn write_headers(&self, text: &mut String, headers: &HeaderMap) {
+        for (name, value) in headers {
+            let value = if SENSITIVE.contains(name) || self.sensitive.contains(name) {
+                "[redacted]".into()
+            } else {
+                String::from_utf8_lossy(value.as_bytes())
+            };
+            let _ = write!(text, "{}: {}\r\n", name, value);
+        }
+    }
+}
+
+/// One exchange being recorded. It is written out when dropped.
+pub struct Recording {
+    recorder: Arc<Recorder>,
+    seq: u64,
+    text: String,
+    body: Vec<u8>,
+    /// The size of the whole response body, as far as it has been sent.
+    total: u64,
+    /// Room for the body in the `--memory-budget`.
+    _memory: Option<Reservation>,
+}
+
+impl Recording {
+    /// Record the head of `resp`. The recording is left on the response for
+    /// `tap` to record the start of its body as it is sent.
+    pub fn finish(mut self, mut resp: Response<Body>) -> Response<Body> {
+        let _ = write!(self.text, "{:?} {}\r\n", resp.version(), resp.status());
+        self.recorder.write_headers(&mut self.text, resp.headers());
+        self.text.push_str("\r\n");
+
+        resp.extensions_mut().insert(self);
+        resp
+    }
+
+    fn record_chunk(&mut self, chunk: &Bytes) {
+        self.total += chunk.len() as u64;
+        let room = self.recorder.body_limit.saturating_sub(self.body.len());
+        self.body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    }
+}
+
+/// Record the body of `resp` as it is sent, if `Recording::finish` left a
+/// recording on it.
+pub fn tap(mut resp: Response<Body>) -> Response<Recorded> {
+    let recording = resp.extensions_mut().remove::<Recording>();
+    resp.map(|body| Recorded { body, recording })
+}
+
+/// A response body being recorded. Everything is passed through, the size
+/// hint included, as `limit::Held` does.
+pub struct Recorded {
+    body: Body,
+    recording: Option<Recording>,
+}
+
+impl HttpBody for Recorded {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Bytes, hyper::Error>>> {
+        let poll = Pin::new(&mut self.body).poll_data(cx);
+        if let (Poll::Ready(Some(Ok(chunk))), Some(recording)) = (&poll, &mut self.recording) {
+            recording.record_chunk(chunk);
+        }
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::result::Result<Option<HeaderMap>, hyper::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let name = format!("{}-{:06}.http", millis, self.seq);
+        let path = self.recorder.dir.join(&name);
+        // Written under a hidden name first, so that nothing watching the
+        // directory sees a recording before it is whole.
+        let part = self.recorder.dir.join(format!(".{}.part", name));
+
+        let mut contents = self.text.clone().into_bytes();
+        contents.extend_from_slice(&self.body);
+        if self.total > self.body.len() as u64 {
+            debug!(
+                "recorded {} of {} body bytes in {}",
+                self.body.len(),
+                self.total,
+                path.display()
+            );
+        }
+        // Recordings are dropped on a runtime worker, which mustn't wait on
+        // the disk, so the file is written on the blocking pool when there is
+        // one.
+        let write = move || {
+            let written = fs::write(&part, contents).and_then(|()| fs::rename(&part, &path));
+            if let Err(e) = written {
+                let _ = fs::remove_file(&part);
+                warn!("failed to write {}: {}", path.display(), e);
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(write)),
+            Err(_) => write(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> Request<Body> {
+        Request::builder().uri("/x").body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn checking_the_directory_leaves_nothing_in_it() {
+        let dir =
+            std::env::temp_dir().join(format!("sufficient-record-new-{}", std::process::id()));
+        Recorder::new(&dir, Vec::new(), 10, 60, Vec::new(), None).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let file = dir.join("file");
+        fs::write(&file, b"").unwrap();
+        let err = Recorder::new(&file, Vec::new(), 10, 60, Vec::new(), None).err();
+        assert!(matches!(err, Some(Error::RecordDir(..))), "{:?}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_used_up_budget_skips_recording() {
+        let dir = std::env::temp_dir().join(format!("sufficient-record-{}", std::process::id()));
+        let budget = Budget::new(100);
+        let recorder = Arc::new(
+            Recorder::new(&dir, Vec::new(), 10, 60, Vec::new(), Some(budget.clone())).unwrap(),
+        );
+
+        let first = recorder.start(&request());
+        assert!(first.is_some());
+        assert_eq!(budget.available(), 40);
+        // No room for a second body: the request goes unrecorded rather than
+        // failing or growing past the budget.
+        assert!(recorder.start(&request()).is_none());
+        assert_eq!(budget.available(), 40);
+
+        drop(first);
+        assert_eq!(budget.available(), 100);
+        assert!(recorder.start(&request()).is_some());
+        assert_eq!(budget.available(), 100);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recordings_are_written_off_the_runtime_worker() {
+        let dir =
+            std::env::temp_dir().join(format!("sufficient-record-runtime-{}", std::process::id()));
+        let recorder = Arc::new(Recorder::new(&dir, Vec::new(), 10, 60, Vec::new(), None).unwrap());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let recording = recorder.start(&request()).unwrap();
+            let resp = tap(recording.finish(Response::new(Body::from("body"))));
+            hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        });
+
+        // The write happens in the background; shutting down waits for it.
+        runtime.shutdown_timeout(std::time::Duration::from_secs(10));
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let contents = fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+        assert!(contents.starts_with("GET /x HTTP/1.1\r\n"), "{}", contents);
+        assert!(contents.ends_with("\r\n\r\nbody"), "{}", contents);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recorded_bodies_keep_their_size_hint() {
+        let dir =
+            std::env::temp_dir().join(format!("sufficient-record-hint-{}", std::process::id()));
+        let recorder = Arc::new(Recorder::new(&dir, Vec::new(), 10, 60, Vec::new(), None).unwrap());
+
+        let recording = recorder.start(&request()).unwrap();
+        let resp = tap(recording.finish(Response::new(Body::from("hello"))));
+        assert!(resp.extensions().get::<Recording>().is_none());
+        assert_eq!(resp.body().size_hint().exact(), Some(5));
+        assert!(!resp.body().is_end_stream());
+        drop(resp);
+
+        let resp = tap(Response::new(Body::empty()));
+        assert_eq!(resp.body().size_hint().exact(), Some(0));
+        assert!(resp.body().is_end_stream());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,271 @@
+//! Request path rewriting, for keeping old URLs working.
+//!
+//! Each `--rewrite <PATTERN>=<TARGET>[,internal|redirect]` option becomes a
+//! `RewriteRule`. PATTERN is a glob, or a regex if it starts with `^`, and is
+//! matched against the whole decoded path. Its captures can be used in TARGET
+//! as `$1`, `$2` and so on; every `*`, `**` and `?` of a glob is a capture.
+//!
+//! An `internal` rule (the default) serves TARGET as if it had been requested.
+//! A `redirect` rule sends the client to it, keeping the query string. Rules
+//! are tried in order and at most one applies to a request.
+
+use http::header::{self, HeaderValue};
+use http::status::StatusCode;
+use http::uri::{PathAndQuery, Uri};
+use hyper::{Body, Method, Request, Response};
+use regex::Regex;
+use std::fmt;
+
+use crate::{urlpath, Error, Result};
+
+/// What a rule does with a matching request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Internal,
+    Redirect,
+}
+
+#[derive(Clone, Debug)]
+pub struct RewriteRule {
+    pattern: String,
+    regex: Regex,
+    target: String,
+    /// A query string given with the target, added to the request's own.
+    query: Option<String>,
+    kind: Kind,
+}
+
+/// A rule applied to a particular request path.
+#[derive(Debug)]
+pub struct Rewrite {
+    /// The pattern of the rule that applied, used for logging.
+    pub pattern: String,
+    pub kind: Kind,
+    /// The target with captures substituted. For an internal rule this is
+    /// the decoded path that is served, already resolved, and is what access
+    /// rules are checked against.
+    pub target: String,
+    /// The target's query string with captures substituted, encoded.
+    pub query: Option<String>,
+}
+
+impl fmt::Display for RewriteRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.target)?;
+        if let Some(query) = &self.query {
+            write!(f, "?{}", query)?;
+        }
+        Ok(())
+    }
+}
+
+impl RewriteRule {
+    /// Whether this is an internal rule whose target climbs out of the root.
+    pub fn escapes_root(&self) -> bool {
+        self.kind == Kind::Internal && self.target.split('/').any(|segment| segment == "..")
+    }
+}
+
+/// Parse a `<PATTERN>=<TARGET>[,internal|redirect]` command line argument.
+pub fn parse_rule(s: &str) -> std::result::Result<RewriteRule, String> {
+    let usage = || {
+        format!(
+            "expected <PATTERN>=<TARGET>[,internal|redirect], got '{}'",
+            s
+        )
+    };
+
+    let (pattern, rest) = match s.find('=') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => return Err(usage()),
+    };
+    let (target, kind) = match rest.rfind(',') {
+        Some(i) if &rest[i + 1..] == "internal" => (&rest[..i], Kind::Internal),
+        Some(i) if &rest[i + 1..] == "redirect" => (&rest[..i], Kind::Redirect),
+        _ => (rest, Kind::Internal),
+    };
+    if pattern.is_empty() || target.is_empty() {
+        return Err(usage());
+    }
+    // Split off the query here, so that nothing substituted into the path
+    // later can start one.
+    let (target, query) = match target.find('?') {
+        Some(i) => (&target[..i], Some(target[i + 1..].to_string())),
+        None => (target, None),
+    };
+    if kind == Kind::Internal && !target.starts_with('/') {
+        return Err(format!(
+            "internal rewrite target '{}' must be a path starting with '/'",
+            target
+        ));
+    }
+
+    let source = match pattern.strip_prefix('^') {
+        Some(regex) => format!("^(?:{})$", regex),
+        None => glob_regex(pattern),
+    };
+    let regex = Regex::new(&source).map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?;
+
+    Ok(RewriteRule {
+        pattern: pattern.to_string(),
+        regex,
+        target: target.to_string(),
+        query,
+        kind,
+    })
+}
+
+/// Find the first rule matching `path`, the raw request path.
+pub fn find(rules: &[RewriteRule], path: &str) -> Option<Rewrite> {
+    // `OPTIONS *` has no path to rewrite.
+    if rules.is_empty() || !path.starts_with('/') {
+        return None;
+    }
+
+    let path = urlpath::normalize(path);
+    rules.iter().find_map(|rule| {
+        let captures = rule.regex.captures(&path)?;
+        let mut target = String::new();
+        captures.expand(&rule.target, &mut target);
+        // The captures are decoded already, so the target is resolved without
+        // decoding it again; otherwise a `%252e%252e` in the request would
+        // become a `..` after protected areas were checked.
+        if rule.kind == Kind::Internal {
+            target = urlpath::resolve(&target);
+        }
+        let query = rule.query.as_ref().map(|template| {
+            let mut query = String::new();
+            captures.expand(template, &mut query);
+            urlpath::encode_query(&query)
+        });
+        Some(Rewrite {
+            pattern: rule.pattern.clone(),
+            kind: rule.kind,
+            target,
+            query,
+        })
+    })
+}
+
+/// Point the request at the rewritten path, keeping its query string.
+pub fn set_path(req: &mut Request<Body>, rewrite: &Rewrite) -> Result<()> {
+    let mut path_and_query = urlpath::encode(&rewrite.target);
+    push_queries(&mut path_and_query, rewrite, req.uri().query());
+
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(
+        path_and_query
+            .parse::<PathAndQuery>()
+            .map_err(|e| Error::Http(e.into()))?,
+    );
+    *req.uri_mut() = Uri::from_parts(parts).map_err(|e| Error::Http(e.into()))?;
+
+    Ok(())
+}
+
+/// The redirect for `rewrite`, carrying over the request's query string. GET
+/// and HEAD get a 301; anything else a 308, so the method is kept.
+pub fn redirect(rewrite: &Rewrite, method: &Method, query: Option<&str>) -> Result<Response<Body>> {
+    let status = match *method {
+        Method::GET | Method::HEAD => StatusCode::MOVED_PERMANENTLY,
+        _ => StatusCode::PERMANENT_REDIRECT,
+    };
+    let mut location = urlpath::encode(&rewrite.target);
+    push_queries(&mut location, rewrite, query);
+    let location = HeaderValue::from_str(&location).map_err(|e| Error::Http(e.into()))?;
+
+    let resp = Response::builder()
+        .status(status)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .map_err(Error::Http)?;
+
+    Ok(resp)
+}
+
+/// Append the rewrite's query string and then the request's to `url`.
+fn push_queries(url: &mut String, rewrite: &Rewrite, query: Option<&str>) {
+    let queries = rewrite.query.as_deref().into_iter().chain(query);
+    for (i, query) in queries.enumerate() {
+        url.push(if i == 0 { '?' } else { '&' });
+        url.push_str(query);
+    }
+}
+
+/// Translate a glob into an anchored regex with a capture for each wildcard.
+fn glob_regex(glob: &str) -> String {
+    let mut source = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                source.push_str("(.*)");
+            }
+            '*' => source.push_str("([^/]*)"),
+            '?' => source.push_str("([^/])"),
+            c => source.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    source.push('$');
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protect;
+
+    fn rewrite(rule: &str, path: &str) -> Rewrite {
+        let rules = [parse_rule(rule).unwrap()];
+        find(&rules, path).unwrap()
+    }
+
+    #[test]
+    fn internal_target_is_resolved() {
+        let rewrite = rewrite("/old/**=/new/$1", "/old/a/./b//c");
+        assert_eq!(rewrite.target, "/new/a/b/c");
+    }
+
+    #[test]
+    fn encoded_dot_segments_are_not_decoded_twice() {
+        let protected = [protect::parse_rule("/private/**=secret").unwrap()];
+        let rewrite = rewrite("/old/**=/new/$1", "/old/%252e%252e/private/x");
+        assert_eq!(rewrite.target, "/new/%2e%2e/private/x");
+
+        let mut req = Request::builder()
+            .uri("/old/%252e%252e/private/x")
+            .body(Body::empty())
+            .unwrap();
+        set_path(&mut req, &rewrite).unwrap();
+
+        // What is served, what is signed and what protect checks all agree.
+        assert_eq!(req.uri().path(), "/new/%252e%252e/private/x");
+        assert_eq!(urlpath::normalize(req.uri().path()), rewrite.target);
+        assert!(protect::matching_rule(&protected, req.uri().path()).is_none());
+        assert!(protect::matching_rule(&protected, &urlpath::encode(&rewrite.target)).is_none());
+    }
+
+    #[test]
+    fn captures_cannot_climb_out_of_the_root() {
+        let rewrite = rewrite("^/old/(.*)=/new/$1/../../..", "/old/x");
+        assert_eq!(rewrite.target, "/");
+    }
+
+    #[test]
+    fn set_path_keeps_both_queries() {
+        let rewrite = rewrite("/old/*=/new/$1?from=old", "/old/x");
+        let mut req = Request::builder()
+            .uri("/old/x?a=1")
+            .body(Body::empty())
+            .unwrap();
+        set_path(&mut req, &rewrite).unwrap();
+        assert_eq!(req.uri().path_and_query().unwrap(), "/new/x?from=old&a=1");
+    }
+
+    #[test]
+    fn redirect_target_is_left_alone() {
+        let rewrite = rewrite("/old/*=https://example.com/$1,redirect", "/old/x");
+        assert_eq!(rewrite.target, "https://example.com/x");
+    }
+}
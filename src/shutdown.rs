@@ -0,0 +1,118 @@
+//! Graceful shutdown on SIGTERM or Ctrl-C.
+//!
+//! One `Trigger` is fired when the process is asked to stop, and every
+//! server, in every worker, holds a `Signal` from it. Servers stop accepting
+//! once the signal fires, finish the requests they have in flight, and give up
+//! on whatever is left when `--shutdown-timeout` runs out.
+
+use futures::future;
+use tokio::sync::watch;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+/// Fires the `Signal`s made with it.
+pub struct Trigger(watch::Sender<bool>);
+
+/// Resolves once its `Trigger` has fired. Works on any runtime, so one
+/// trigger reaches every worker.
+#[derive(Clone)]
+pub struct Signal(watch::Receiver<bool>);
+
+pub fn channel() -> (Trigger, Signal) {
+    let (sender, receiver) = watch::channel(false);
+    (Trigger(sender), Signal(receiver))
+}
+
+impl Trigger {
+    pub fn fire(&self) {
+        // Nobody may be waiting any more, which is fine.
+        let _ = self.0.send(true);
+    }
+}
+
+impl Signal {
+    pub fn fired(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait for the trigger. A trigger dropped without firing never fires.
+    pub async fn wait(mut self) {
+        while !self.fired() {
+            if self.0.changed().await.is_err() {
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Wait until the process is asked to stop.
+pub async fn requested() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terms) => {
+                let term = Box::pin(terms.recv());
+                let interrupt = Box::pin(tokio::signal::ctrl_c());
+                future::select(term, interrupt).await;
+                info!("shutting down");
+                return;
+            }
+            Err(e) => warn!("can't listen for SIGTERM, only Ctrl-C shuts down: {}", e),
+        }
+    }
+
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => info!("shutting down"),
+        Err(e) => {
+            warn!("can't listen for Ctrl-C, shutdown takes a kill: {}", e);
+            future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn firing_reaches_every_signal_on_every_runtime() {
+        let (trigger, signal) = channel();
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let signal = signal.clone();
+                thread::spawn(move || {
+                    let rt = tokio::runtime::Builder::new_current_thread()
+                        .build()
+                        .unwrap();
+                    rt.block_on(signal.wait());
+                })
+            })
+            .collect();
+
+        assert!(!signal.fired());
+        trigger.fire();
+        assert!(signal.fired());
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        // A signal made after the trigger fired doesn't wait at all.
+        futures::executor::block_on(signal.clone().wait());
+    }
+
+    #[test]
+    fn a_dropped_trigger_never_fires() {
+        let (trigger, signal) = channel();
+        drop(trigger);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let waited = rt.block_on(async {
+            tokio::time::timeout(std::time::Duration::from_millis(50), signal.wait()).await
+        });
+        assert!(waited.is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Signed, expiring links.
+//!
+//! With `--signing-key` set, a request carrying `?expires=<unix>&sig=<hmac>`
+//! is allowed into protected areas for exactly the signed path until the
+//! expiry passes. The signature is a hex HMAC-SHA256 of the normalized path
+//! and the expiry, so neither can be changed without invalidating it.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::query::QueryParams;
+use crate::urlpath;
+
+/// What a request's signature parameters amount to.
+pub enum Signature {
+    /// The request isn't signed; it goes through the usual checks.
+    Absent,
+    /// The request is signed for its path and hasn't expired.
+    Valid,
+    /// The request is signed, but not validly for this path right now.
+    Invalid,
+}
+
+/// Check the `expires` and `sig` query parameters of a request for `path`.
+pub fn check(key: Option<&str>, path: &str, params: &QueryParams) -> Signature {
+    let key = match key {
+        Some(key) => key,
+        None => return Signature::Absent,
+    };
+
+    let (expires, sig) = match (&params.expires, &params.sig) {
+        (None, None) => return Signature::Absent,
+        (Some(expires), Some(sig)) => (expires, sig),
+        _ => return Signature::Invalid,
+    };
+    let expires = match expires.parse::<u64>() {
+        Ok(expires) => expires,
+        Err(_) => return Signature::Invalid,
+    };
+    if expires <= unix_now() {
+        return Signature::Invalid;
+    }
+
+    let sig = match decode_hex(sig) {
+        Some(sig) => sig,
+        None => return Signature::Invalid,
+    };
+    let path = urlpath::normalize(path);
+    // `verify_slice` compares in constant time.
+    match mac(key, &path, expires).verify_slice(&sig) {
+        Ok(()) => Signature::Valid,
+        Err(_) => Signature::Invalid,
+    }
+}
+
+/// Build a complete signed URL for `path` on `authority`, valid for `ttl`. A
+/// `ttl` too long to count to is a link that never expires.
+pub fn signed_url(key: &str, authority: &str, path: &str, ttl: Duration) -> String {
+    let path = urlpath::normalize(path);
+    let expires = unix_now().saturating_add(ttl.as_secs());
+    let sig = encode_hex(&mac(key, &path, expires).finalize().into_bytes());
+
+    format!(
+        "http://{}{}?expires={}&sig={}",
+        authority,
+        urlpath::encode(&path),
+        expires,
+        sig
+    )
+}
+
+fn mac(key: &str, path: &str, expires: u64) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(path.as_bytes());
+    mac.update(b"\n");
+    mac.update(expires.to_string().as_bytes());
+    mac
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "key";
+
+    fn check_url(key: Option<&str>, path: &str, url: &str) -> Signature {
+        let query = url.split_once('?').map(|(_, query)| query);
+        check(key, path, &QueryParams::parse(query).unwrap())
+    }
+
+    fn url(path: &str, ttl: Duration) -> String {
+        signed_url(KEY, "127.0.0.1:4000", path, ttl)
+    }
+
+    /// A link for `path` that expired `ago` seconds ago, correctly signed.
+    fn expired(path: &str, ago: u64) -> String {
+        let expires = unix_now() - ago;
+        let sig = encode_hex(&mac(KEY, path, expires).finalize().into_bytes());
+        format!("?expires={}&sig={}", expires, sig)
+    }
+
+    #[test]
+    fn signed_links_are_valid_for_their_path() {
+        let link = url("/private/report.pdf", Duration::from_secs(60));
+        assert!(link.starts_with("http://127.0.0.1:4000/private/report.pdf?expires="));
+        let check = |path| check_url(Some(KEY), path, &link);
+        assert!(matches!(check("/private/report.pdf"), Signature::Valid));
+        assert!(matches!(check("/private/./report.pdf"), Signature::Valid));
+        // The same signature replayed for another path.
+        assert!(matches!(check("/private/other.pdf"), Signature::Invalid));
+        assert!(matches!(check("/private/"), Signature::Invalid));
+    }
+
+    #[test]
+    fn tampered_links_are_invalid() {
+        let link = url("/report.pdf", Duration::from_secs(60));
+        let (query, sig) = link.split_once("&sig=").unwrap();
+
+        let mut flipped = sig.to_string().into_bytes();
+        flipped[0] = if flipped[0] == b'0' { b'1' } else { b'0' };
+        let flipped = String::from_utf8(flipped).unwrap();
+        let tampered = [
+            format!("{}&sig={}", query, flipped),
+            format!("{}&sig={}", query, &sig[..sig.len() - 2]),
+            format!("{}&sig={}00", query, sig),
+            format!("{}&sig=not-hex", query),
+            format!("{}9&sig={}", query, sig),
+            query.to_string(),
+            format!("/report.pdf?sig={}", sig),
+        ];
+        for link in &tampered {
+            let signature = check_url(Some(KEY), "/report.pdf", link);
+            assert!(matches!(signature, Signature::Invalid), "{}", link);
+        }
+
+        let signature = check_url(Some("other key"), "/report.pdf", &link);
+        assert!(matches!(signature, Signature::Invalid));
+    }
+
+    #[test]
+    fn expired_links_are_invalid() {
+        let signature = check_url(Some(KEY), "/report.pdf", &expired("/report.pdf", 1));
+        assert!(matches!(signature, Signature::Invalid));
+        let signature = check_url(Some(KEY), "/report.pdf", &expired("/report.pdf", 0));
+        assert!(matches!(signature, Signature::Invalid));
+        let link = url("/report.pdf", Duration::from_secs(0));
+        let signature = check_url(Some(KEY), "/report.pdf", &link);
+        assert!(matches!(signature, Signature::Invalid));
+    }
+
+    #[test]
+    fn unsigned_requests_and_keyless_servers_take_the_usual_checks() {
+        let signature = check_url(Some(KEY), "/report.pdf", "/report.pdf");
+        assert!(matches!(signature, Signature::Absent));
+        let link = url("/report.pdf", Duration::from_secs(60));
+        assert!(matches!(
+            check_url(None, "/report.pdf", &link),
+            Signature::Absent
+        ));
+    }
+
+    #[test]
+    fn the_longest_ttls_never_expire() {
+        let link = url("/report.pdf", Duration::from_secs(u64::MAX));
+        assert!(link.contains(&format!("expires={}&", u64::MAX)));
+        let signature = check_url(Some(KEY), "/report.pdf", &link);
+        assert!(matches!(signature, Signature::Valid));
+    }
+}
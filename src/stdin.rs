@@ -0,0 +1,442 @@
+//! `--stdin` mode: serving a single artifact piped in on standard input.
+//!
+//! Standard input is read fully at startup and served from memory at `/NAME`
+//! and at `/`, with its type guessed from NAME. Every other path is a 404.
+//!
+//! Text starting with a UTF-16 byte order mark is served with that charset,
+//! or with `--transcode-utf16` as UTF-8 under an `ETag` of its own. Range
+//! requests always get the bytes as they were piped in.
+//!
+//! With `--expose-metadata` responses also carry `X-File-Mtime`, in unix
+//! seconds, and `X-File-Size`, the size as piped in, for sync tools. They
+//! come from the same values as `ETag` and `Last-Modified`.
+
+use http::header::{self, HeaderName, HeaderValue};
+use http::status::StatusCode;
+use hyper::body::Bytes;
+use hyper::{Body, Method, Request, Response};
+use mime_guess::mime::Mime;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::memory::{Budget, Category, Reservation};
+use crate::preconditions::{Outcome, Preconditions, Resource};
+use crate::utf16::{self, Endian};
+use crate::{httpdate, urlpath, Error, Result};
+
+const FILE_MTIME: HeaderName = HeaderName::from_static("x-file-mtime");
+const FILE_SIZE: HeaderName = HeaderName::from_static("x-file-size");
+
+/// The piped-in data, with everything needed to serve it.
+pub struct Artifact {
+    path: String,
+    data: Bytes,
+    content_type: String,
+    etag: String,
+    /// When standard input was read.
+    modified: SystemTime,
+    last_modified: String,
+    /// How to serve the data as UTF-8, if it is UTF-16 text.
+    utf16: Option<Utf16>,
+    /// Whether to, with `--transcode-utf16`.
+    transcode: bool,
+    /// Whether to add the `X-File-*` headers, with `--expose-metadata`.
+    metadata: bool,
+    /// The data's share of the `--memory-budget`, held as long as it is.
+    _memory: Option<Reservation>,
+}
+
+/// UTF-16 text, as it is served transcoded.
+struct Utf16 {
+    endian: Endian,
+    content_type: String,
+    etag: String,
+}
+
+impl Artifact {
+    /// `data`, to be served at `/NAME` as `content_type`, holding `memory`
+    /// for as long as it is.
+    pub fn new(
+        name: &str,
+        data: Vec<u8>,
+        content_type: &Mime,
+        memory: Option<Reservation>,
+    ) -> Artifact {
+        let digest = Sha256::digest(&data);
+        let modified = SystemTime::now();
+        let utf16 = utf16::detect(content_type, &data).map(|endian| Utf16 {
+            endian,
+            content_type: utf16::with_charset(content_type, "utf-8"),
+            etag: format!("\"{:x}-utf8\"", digest),
+        });
+
+        Artifact {
+            path: urlpath::join("/", name),
+            data: Bytes::from(data),
+            content_type: match &utf16 {
+                Some(utf16) => utf16::with_charset(content_type, utf16.endian.charset()),
+                None => content_type.to_string(),
+            },
+            etag: format!("\"{:x}\"", digest),
+            modified,
+            last_modified: httpdate::format(modified),
+            utf16,
+            transcode: false,
+            metadata: false,
+            _memory: memory,
+        }
+    }
+
+    /// Serve UTF-16 text as UTF-8, except to Range requests.
+    pub fn transcode_utf16(&mut self) {
+        self.transcode = true;
+    }
+
+    /// Tell clients the artifact's modification time and size.
+    pub fn expose_metadata(&mut self) {
+        self.metadata = true;
+    }
+
+    /// The path the artifact is served at.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The size of the artifact in bytes.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    /// When standard input was read.
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+}
+
+/// How a successful response delivers the artifact, recorded on the response
+/// for the access log.
+#[derive(Clone, Copy, Debug)]
+pub enum Delivery {
+    /// The whole body.
+    Full,
+    /// A single byte range.
+    Range,
+    /// The whole body, transcoded from UTF-16.
+    Transcoded,
+    /// Nothing, because the client's copy is current.
+    NotModified,
+}
+
+impl Delivery {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Delivery::Full => "full",
+            Delivery::Range => "range",
+            Delivery::Transcoded => "transcoded",
+            Delivery::NotModified => "not-modified",
+        }
+    }
+}
+
+/// Read all of `input`, standard input but for tests, refusing more than
+/// `limit` bytes or more than `budget` has room for, to be served as
+/// `content_type`.
+pub fn read(
+    input: impl Read,
+    name: &str,
+    limit: u64,
+    content_type: &Mime,
+    budget: Option<&Arc<Budget>>,
+) -> Result<Artifact> {
+    let room = budget.map_or(limit, |budget| budget.available().min(limit));
+    let mut data = Vec::new();
+    input
+        .take(room + 1)
+        .read_to_end(&mut data)
+        .map_err(Error::Io)?;
+    let over_budget = |budget: &Arc<Budget>| Error::OverMemoryBudget {
+        what: "standard input",
+        budget: budget.limit(),
+    };
+    if data.len() as u64 > room {
+        return Err(match budget {
+            Some(budget) if room < limit => over_budget(budget),
+            _ => Error::StdinTooLarge { limit },
+        });
+    }
+    let memory = match budget {
+        Some(budget) => Some(
+            budget
+                .reserve(Category::Stdin, data.len() as u64)
+                .ok_or_else(|| over_budget(budget))?,
+        ),
+        None => None,
+    };
+
+    Ok(Artifact::new(name, data, content_type, memory))
+}
+
+/// Answer a request from the artifact, allowing for client clocks `skew` off
+/// from ours.
+pub fn serve(artifact: &Artifact, req: &Request<Body>, skew: Duration) -> Result<Response<Body>> {
+    let path = urlpath::normalize_strict(req.uri().path())?;
+    if path != "/" && path != artifact.path {
+        let e = io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path));
+        return Err(Error::Io(e));
+    }
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return crate::other_method(req.method());
+    }
+
+    // Range only counts for GET, so HEAD describes what a plain GET gets.
+    let ranged = req.method() == Method::GET && req.headers().contains_key(header::RANGE);
+    let transcoded = match &artifact.utf16 {
+        Some(utf16) if artifact.transcode && !ranged => Some(utf16),
+        _ => None,
+    };
+    let (content_type, etag) = match transcoded {
+        Some(utf16) => (&utf16.content_type, &utf16.etag),
+        None => (&artifact.content_type, &artifact.etag),
+    };
+    let mut resp = Response::builder()
+        .header(header::CONTENT_TYPE, content_type.as_str())
+        .header(header::ETAG, etag.as_str())
+        .header(header::LAST_MODIFIED, artifact.last_modified.as_str())
+        .header(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if artifact.metadata {
+        let mtime = artifact
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        resp = resp
+            .header(FILE_MTIME, mtime)
+            .header(FILE_SIZE, artifact.data.len());
+    }
+
+    let len = artifact.data.len() as u64;
+    let resource = Resource {
+        etag,
+        modified: Some(artifact.modified),
+        len,
+    };
+    let outcome = Preconditions::parse(req.headers()).evaluate(
+        req.method(),
+        &resource,
+        SystemTime::now(),
+        skew,
+    );
+
+    let (resp, data) = match (outcome, transcoded) {
+        (Outcome::NotModified, _) => {
+            let resp = resp
+                .status(StatusCode::NOT_MODIFIED)
+                .extension(Delivery::NotModified)
+                .body(Body::empty())
+                .map_err(Error::Http)?;
+            return Ok(resp);
+        }
+        // Made into a response of its own by make_error_response, so without
+        // the validators and X-File-* headers above, which only 200, 206 and
+        // 304 carry.
+        (Outcome::Unsatisfiable, _) => return Err(Error::RangeNotSatisfiable { len }),
+        (Outcome::Partial { start, end }, _) => (
+            resp.status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, len),
+                )
+                .extension(Delivery::Range),
+            artifact.data.slice(start as usize..end as usize + 1),
+        ),
+        // How long the UTF-8 is isn't known until it is sent, so the body
+        // goes out chunked.
+        (Outcome::Full, Some(utf16)) => {
+            let body = if req.method() == Method::HEAD {
+                Body::empty()
+            } else {
+                utf16::transcode(artifact.data.clone(), utf16.endian)
+            };
+            let resp = resp
+                .status(StatusCode::OK)
+                .extension(Delivery::Transcoded)
+                .body(body)
+                .map_err(Error::Http)?;
+            return Ok(resp);
+        }
+        (Outcome::Full, None) => (
+            resp.status(StatusCode::OK).extension(Delivery::Full),
+            artifact.data.clone(),
+        ),
+    };
+    let resp = resp.header(header::CONTENT_LENGTH, data.len());
+    let body = if req.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::from(data)
+    };
+    let resp = resp.body(body).map_err(Error::Http)?;
+
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact() -> Artifact {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        Artifact {
+            path: "/data.txt".to_string(),
+            data: Bytes::from_static(b"0123456789"),
+            content_type: "text/plain".to_string(),
+            etag: "\"abc\"".to_string(),
+            modified,
+            last_modified: httpdate::format(modified),
+            utf16: None,
+            transcode: false,
+            metadata: false,
+            _memory: None,
+        }
+    }
+
+    fn get(headers: &[(header::HeaderName, &str)]) -> Response<Body> {
+        let mut req = Request::builder().uri("/data.txt");
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        serve(
+            &artifact(),
+            &req.body(Body::empty()).unwrap(),
+            Duration::from_secs(2),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn if_range_with_the_current_etag_gets_the_range() {
+        let resp = get(&[(header::RANGE, "bytes=2-4"), (header::IF_RANGE, "\"abc\"")]);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 2-4/10");
+    }
+
+    #[test]
+    fn if_range_refuses_weak_etags() {
+        let resp = get(&[
+            (header::RANGE, "bytes=2-4"),
+            (header::IF_RANGE, "W/\"abc\""),
+        ]);
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn if_range_with_a_stale_validator_gets_everything() {
+        for validator in &["\"old\"", "*", "Sun, 06 Nov 1994 08:49:38 GMT", "garbage"] {
+            let resp = get(&[(header::RANGE, "bytes=2-4"), (header::IF_RANGE, validator)]);
+            assert_eq!(resp.status(), StatusCode::OK, "{}", validator);
+        }
+    }
+
+    #[test]
+    fn if_range_with_last_modified_gets_the_range() {
+        let resp = get(&[
+            (header::RANGE, "bytes=2-4"),
+            (header::IF_RANGE, "Sun, 06 Nov 1994 08:49:37 GMT"),
+        ]);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let resp = get(&[(header::IF_NONE_MATCH, "W/\"abc\"")]);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    fn utf16(transcode: bool, headers: &[(header::HeaderName, &str)]) -> Response<Body> {
+        let mut artifact = Artifact::new(
+            "page.html",
+            b"\xff\xfeh\0i\0".to_vec(),
+            &mime_guess::mime::TEXT_HTML_UTF_8,
+            None,
+        );
+        if transcode {
+            artifact.transcode_utf16();
+        }
+        let mut req = Request::builder().uri("/page.html");
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        serve(
+            &artifact,
+            &req.body(Body::empty()).unwrap(),
+            Duration::from_secs(2),
+        )
+        .unwrap()
+    }
+
+    fn body(resp: Response<Body>) -> Bytes {
+        futures::executor::block_on(hyper::body::to_bytes(resp.into_body())).unwrap()
+    }
+
+    #[test]
+    fn utf16_text_gets_its_charset() {
+        let resp = utf16(false, &[]);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-16le"
+        );
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "6");
+        assert_eq!(body(resp), &b"\xff\xfeh\0i\0"[..]);
+
+        assert_eq!(get(&[]).headers()[header::CONTENT_TYPE], "text/plain");
+    }
+
+    #[test]
+    fn utf16_text_can_be_transcoded() {
+        let resp = utf16(true, &[]);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        assert!(!resp.headers().contains_key(header::CONTENT_LENGTH));
+        let etag = resp.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert!(etag.ends_with("-utf8\""));
+        assert_eq!(body(resp), "hi");
+
+        let resp = utf16(true, &[(header::IF_NONE_MATCH, &etag)]);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn ranges_of_utf16_text_are_not_transcoded() {
+        let resp = utf16(true, &[(header::RANGE, "bytes=2-3")]);
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-16le"
+        );
+        assert_eq!(body(resp), &b"h\0"[..]);
+    }
+
+    #[test]
+    fn metadata_is_exposed_on_request() {
+        assert!(!get(&[]).headers().contains_key(FILE_MTIME));
+
+        let mut artifact = artifact();
+        artifact.expose_metadata();
+        let req = Request::builder()
+            .uri("/data.txt")
+            .header(header::IF_NONE_MATCH, "\"abc\"")
+            .body(Body::empty())
+            .unwrap();
+        let resp = serve(&artifact, &req, Duration::from_secs(2)).unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[FILE_MTIME], "784111777");
+        assert_eq!(resp.headers()[FILE_SIZE], "10");
+    }
+}
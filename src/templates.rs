@@ -0,0 +1,238 @@
+//! The HTML pages the server renders, and the tiny template engine behind
+//! them.
+//!
+//! Templates are plain text with `{{name}}` placeholders. Every value
+//! interpolated into a placeholder is HTML-escaped, so nothing taken from a
+//! request can inject markup, and is stripped of control characters and cut
+//! to `MAX_VALUE_CHARS`, so a page is never much bigger than its template
+//! however long the request path is. The built-in templates can be
+//! overridden with files of the same name in `--template-dir`.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::{Error, Result};
+
+/// The most characters of a value that are rendered; the rest is replaced by
+/// an ellipsis.
+const MAX_VALUE_CHARS: usize = 256;
+
+/// The built-in error page. Placeholders: `status`, `detail`, `path`.
+const ERROR_HTML: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{{status}}</title>
+</head>
+<body>
+<h1>{{status}}</h1>
+<p>{{detail}}</p>
+<p><code>{{path}}</code></p>
+</body>
+</html>
+"#;
+
+/// Every template, by file name, with its built-in source.
+const BUILTIN: &[(&str, &str)] = &[("error.html", ERROR_HTML)];
+
+/// A parsed template.
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Text(String),
+    Var(String),
+}
+
+/// Why a template failed to parse, and where.
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Template {
+    /// Parse a template, rejecting unclosed or malformed placeholders.
+    pub fn parse(source: &str) -> std::result::Result<Template, ParseError> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let error = |message: String| {
+                let offset = source.len() - rest.len() + start;
+                let line = source[..offset].matches('\n').count() + 1;
+                ParseError { line, message }
+            };
+            let after = &rest[start + 2..];
+            let end = after
+                .find("}}")
+                .ok_or_else(|| error("unclosed '{{'".to_string()))?;
+            let name = after[..end].trim();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+                return Err(error(format!("bad placeholder name '{}'", name)));
+            }
+            parts.push(Part::Var(name.to_string()));
+            rest = &after[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        Ok(Template { parts })
+    }
+
+    /// Render the template, escaping and capping every value. Placeholders
+    /// without a value render as nothing.
+    pub fn render(&self, vars: &[(&str, &str)]) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Var(name) => {
+                    if let Some((_, value)) = vars.iter().find(|(var, _)| var == name) {
+                        escape_html_into(value, &mut out);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// The full set of page templates.
+#[derive(Debug)]
+pub struct Templates {
+    pub error: Template,
+}
+
+impl Templates {
+    /// Load the templates, taking each from `dir` if it has a file of that
+    /// name and using the built-in one otherwise.
+    pub fn load(dir: Option<&Path>) -> Result<Templates> {
+        let load = |name: &str, builtin: &str| -> Result<Template> {
+            let path = match dir {
+                Some(dir) => dir.join(name),
+                None => return Ok(Template::parse(builtin).expect("built-in template")),
+            };
+            let source = match fs::read_to_string(&path) {
+                Ok(source) => source,
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => builtin.to_string(),
+                Err(e) => return Err(Error::TemplateIo(path, e)),
+            };
+            Template::parse(&source).map_err(|e| Error::TemplateParse(path, e))
+        };
+
+        Ok(Templates {
+            error: load("error.html", ERROR_HTML)?,
+        })
+    }
+}
+
+/// Write the built-in templates into `dir`, as a starting point for
+/// customizing them.
+pub fn dump(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).map_err(Error::Io)?;
+    for (name, source) in BUILTIN {
+        let path = dir.join(name);
+        fs::write(&path, source).map_err(|e| Error::TemplateIo(path, e))?;
+    }
+    Ok(())
+}
+
+/// Escape `s` into `out` as HTML text, dropping control characters and
+/// cutting it to `MAX_VALUE_CHARS`.
+fn escape_html_into(s: &str, out: &mut String) {
+    let mut chars = s.chars().filter(|c| !c.is_control());
+    for c in chars.by_ref().take(MAX_VALUE_CHARS) {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    if chars.next().is_some() {
+        out.push('\u{2026}');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(value: &str) -> String {
+        Template::parse("{{value}}")
+            .unwrap()
+            .render(&[("value", value)])
+    }
+
+    #[test]
+    fn values_are_escaped() {
+        assert_eq!(
+            render("<script>alert('x')</script>"),
+            "&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;"
+        );
+        assert_eq!(render("a & \"b\""), "a &amp; &quot;b&quot;");
+    }
+
+    #[test]
+    fn long_values_are_cut() {
+        let long = "a".repeat(MAX_VALUE_CHARS + 1);
+        let rendered = render(&long);
+        assert_eq!(rendered.chars().count(), MAX_VALUE_CHARS + 1);
+        assert!(rendered.ends_with('\u{2026}'));
+
+        let exact = "a".repeat(MAX_VALUE_CHARS);
+        assert_eq!(render(&exact), exact);
+    }
+
+    #[test]
+    fn the_cap_counts_characters_not_escapes() {
+        let rendered = render(&"<".repeat(MAX_VALUE_CHARS + 10));
+        assert_eq!(
+            rendered,
+            format!("{}\u{2026}", "&lt;".repeat(MAX_VALUE_CHARS))
+        );
+    }
+
+    #[test]
+    fn control_characters_are_dropped() {
+        assert_eq!(render("a\r\nb\tc\0d\u{1b}[31m"), "abcd[31m");
+    }
+
+    #[test]
+    fn missing_values_render_as_nothing() {
+        let template = Template::parse("<p>{{ a }}|{{b}}</p>").unwrap();
+        assert_eq!(template.render(&[("b", "x")]), "<p>|x</p>");
+    }
+
+    #[test]
+    fn bad_placeholders_are_refused() {
+        let e = Template::parse("line one\n{{oops").unwrap_err();
+        assert_eq!(e.line, 2);
+        assert!(Template::parse("{{}}").is_err());
+        assert!(Template::parse("{{Status}}").is_err());
+        assert!(Template::parse("{{a-b}}").is_err());
+    }
+
+    #[test]
+    fn builtin_templates_parse() {
+        for (name, source) in BUILTIN {
+            assert!(Template::parse(source).is_ok(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! A real server on a port of its own, for tests that go over the wire.
+//!
+//! `TestServer` goes through the same setup as `run()`: the arguments are
+//! parsed and validated, the piped-in data is read the way `--stdin` reads
+//! it, and the shared state and listeners come from the resulting
+//! configuration. Only logging differs, being captured rather than set up
+//! globally, so tests can look at it.
+//!
+//! Every server gets a runtime of its own, so tests stay plain `#[test]`
+//! functions, and is shut down gracefully when dropped.
+
+use clap::Parser;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+use tracing::instrument::WithSubscriber;
+
+use crate::shutdown::{self, Trigger};
+use crate::{addr, listen, read_stdin, Config, Result, Shared};
+
+/// How long a test waits on the server before giving up on it.
+const PATIENCE: Duration = Duration::from_secs(5);
+
+pub struct TestServer {
+    addr: SocketAddr,
+    stop: Trigger,
+    server: Option<JoinHandle<Result<()>>>,
+    log: Captured,
+    runtime: Runtime,
+}
+
+/// A response as it came over the wire.
+#[derive(Debug)]
+pub struct Exchange {
+    /// E.g. `HTTP/1.1 200 OK`.
+    pub status: String,
+    /// The header lines other than `Date`, sorted.
+    pub headers: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+impl TestServer {
+    /// Run `sufficient ARGS` on a port of its own on localhost, with `stdin`
+    /// piped in.
+    pub fn spawn(args: &[&str], stdin: &[u8]) -> TestServer {
+        let args = ["sufficient", "--addr", "127.0.0.1:0"].iter().chain(args);
+        let config = Config::try_parse_from(args).unwrap();
+        let validated = config.validate().unwrap_or_else(|problems| {
+            let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+            panic!("invalid configuration: {:?}", problems)
+        });
+        let config = validated.config;
+        let memory = config.memory_budget.map(crate::memory::Budget::new);
+        let stdin = read_stdin(&config, stdin, memory.as_ref()).unwrap();
+
+        let (stop, stopping) = shutdown::channel();
+        let shared = Shared::new(&config, validated.templates, stdin, memory, stopping).unwrap();
+        let listeners = addr::bind_all(&config.addr.listeners(config.ipv6_only), false).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+
+        let log = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let log = log.clone();
+                move || log.clone()
+            })
+            .finish();
+        let runtime = Runtime::new().unwrap();
+        let server = listen(config, shared, listeners, None).with_subscriber(subscriber);
+        let server = runtime.spawn(server);
+
+        TestServer {
+            addr,
+            stop,
+            server: Some(server),
+            log,
+            runtime,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// What the server has logged so far.
+    pub fn log(&self) -> String {
+        self.log.contents()
+    }
+
+    /// Send `method` for `path` with the `extra` header lines, each ending in
+    /// CRLF, on a connection of its own.
+    pub fn exchange(&self, method: &str, path: &str, extra: &str) -> Exchange {
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        stream.set_read_timeout(Some(PATIENCE)).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+            method, path, extra
+        )
+        .unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).unwrap();
+
+        let end = resp.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let body = resp.split_off(end + 4);
+        let head = String::from_utf8(resp).unwrap();
+        let mut lines = head.trim_end().split("\r\n").map(str::to_string);
+        let status = lines.next().unwrap();
+        let mut headers: Vec<String> = lines
+            .filter(|line| !line.to_ascii_lowercase().starts_with("date:"))
+            .collect();
+        headers.sort();
+        Exchange {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    /// Shut the server down, returning how serving ended.
+    pub fn stop(&mut self) -> Result<()> {
+        self.stop.fire();
+        let server = self.server.take().expect("server already stopped");
+        let stopped = self
+            .runtime
+            .block_on(async { tokio::time::timeout(PATIENCE, server).await });
+        stopped
+            .expect("server didn't stop in time")
+            .expect("server panicked")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop.fire();
+        if let Some(server) = self.server.take() {
+            let _ = self
+                .runtime
+                .block_on(async { tokio::time::timeout(PATIENCE, server).await });
+        }
+    }
+}
+
+impl Exchange {
+    /// The value of the `name` header, if there is one.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find_map(|line| {
+            let (line_name, value) = line.split_once(':')?;
+            if line_name.eq_ignore_ascii_case(name) {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// A log writer keeping what is written, for looking at afterwards.
+#[derive(Clone, Default)]
+pub struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
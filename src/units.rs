@@ -0,0 +1,212 @@
+//! Sizes and durations, as people write and read them.
+//!
+//! Options taking a size accept a byte count with an optional unit, e.g.
+//! `64MiB`, `1.5GB` or `500`, which is bytes. Binary (`KiB`, `MiB`, ...) and
+//! decimal (`KB`, `MB`, ...) units are both accepted, but not the
+//! single-letter kind like `G`, which could mean either. Options taking a
+//! duration need a unit, e.g. `250ms` or `15m`, except for `0`.
+//!
+//! Sizes are always shown in binary units, through `Size`.
+//!
+//! This isn't called `fmt` to stay clear of `std::fmt` and
+//! `tracing_subscriber::fmt`, which the crate imports by that name.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+const BINARY: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+const DECIMAL: &[&str] = &["B", "KB", "MB", "GB", "TB", "PB", "EB"];
+
+/// Parse a size like `64MiB`, `1.5GB` or `500` into bytes.
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = (&s[..split], s[split..].trim());
+
+    let multiplier: u64 = if unit.is_empty() {
+        1
+    } else if let Some(i) = position(BINARY, unit) {
+        1 << (10 * i)
+    } else if let Some(i) = position(DECIMAL, unit) {
+        1000u64.pow(i as u32)
+    } else if unit.len() == 1 && "kmgtpe".contains(&unit.to_ascii_lowercase()) {
+        let unit = unit.to_ascii_uppercase();
+        return Err(format!(
+            "ambiguous size '{}': use {}iB for powers of 1024 or {}B for powers of 1000",
+            s, unit, unit
+        ));
+    } else {
+        return Err(format!(
+            "invalid size '{}': expected a number of bytes with an optional unit, e.g. 64MiB",
+            s
+        ));
+    };
+
+    // Work in whole units so `1.5GiB` is exact.
+    let (whole, fraction) = match number.find('.') {
+        Some(i) => (&number[..i], &number[i + 1..]),
+        None => (number, ""),
+    };
+    let invalid = || format!("invalid size '{}'", s);
+    if whole.is_empty() && fraction.is_empty() || fraction.contains('.') {
+        return Err(invalid());
+    }
+    // Trailing zeroes change nothing, and a fraction too long for the scale
+    // to fit in a u128 can't be a whole number of bytes.
+    let fraction = fraction.trim_end_matches('0');
+    let digits = format!("{}{}", whole, fraction);
+    let scale = 10u128
+        .checked_pow(fraction.len() as u32)
+        .ok_or_else(|| format!("size '{}' has too many decimal places", s))?;
+    let scaled = digits
+        .parse::<u128>()
+        .map_err(|_| invalid())?
+        .checked_mul(multiplier as u128)
+        .ok_or_else(|| format!("size '{}' is too large", s))?;
+    if scaled % scale != 0 {
+        return Err(format!("size '{}' is not a whole number of bytes", s));
+    }
+
+    u64::try_from(scaled / scale).map_err(|_| format!("size '{}' is too large", s))
+}
+
+/// Parse a duration like `250ms`, `15m` or `1h 30m`.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim();
+    if s == "0" {
+        return Ok(Duration::from_secs(0));
+    }
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!(
+            "duration '{}' is missing a unit, e.g. {}ms or {}s",
+            s, s, s
+        ));
+    }
+
+    humantime::parse_duration(s).map_err(|e| format!("invalid duration '{}': {}", s, e))
+}
+
+/// Displays a byte count in binary units, e.g. `64 MiB` or `1.5 GiB`. The
+/// precision, one decimal by default, can be set with `{:.N}`; without one,
+/// trailing zeroes are dropped.
+#[derive(Clone, Copy, Debug)]
+pub struct Size(pub u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut unit = 0;
+        let mut value = self.0 as f64;
+        while value >= 1024.0 && unit + 1 < BINARY.len() {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            return write!(f, "{} B", self.0);
+        }
+
+        let mut number = format!("{:.*}", f.precision().unwrap_or(1), value);
+        if f.precision().is_none() && number.contains('.') {
+            number = number
+                .trim_end_matches('0')
+                .trim_end_matches('.')
+                .to_string();
+        }
+        write!(f, "{} {}", number, BINARY[unit])
+    }
+}
+
+/// Displays a duration the way `parse_duration` reads it, e.g. `1h 30m`,
+/// dropping anything below a millisecond.
+#[derive(Clone, Copy, Debug)]
+pub struct Elapsed(pub Duration);
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = Duration::from_millis(self.0.as_millis() as u64);
+        write!(f, "{}", humantime::format_duration(millis))
+    }
+}
+
+fn position(units: &[&str], unit: &str) -> Option<usize> {
+    units.iter().position(|u| u.eq_ignore_ascii_case(unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("64MiB"), Ok(64 << 20));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("1.5GB"), Ok(1_500_000_000));
+        assert_eq!(parse_size(" 2 kib "), Ok(2048));
+        assert_eq!(parse_size("1.50000KB"), Ok(1500));
+        assert_eq!(parse_size(".5KiB"), Ok(512));
+    }
+
+    #[test]
+    fn single_letter_units_are_ambiguous() {
+        let e = parse_size("1.5G").unwrap_err();
+        assert!(e.contains("ambiguous"), "{}", e);
+        assert!(e.contains("GiB") && e.contains("GB"), "{}", e);
+    }
+
+    #[test]
+    fn bad_sizes() {
+        for s in &["", ".", "1..5MiB", "1.2.3", "lots", "5 bytes", "-1"] {
+            assert!(parse_size(s).is_err(), "{:?} parsed", s);
+        }
+        assert!(parse_size("1.5B").unwrap_err().contains("whole number"));
+        assert!(parse_size("16EiB").unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn long_fractions_are_refused_not_a_panic() {
+        let s = format!("1.{}1", "0".repeat(40));
+        assert!(parse_size(&s).is_err());
+        let s = format!("1.{}1EiB", "0".repeat(38));
+        assert!(parse_size(&s).is_err());
+        // Only significant digits count against the limit.
+        let s = format!("1.5{}MiB", "0".repeat(60));
+        assert_eq!(parse_size(&s), Ok(3 << 19));
+    }
+
+    #[test]
+    fn sizes_round_trip() {
+        for &bytes in &[0, 500, 1024, 64 << 20, 3 << 29, 1 << 40] {
+            let shown = Size(bytes).to_string().replace(' ', "");
+            assert_eq!(parse_size(&shown), Ok(bytes), "{}", shown);
+        }
+    }
+
+    #[test]
+    fn size_display() {
+        assert_eq!(Size(0).to_string(), "0 B");
+        assert_eq!(Size(500).to_string(), "500 B");
+        assert_eq!(Size(3 << 29).to_string(), "1.5 GiB");
+        assert_eq!(format!("{:.2}", Size(64 << 20)), "64.00 MiB");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("0"), Ok(Duration::from_secs(0)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1h 30m"), Ok(Duration::from_secs(5400)));
+        assert!(parse_duration("15").unwrap_err().contains("missing a unit"));
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn durations_round_trip() {
+        for &millis in &[1, 250, 1500, 90_000, 5_400_000] {
+            let duration = Duration::from_millis(millis);
+            let shown = Elapsed(duration).to_string();
+            assert_eq!(parse_duration(&shown), Ok(duration), "{}", shown);
+        }
+    }
+}
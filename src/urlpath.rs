@@ -0,0 +1,196 @@
+//! Moving paths between their decoded form and URLs.
+//!
+//! Everything that puts a path into a URL or a header, or takes one out of a
+//! request, goes through here so the encoding rules can't drift apart. Paths
+//! are encoded one segment at a time, leaving only RFC 3986 `pchar`s raw, so
+//! control characters like a newline can never reach a header unescaped.
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::borrow::Cow;
+
+use crate::{Error, Result};
+
+/// Everything except a path segment's `pchar`s: unreserved characters,
+/// sub-delims, `:` and `@`.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'!')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')')
+    .remove(b'*')
+    .remove(b'+')
+    .remove(b',')
+    .remove(b';')
+    .remove(b'=')
+    .remove(b':')
+    .remove(b'@');
+
+/// Everything that can't appear raw in a query string: a `pchar`, `/` or `?`.
+const QUERY: &AsciiSet = &SEGMENT.remove(b'/').remove(b'?');
+
+/// How to treat a request path that doesn't decode cleanly.
+#[derive(Clone, Copy, Debug)]
+pub enum Decoding {
+    /// Replace invalid UTF-8, so every path decodes to something.
+    Lossy,
+    /// Refuse invalid UTF-8 and encoded NUL bytes.
+    Strict,
+}
+
+/// Percent-encode a decoded path for use in a URL, keeping its `/`s.
+pub fn encode(path: &str) -> String {
+    let segments: Vec<String> = path.split('/').map(encode_segment).collect();
+    segments.join("/")
+}
+
+/// Percent-encode a single path segment, including any `/` in it.
+pub fn encode_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, SEGMENT).to_string()
+}
+
+/// Percent-encode a query string, keeping its `&` and `=`.
+pub fn encode_query(query: &str) -> String {
+    utf8_percent_encode(query, QUERY).to_string()
+}
+
+/// Percent-decode a request path.
+pub fn decode(path: &str, decoding: Decoding) -> Result<Cow<'_, str>> {
+    let decoded = percent_decode_str(path);
+    match decoding {
+        Decoding::Lossy => Ok(decoded.decode_utf8_lossy()),
+        Decoding::Strict => {
+            let decoded = decoded.decode_utf8().map_err(|_| Error::UriNotUtf8)?;
+            if decoded.contains('\0') {
+                return Err(Error::UriNotUtf8);
+            }
+            Ok(decoded)
+        }
+    }
+}
+
+/// Decode a request path and resolve its `.`, `..` and empty segments, never
+/// climbing above `/`.
+pub fn normalize(path: &str) -> String {
+    let decoded = decode(path, Decoding::Lossy).expect("lossy decoding can't fail");
+    resolve(&decoded)
+}
+
+/// Like `normalize`, but with strict decoding.
+pub fn normalize_strict(path: &str) -> Result<String> {
+    Ok(resolve(&decode(path, Decoding::Strict)?))
+}
+
+/// Join a relative decoded path onto a decoded base. The result is
+/// normalized, so `rel` can't climb above the root of `base`.
+pub fn join(base: &str, rel: &str) -> String {
+    resolve(&format!("{}/{}", base, rel))
+}
+
+/// Resolve the `.`, `..` and empty segments of an already decoded path,
+/// never climbing above `/`. Nothing is decoded, so a `%2e%2e` left in a
+/// decoded path stays a segment of its own.
+pub fn resolve(decoded: &str) -> String {
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() || decoded.ends_with('/') {
+        normalized.push('/');
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_escapes_what_isnt_a_pchar() {
+        assert_eq!(encode("/100%/a b"), "/100%25/a%20b");
+        assert_eq!(encode("/a#b?c"), "/a%23b%3Fc");
+        assert_eq!(encode("/a+b"), "/a+b");
+        assert_eq!(encode("/a\r\nSet-Cookie: x"), "/a%0D%0ASet-Cookie:%20x");
+        assert_eq!(encode("/café/日本"), "/caf%C3%A9/%E6%97%A5%E6%9C%AC");
+        assert_eq!(encode("/a-b_c.d~e!$&'()*,;=:@"), "/a-b_c.d~e!$&'()*,;=:@");
+    }
+
+    #[test]
+    fn encode_segment_escapes_slashes() {
+        assert_eq!(encode_segment("a/b"), "a%2Fb");
+    }
+
+    #[test]
+    fn encode_query_keeps_separators() {
+        assert_eq!(encode_query("a=1&b=x y/?#"), "a=1&b=x%20y/?%23");
+    }
+
+    #[test]
+    fn decode_reverses_encode() {
+        for path in &[
+            "/100%",
+            "/a#b?c",
+            "/a b+c",
+            "/a\nb",
+            "/café/日本",
+            "/%2e%2e",
+        ] {
+            let encoded = encode(path);
+            assert_eq!(decode(&encoded, Decoding::Strict).unwrap(), *path);
+        }
+    }
+
+    #[test]
+    fn decoding_doesnt_treat_plus_as_space() {
+        assert_eq!(decode("/a+b", Decoding::Strict).unwrap(), "/a+b");
+    }
+
+    #[test]
+    fn strict_decoding_refuses_bad_utf8_and_nul() {
+        assert!(decode("/%ff", Decoding::Strict).is_err());
+        assert!(decode("/a%00b", Decoding::Strict).is_err());
+        assert_eq!(decode("/%ff", Decoding::Lossy).unwrap(), "/\u{fffd}");
+    }
+
+    #[test]
+    fn normalize_resolves_dot_segments() {
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("/a/./b/../c"), "/a/c");
+        assert_eq!(normalize("/../../a"), "/a");
+        assert_eq!(normalize("//a//b/"), "/a/b/");
+        assert_eq!(normalize("/a/%2e%2e/b"), "/b");
+    }
+
+    #[test]
+    fn encode_then_normalize_decodes_once() {
+        // A decoded path holding `%2e%2e` literally must come back as it was,
+        // not as a `..` that climbs a level.
+        let decoded = "/new/%2e%2e/private";
+        assert_eq!(encode(decoded), "/new/%252e%252e/private");
+        assert_eq!(normalize(&encode(decoded)), decoded);
+        assert_eq!(resolve(decoded), decoded);
+    }
+
+    #[test]
+    fn join_stays_under_the_root() {
+        assert_eq!(join("/a/b", "c"), "/a/b/c");
+        assert_eq!(join("/a/b", "../../../c"), "/c");
+    }
+}
@@ -0,0 +1,285 @@
+//! Text exported as UTF-16, which browsers show as mojibake when it is
+//! served as anything else.
+//!
+//! Text that starts with a UTF-16 byte order mark gets its charset from it.
+//! With `--transcode-utf16` it is converted to UTF-8 instead, as it is sent:
+//! a chunk at a time, so nothing the size of the text is ever held twice.
+//! Surrogate pairs and code units split between chunks are put back
+//! together; anything that doesn't decode, like an odd byte at the end,
+//! becomes U+FFFD.
+
+use hyper::body::Bytes;
+use hyper::Body;
+use mime_guess::mime::{self, Mime};
+use std::convert::Infallible;
+
+/// How much is transcoded at a time.
+const CHUNK: usize = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub fn charset(self) -> &'static str {
+        match self {
+            Endian::Little => "utf-16le",
+            Endian::Big => "utf-16be",
+        }
+    }
+}
+
+/// The byte order `data` says it is in, if it is text of type `mime` that
+/// starts with a UTF-16 byte order mark.
+pub fn detect(mime: &Mime, data: &[u8]) -> Option<Endian> {
+    let text = mime.type_() == mime::TEXT
+        || (mime.subtype() == "xhtml" && mime.suffix() == Some(mime::XML));
+    if !text {
+        return None;
+    }
+
+    match data {
+        [0xff, 0xfe, ..] => Some(Endian::Little),
+        [0xfe, 0xff, ..] => Some(Endian::Big),
+        _ => None,
+    }
+}
+
+/// `mime` with its charset, if any, replaced by `charset`.
+pub fn with_charset(mime: &Mime, charset: &str) -> String {
+    let mut content_type = mime.essence_str().to_string();
+    for (name, value) in mime.params().filter(|(name, _)| *name != mime::CHARSET) {
+        content_type.push_str(&format!("; {}={}", name, value));
+    }
+    content_type.push_str("; charset=");
+    content_type.push_str(charset);
+    content_type
+}
+
+/// A body of `data`, UTF-16 in `endian` order with a byte order mark, as
+/// UTF-8 without one.
+pub fn transcode(data: Bytes, endian: Endian) -> Body {
+    let chunks = Chunks {
+        data: data.slice(2.min(data.len())..),
+        decoder: Decoder::new(endian),
+        done: false,
+    };
+    Body::wrap_stream(futures::stream::iter(chunks.map(Ok::<_, Infallible>)))
+}
+
+/// UTF-16 decoding that can be fed a byte at a time.
+pub struct Decoder {
+    endian: Endian,
+    /// The first byte of a code unit the last input ended in the middle of.
+    odd: Option<u8>,
+    /// A high surrogate waiting for its low one.
+    high: Option<u16>,
+}
+
+impl Decoder {
+    pub fn new(endian: Endian) -> Decoder {
+        Decoder {
+            endian,
+            odd: None,
+            high: None,
+        }
+    }
+
+    /// Decode `bytes` onto `out`, keeping back whatever they end in the
+    /// middle of.
+    pub fn push(&mut self, mut bytes: &[u8], out: &mut String) {
+        if let Some(first) = self.odd {
+            match bytes.split_first() {
+                Some((&second, rest)) => {
+                    self.odd = None;
+                    self.unit(first, second, out);
+                    bytes = rest;
+                }
+                None => return,
+            }
+        }
+
+        let mut pairs = bytes.chunks_exact(2);
+        for pair in &mut pairs {
+            self.unit(pair[0], pair[1], out);
+        }
+        if let [last] = pairs.remainder() {
+            self.odd = Some(*last);
+        }
+    }
+
+    /// Finish decoding, replacing anything left incomplete.
+    pub fn finish(&mut self, out: &mut String) {
+        if self.high.take().is_some() {
+            out.push(char::REPLACEMENT_CHARACTER);
+        }
+        if self.odd.take().is_some() {
+            out.push(char::REPLACEMENT_CHARACTER);
+        }
+    }
+
+    fn unit(&mut self, first: u8, second: u8, out: &mut String) {
+        let unit = match self.endian {
+            Endian::Little => u16::from_le_bytes([first, second]),
+            Endian::Big => u16::from_be_bytes([first, second]),
+        };
+
+        match unit {
+            0xd800..=0xdbff => {
+                if self.high.replace(unit).is_some() {
+                    out.push(char::REPLACEMENT_CHARACTER);
+                }
+            }
+            0xdc00..=0xdfff => match self.high.take() {
+                Some(high) => {
+                    let high = u32::from(high - 0xd800) << 10;
+                    let c = 0x10000 + (high | u32::from(unit - 0xdc00));
+                    out.push(char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                None => out.push(char::REPLACEMENT_CHARACTER),
+            },
+            _ => {
+                if self.high.take().is_some() {
+                    out.push(char::REPLACEMENT_CHARACTER);
+                }
+                let c = char::from_u32(u32::from(unit));
+                out.push(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+        }
+    }
+}
+
+/// The UTF-8 of `data`, a chunk at a time.
+struct Chunks {
+    data: Bytes,
+    decoder: Decoder,
+    done: bool,
+}
+
+impl Iterator for Chunks {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Bytes> {
+        let mut out = String::new();
+        while out.is_empty() && !self.data.is_empty() {
+            let chunk = self.data.split_to(CHUNK.min(self.data.len()));
+            self.decoder.push(&chunk, &mut out);
+        }
+        if out.is_empty() && !self.done {
+            self.done = true;
+            self.decoder.finish(&mut out);
+        }
+
+        if out.is_empty() {
+            None
+        } else {
+            Some(Bytes::from(out))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "<p>caf\u{e9} \u{1f600} \u{65e5}\u{672c}</p>";
+
+    fn encode(text: &str, endian: Endian) -> Vec<u8> {
+        let mut data = match endian {
+            Endian::Little => vec![0xff, 0xfe],
+            Endian::Big => vec![0xfe, 0xff],
+        };
+        for unit in text.encode_utf16() {
+            data.extend_from_slice(&match endian {
+                Endian::Little => unit.to_le_bytes(),
+                Endian::Big => unit.to_be_bytes(),
+            });
+        }
+        data
+    }
+
+    fn decode(pieces: &[&[u8]], endian: Endian) -> String {
+        let mut decoder = Decoder::new(endian);
+        let mut out = String::new();
+        for piece in pieces {
+            decoder.push(piece, &mut out);
+        }
+        decoder.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn detects_byte_order_marks_on_text() {
+        assert_eq!(
+            detect(&mime::TEXT_HTML, b"\xff\xfe<\0"),
+            Some(Endian::Little)
+        );
+        assert_eq!(detect(&mime::TEXT_PLAIN, b"\xfe\xff\0<"), Some(Endian::Big));
+        let xhtml = "application/xhtml+xml".parse().unwrap();
+        assert_eq!(detect(&xhtml, b"\xff\xfe"), Some(Endian::Little));
+        assert_eq!(detect(&mime::TEXT_HTML, b"<html>"), None);
+        assert_eq!(detect(&mime::TEXT_HTML, b"\xef\xbb\xbf<html>"), None);
+        assert_eq!(detect(&mime::IMAGE_PNG, b"\xff\xfe"), None);
+    }
+
+    #[test]
+    fn charsets_are_replaced() {
+        let mime: Mime = "text/html; charset=utf-8".parse().unwrap();
+        assert_eq!(
+            with_charset(&mime, "utf-16le"),
+            "text/html; charset=utf-16le"
+        );
+        let mime: Mime = "text/plain; format=flowed".parse().unwrap();
+        assert_eq!(
+            with_charset(&mime, "utf-16be"),
+            "text/plain; format=flowed; charset=utf-16be"
+        );
+    }
+
+    #[test]
+    fn decodes_across_every_split() {
+        for endian in &[Endian::Little, Endian::Big] {
+            let data = encode(TEXT, *endian);
+            for i in 2..data.len() {
+                let (a, b) = data[2..].split_at(i - 2);
+                assert_eq!(decode(&[a, b], *endian), TEXT, "split at {}", i);
+            }
+            let bytes: Vec<&[u8]> = data[2..].chunks(1).collect();
+            assert_eq!(decode(&bytes, *endian), TEXT);
+        }
+    }
+
+    #[test]
+    fn what_doesnt_decode_is_replaced() {
+        // An odd byte at the end.
+        assert_eq!(decode(&[b"a\0b"], Endian::Little), "a\u{fffd}");
+        // A lone high surrogate, at the end and before something else.
+        assert_eq!(decode(&[b"\x3d\xd8"], Endian::Little), "\u{fffd}");
+        assert_eq!(decode(&[b"\x3d\xd8a\0"], Endian::Little), "\u{fffd}a");
+        assert_eq!(
+            decode(&[b"\x3d\xd8\x3d\xd8\x00\xde"], Endian::Little),
+            "\u{fffd}\u{1f600}"
+        );
+        // A lone low surrogate.
+        assert_eq!(decode(&[b"\x00\xdea\0"], Endian::Little), "\u{fffd}a");
+    }
+
+    #[test]
+    fn transcodes_in_chunks_without_the_byte_order_mark() {
+        // Long enough for several chunks, with a pair split between two.
+        let text = format!(
+            "{}\u{1f600}{}",
+            "a".repeat(CHUNK / 2 - 1),
+            TEXT.repeat(2000)
+        );
+        let body = transcode(Bytes::from(encode(&text, Endian::Big)), Endian::Big);
+        let bytes = futures::executor::block_on(hyper::body::to_bytes(body)).unwrap();
+        assert_eq!(String::from_utf8(bytes.to_vec()).unwrap(), text);
+
+        let body = transcode(Bytes::from_static(b"\xff\xfe"), Endian::Little);
+        let bytes = futures::executor::block_on(hyper::body::to_bytes(body)).unwrap();
+        assert!(bytes.is_empty());
+    }
+}
@@ -0,0 +1,329 @@
+//! `--wire-log`: dumping the raw bytes of every connection.
+//!
+//! With the flag set, accepted connections are wrapped in `WireLog`, which
+//! emits a trace event under `sufficient::wire` for every read and write,
+//! with the byte count and a hex/ASCII dump of up to `DUMP_LIMIT` bytes.
+//! Each connection gets an id, which the request spans carry as `conn` so
+//! requests can be matched to their bytes. Without the flag, connections are
+//! served unwrapped.
+//!
+//! The values of `Authorization` and `Proxy-Authorization` are masked in the
+//! dumps of every request head on a connection, up to the first one with a
+//! chunked body; the heads after that are dumped as sent.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::accept::Connection;
+
+/// The tracing target of the dumps.
+pub const WIRE_TARGET: &str = "sufficient::wire";
+
+/// The most bytes of a single read or write that are dumped.
+const DUMP_LIMIT: usize = 4096;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A connection whose traffic is logged.
+pub struct WireLog {
+    inner: TcpStream,
+    id: u64,
+    head: HeadRedactor,
+}
+
+impl WireLog {
+    pub fn new(inner: TcpStream) -> WireLog {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        match inner.peer_addr() {
+            Ok(peer) => trace!(target: WIRE_TARGET, conn = id, %peer, "connection opened"),
+            Err(_) => trace!(target: WIRE_TARGET, conn = id, "connection opened"),
+        }
+
+        WireLog {
+            inner,
+            id,
+            head: HeadRedactor::default(),
+        }
+    }
+}
+
+impl Connection for WireLog {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn id(&self) -> Option<u64> {
+        Some(self.id)
+    }
+}
+
+impl AsyncRead for WireLog {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let mut data = buf.filled()[before..].to_vec();
+            if data.is_empty() {
+                trace!(target: WIRE_TARGET, conn = self.id, "peer closed its side");
+            } else {
+                self.head.redact(&mut data);
+                dump(self.id, "read", &data);
+            }
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for WireLog {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            dump(self.id, "wrote", &buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        trace!(target: WIRE_TARGET, conn = self.id, "shutting down");
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn dump(id: u64, direction: &str, data: &[u8]) {
+    let shown = &data[..data.len().min(DUMP_LIMIT)];
+    let mut lines = String::new();
+    for (i, row) in shown.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = row
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        lines.push_str(&format!(
+            "\n{:04x}  {:<47}  |{}|",
+            i * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    if shown.len() < data.len() {
+        lines.push_str(&format!("\n... {} more bytes", data.len() - shown.len()));
+    }
+
+    trace!(
+        target: WIRE_TARGET,
+        conn = id,
+        "{} {} bytes{}",
+        direction,
+        data.len(),
+        lines
+    );
+}
+
+/// Masks credential header values in every request head, however it is
+/// split across reads. Bodies are skipped by their `Content-Length`; after a
+/// chunked one nothing more is masked, since where it ends isn't tracked.
+#[derive(Default)]
+struct HeadRedactor {
+    /// Nothing more is masked.
+    done: bool,
+    /// Bytes of the last request's body still to come.
+    body: u64,
+    /// The name of the header line being read, until its `:`.
+    name: Vec<u8>,
+    /// Past the `:` of the current line.
+    in_value: bool,
+    /// Masking the rest of the current line.
+    masking: bool,
+    /// The value of the current line, if it is a header that frames the body.
+    framing: Option<Vec<u8>>,
+    /// The current line has had any bytes besides its `\r`.
+    line_started: bool,
+    /// Past the request line.
+    in_headers: bool,
+    /// The current head's `Content-Length`.
+    content_length: u64,
+    /// The current head has a `Transfer-Encoding`.
+    chunked: bool,
+}
+
+impl HeadRedactor {
+    fn redact(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            if self.done {
+                return;
+            }
+            if self.body > 0 {
+                self.body -= 1;
+                continue;
+            }
+            match *byte {
+                b'\n' => {
+                    if let Some(value) = self.framing.take() {
+                        self.frame(&value);
+                    }
+                    if self.in_headers && !self.line_started {
+                        self.end_head();
+                        continue;
+                    }
+                    self.in_headers = true;
+                    self.name.clear();
+                    self.in_value = false;
+                    self.masking = false;
+                    self.line_started = false;
+                }
+                b'\r' => {}
+                b':' if self.in_headers && !self.in_value => {
+                    self.line_started = true;
+                    self.in_value = true;
+                    let name = &self.name[..];
+                    self.masking = name.eq_ignore_ascii_case(b"authorization")
+                        || name.eq_ignore_ascii_case(b"proxy-authorization");
+                    if name.eq_ignore_ascii_case(b"content-length")
+                        || name.eq_ignore_ascii_case(b"transfer-encoding")
+                    {
+                        self.framing = Some(Vec::new());
+                    }
+                }
+                _ => {
+                    self.line_started = true;
+                    if self.masking {
+                        if *byte != b' ' {
+                            *byte = b'*';
+                        }
+                    } else if let Some(value) = &mut self.framing {
+                        if value.len() < 32 {
+                            value.push(*byte);
+                        }
+                    } else if self.in_headers && !self.in_value && self.name.len() < 32 {
+                        self.name.push(*byte);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Note what the value of the current framing header says about the body.
+    fn frame(&mut self, value: &[u8]) {
+        if self.name.eq_ignore_ascii_case(b"transfer-encoding") {
+            self.chunked = true;
+            return;
+        }
+        let length = std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok());
+        match length {
+            Some(length) => self.content_length = length,
+            // hyper refuses the request, and the connection with it.
+            None => self.done = true,
+        }
+    }
+
+    /// Skip the body of the head that just ended, then read the next head.
+    fn end_head(&mut self) {
+        if self.chunked {
+            self.done = true;
+        }
+        *self = HeadRedactor {
+            done: self.done,
+            body: self.content_length,
+            ..HeadRedactor::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEAD: &[u8] = b"GET /private HTTP/1.1\r\nHost: localhost\r\n\
+        Authorization: Bearer s3cret\r\nAccept: */*\r\n\r\n";
+
+    /// `data` as redacted when read in pieces split at `splits`.
+    fn redacted(data: &[u8], splits: &[usize]) -> String {
+        let mut redactor = HeadRedactor::default();
+        let mut data = data.to_vec();
+        let mut start = 0;
+        for &end in splits.iter().chain(Some(&data.len())) {
+            redactor.redact(&mut data[start..end]);
+            start = end;
+        }
+        String::from_utf8(data).unwrap()
+    }
+
+    fn masked(head: &str) -> String {
+        head.replace("Bearer s3cret", "****** ******")
+            .replace("Basic dXNlcjpwYXNz", "***** ************")
+    }
+
+    #[test]
+    fn credentials_are_masked_however_the_head_is_split() {
+        let expected = masked(std::str::from_utf8(HEAD).unwrap());
+        assert!(!expected.contains("s3cret"));
+        for i in 0..=HEAD.len() {
+            assert_eq!(redacted(HEAD, &[i]), expected, "split at {}", i);
+        }
+        let every: Vec<usize> = (1..HEAD.len()).collect();
+        assert_eq!(redacted(HEAD, &every), expected);
+    }
+
+    #[test]
+    fn every_pipelined_head_is_masked() {
+        let second = b"GET /other HTTP/1.1\r\nproxy-authorization: Basic dXNlcjpwYXNz\r\n\r\n";
+        let data = [HEAD, second, HEAD].concat();
+        let expected = masked(std::str::from_utf8(&data).unwrap());
+        assert_eq!(redacted(&data, &[]), expected);
+        for i in 0..=data.len() {
+            assert_eq!(redacted(&data, &[i]), expected, "split at {}", i);
+        }
+    }
+
+    #[test]
+    fn bodies_are_skipped_by_their_length() {
+        let with_body = b"POST /x HTTP/1.1\r\nContent-Length: 26\r\n\r\n\
+            Authorization: in the body";
+        let data = [&with_body[..], HEAD].concat();
+        let expected = masked(std::str::from_utf8(&data).unwrap());
+        assert!(expected.contains("Authorization: in the body"));
+        for i in 0..=data.len() {
+            assert_eq!(redacted(&data, &[i]), expected, "split at {}", i);
+        }
+    }
+
+    #[test]
+    fn nothing_is_masked_after_a_chunked_body() {
+        let chunked = b"POST /x HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+            Authorization: Bearer s3cret\r\n\r\n0\r\n\r\n";
+        let data = [&chunked[..], HEAD].concat();
+        let text = redacted(&data, &[]);
+        assert_eq!(text.matches("****** ******").count(), 1, "{}", text);
+    }
+}
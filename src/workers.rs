@@ -0,0 +1,249 @@
+//! `--workers` mode: several independent single-threaded servers in one
+//! process, each with its own listening sockets bound to the same addresses with
+//! SO_REUSEPORT so the kernel balances connections between them.
+//!
+//! The main thread only supervises. When a worker exits, for whatever reason,
+//! it is restarted until `--worker-restarts` is used up, after a delay that
+//! doubles with every restart so a worker that crashes on startup doesn't
+//! spin. SIGTERM or Ctrl-C stops every worker gracefully, and nothing is
+//! restarted after that.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::runtime;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::shutdown::{self, Trigger};
+use crate::{addr, Config, Error, Result, Shared};
+
+/// Whether this platform lets several sockets bind the same port.
+pub const SUPPORTED: bool = cfg!(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos"))
+));
+
+const MIN_RESTART_DELAY: Duration = Duration::from_millis(100);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+/// What the supervisor waits for.
+enum Event {
+    /// The worker with this id has finished.
+    Exited(usize),
+    /// The process was asked to stop.
+    Stop,
+}
+
+/// Start `config.workers` workers and keep them running until the process is
+/// asked to stop.
+pub fn run(config: Config, shared: Shared, stop: Trigger) -> Result<()> {
+    let (events, received) = mpsc::channel();
+
+    let signals = events.clone();
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || {
+            let rt = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("signal runtime");
+            rt.block_on(shutdown::requested());
+            let _ = signals.send(Event::Stop);
+        })
+        .map_err(Error::Io)?;
+
+    let workers = config.workers;
+    let restarts = config.worker_restarts;
+    let start = move |id, events| spawn(id, config.clone(), shared.clone(), events);
+    supervise(workers, restarts, start, (events, received), stop)
+}
+
+/// Run `workers` workers started with `start`, restarting them as they exit
+/// until `restarts` is used up, and stopping them all on `Event::Stop`.
+fn supervise<F>(
+    workers: usize,
+    restarts: usize,
+    mut start: F,
+    (events, received): (Sender<Event>, Receiver<Event>),
+    stop: Trigger,
+) -> Result<()>
+where
+    F: FnMut(usize, Sender<Event>) -> Result<JoinHandle<Result<()>>>,
+{
+    let mut running = HashMap::new();
+    for id in 0..workers {
+        running.insert(id, (start(id, events.clone())?, Instant::now()));
+    }
+
+    let mut restarted = 0;
+    let mut delay = MIN_RESTART_DELAY;
+    // Workers waiting out their delay, and when they are due.
+    let mut pending: Vec<(Instant, usize)> = Vec::new();
+    let mut stopping = false;
+    loop {
+        let event = match pending.iter().map(|(due, _)| *due).min() {
+            Some(due) => received.recv_timeout(due.saturating_duration_since(Instant::now())),
+            // We hold a sender ourselves, so this never disconnects.
+            None => received.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match event {
+            Ok(Event::Exited(id)) => {
+                let (handle, started) = running.remove(&id).expect("unknown worker exited");
+                match handle.join() {
+                    Ok(Ok(())) if stopping => debug!("worker {} stopped", id),
+                    Ok(Ok(())) => warn!("worker {} exited", id),
+                    Ok(Err(e)) => {
+                        error!("worker {} failed", id);
+                        crate::log_error_chain(&e);
+                    }
+                    Err(_) => error!("worker {} panicked", id),
+                }
+
+                if stopping {
+                    if running.is_empty() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                if restarted == restarts {
+                    stop.fire();
+                    return Err(Error::WorkerRestartsExhausted);
+                }
+                restarted += 1;
+                // A worker that stayed up a while had its crash out of the
+                // blue, not in a loop.
+                if started.elapsed() >= MAX_RESTART_DELAY {
+                    delay = MIN_RESTART_DELAY;
+                }
+                info!(
+                    "restarting worker {} in {:?} (restart {} of {})",
+                    id, delay, restarted, restarts
+                );
+                pending.push((Instant::now() + delay, id));
+                delay = (delay * 2).min(MAX_RESTART_DELAY);
+            }
+            Ok(Event::Stop) => {
+                info!("stopping {} workers", running.len());
+                stopping = true;
+                pending.clear();
+                stop.fire();
+                if running.is_empty() {
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                let (due, waiting) = pending.drain(..).partition(|(at, _)| *at <= now);
+                pending = waiting;
+                for (_, id) in due {
+                    running.insert(id, (start(id, events.clone())?, Instant::now()));
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => unreachable!("event channel closed"),
+        }
+    }
+}
+
+/// Tells the supervisor that a worker thread has finished, including when it
+/// finishes by panicking.
+struct ExitNotice {
+    id: usize,
+    events: Sender<Event>,
+}
+
+impl Drop for ExitNotice {
+    fn drop(&mut self) {
+        let _ = self.events.send(Event::Exited(self.id));
+    }
+}
+
+fn spawn(
+    id: usize,
+    config: Config,
+    shared: Shared,
+    events: Sender<Event>,
+) -> Result<JoinHandle<Result<()>>> {
+    thread::Builder::new()
+        .name(format!("worker-{}", id))
+        .spawn(move || {
+            let _notice = ExitNotice { id, events };
+
+            let rt = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(Error::Io)?;
+            rt.block_on(async {
+                let listeners = addr::bind_all(&config.addr.listeners(config.ipv6_only), true)?;
+                debug!("worker {} listening", id);
+                crate::listen(config, shared, listeners, Some(id)).await
+            })
+        })
+        .map_err(Error::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Starts workers that panic straight away, noting when each started.
+    fn crashing(
+        starts: Arc<Mutex<Vec<(usize, Instant)>>>,
+    ) -> impl FnMut(usize, Sender<Event>) -> Result<JoinHandle<Result<()>>> {
+        move |id, events| {
+            starts.lock().unwrap().push((id, Instant::now()));
+            Ok(thread::spawn(move || {
+                let _notice = ExitNotice { id, events };
+                panic!("worker {} crashed", id);
+            }))
+        }
+    }
+
+    #[test]
+    fn crashed_workers_are_restarted_ever_more_slowly() {
+        let starts = Arc::new(Mutex::new(Vec::new()));
+        let began = Instant::now();
+        let (stop, stopped) = shutdown::channel();
+        let result = supervise(1, 3, crashing(starts.clone()), mpsc::channel(), stop);
+        assert!(matches!(result, Err(Error::WorkerRestartsExhausted)));
+        // Giving up stops any other workers too.
+        assert!(stopped.fired());
+
+        let starts = starts.lock().unwrap();
+        assert_eq!(starts.len(), 4);
+        let gaps: Vec<Duration> = starts
+            .windows(2)
+            .map(|pair| pair[1].1 - pair[0].1)
+            .collect();
+        for (gap, expected) in gaps.iter().zip(&[100, 200, 400]) {
+            assert!(*gap >= Duration::from_millis(*expected), "{:?}", gaps);
+        }
+        assert!(began.elapsed() >= Duration::from_millis(700));
+    }
+
+    #[test]
+    fn stopping_reaches_every_worker_and_restarts_none() {
+        let (stop, stopping) = shutdown::channel();
+        let starts = Arc::new(Mutex::new(0));
+        let start = {
+            let starts = starts.clone();
+            move |id, events| {
+                *starts.lock().unwrap() += 1;
+                let stopping = stopping.clone();
+                Ok(thread::spawn(move || {
+                    let _notice = ExitNotice { id, events };
+                    futures::executor::block_on(stopping.wait());
+                    Ok(())
+                }))
+            }
+        };
+
+        let (events, received) = mpsc::channel();
+        events.send(Event::Stop).unwrap();
+        supervise(3, 5, start, (events, received), stop).unwrap();
+        assert_eq!(*starts.lock().unwrap(), 3);
+    }
+}